@@ -0,0 +1,158 @@
+// SPDX-License-Identifier: AGPL-3.0-only
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::BTreeSet;
+use std::fmt;
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) enum Action {
+    Create,
+    Update,
+    Delete,
+}
+
+impl fmt::Display for Action {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{}",
+            serde_plain::to_string(self).map_err(|_| fmt::Error)?
+        )
+    }
+}
+
+/// A record of one change to one row, written by `Db::save` and `Db::delete`.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub(crate) struct AuditEntry {
+    pub(crate) timestamp: DateTime<Utc>,
+    /// The barcode of the user who made the change, if known.
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) actor: Option<u64>,
+    /// The sled tree of the row, like "item" or "users".
+    pub(crate) tree: String,
+    pub(crate) id: u64,
+    pub(crate) action: Action,
+    pub(crate) changes: Vec<Change>,
+}
+
+/// A top-level field of a row whose value changed. A missing value means the field wasn't set.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub(crate) struct Change {
+    pub(crate) field: String,
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) old: Option<Value>,
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) new: Option<Value>,
+}
+
+impl Change {
+    pub(crate) fn old_json(&self) -> String {
+        self.old.as_ref().map(Value::to_string).unwrap_or_default()
+    }
+
+    pub(crate) fn new_json(&self) -> String {
+        self.new.as_ref().map(Value::to_string).unwrap_or_default()
+    }
+}
+
+fn fields(value: Option<&Value>) -> impl Iterator<Item = (&String, &Value)> {
+    value
+        .and_then(Value::as_object)
+        .into_iter()
+        .flat_map(|object| object.iter())
+}
+
+/// Compares the serialized forms of a row before and after a change, field by field.
+pub(crate) fn diff(old: Option<&Value>, new: Option<&Value>) -> Vec<Change> {
+    let names = fields(old)
+        .chain(fields(new))
+        .map(|(name, _)| name)
+        .collect::<BTreeSet<_>>();
+    names
+        .into_iter()
+        .filter_map(|name| {
+            let old = old.and_then(|old| old.get(name));
+            let new = new.and_then(|new| new.get(name));
+            if old == new {
+                None
+            } else {
+                Some(Change {
+                    field: name.clone(),
+                    old: old.cloned(),
+                    new: new.cloned(),
+                })
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::audit::{diff, Action};
+    use crate::db::Db;
+    use crate::user::User;
+    use failure::Fallible;
+    use serde_json::json;
+
+    #[test]
+    fn test_diff() {
+        let old = json!({"name": "a", "admin": true, "same": 1});
+        let new = json!({"name": "b", "email": "b@example.com", "same": 1});
+        let changes = diff(Some(&old), Some(&new));
+        let summary = changes
+            .iter()
+            .map(|change| (change.field.as_str(), change.old_json(), change.new_json()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            summary,
+            vec![
+                ("admin", "true".to_owned(), "".to_owned()),
+                ("email", "".to_owned(), "\"b@example.com\"".to_owned()),
+                ("name", "\"a\"".to_owned(), "\"b\"".to_owned()),
+            ]
+        );
+        assert!(diff(Some(&old), Some(&old)).is_empty());
+    }
+
+    #[test]
+    fn test() -> Fallible<()> {
+        let mut db = Db::open_memory()?;
+        db.set_actor(Some(7));
+        let mut user = User::test_user();
+        db.save(&mut user)?;
+        db.save(&mut user)?;
+        user.name = "renamed".to_owned();
+        db.save(&mut user)?;
+        db.set_actor(None);
+        db.delete::<User>(user.barcode)?;
+
+        let log = db.audit_log(|_| true, 10)?;
+        let actions = log
+            .iter()
+            .map(|entry| (entry.action, entry.actor, entry.tree.as_str(), entry.id))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            actions,
+            vec![
+                (Action::Delete, None, "users", 0),
+                (Action::Update, Some(7), "users", 0),
+                (Action::Create, Some(7), "users", 0),
+            ]
+        );
+        assert_eq!(log[1].changes.len(), 1);
+        assert_eq!(log[1].changes[0].field, "name");
+        assert_eq!(
+            db.audit_log(|entry| entry.action == Action::Create, 10)?,
+            vec![log[2].clone()]
+        );
+        assert_eq!(db.audit_log(|_| true, 1)?.len(), 1);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,156 @@
+// SPDX-License-Identifier: AGPL-3.0-only
+
+use crate::db::Db;
+use crate::user::User;
+use failure::{ensure, Fallible};
+use serde::Deserialize;
+
+/// Computes the Luhn (mod 10) check digit for the ASCII digits in `s`. Anything else, such as a
+/// letter prefix, is skipped.
+#[allow(clippy::cast_possible_truncation)]
+pub(crate) fn check_digit(s: &str) -> u8 {
+    let sum: u32 = s
+        .bytes()
+        .rev()
+        .filter(u8::is_ascii_digit)
+        .map(|b| u32::from(b - b'0'))
+        .enumerate()
+        .map(|(i, d)| match d * 2 {
+            doubled if i % 2 == 0 && doubled > 9 => doubled - 9,
+            doubled if i % 2 == 0 => doubled,
+            _ => d,
+        })
+        .sum();
+    ((10 - sum % 10) % 10) as u8
+}
+
+/// Checks that the last character of a scanned barcode is the correct check digit for the rest of
+/// it.
+pub(crate) fn is_valid(barcode: &str) -> bool {
+    match barcode.as_bytes().split_last() {
+        Some((last, rest)) if last.is_ascii_digit() && rest.iter().any(u8::is_ascii_digit) => {
+            check_digit(&barcode[..rest.len()]) == last - b'0'
+        }
+        _ => false,
+    }
+}
+
+/// The shape of the barcodes we print on stickers: a fixed prefix, a zero-padded sequence number,
+/// and a check digit.
+#[derive(Debug, Clone, Deserialize)]
+pub(crate) struct BarcodeFormat {
+    pub(crate) prefix: String,
+    pub(crate) digits: usize,
+}
+
+/// The formats `barcode` allocates, from the `[barcodes]` section of `config.toml`.
+#[derive(Debug, Deserialize)]
+#[serde(default)]
+pub(crate) struct BarcodeConfig {
+    pub(crate) item: BarcodeFormat,
+    /// User barcodes are numbers, so this prefix must be numeric too.
+    pub(crate) user: BarcodeFormat,
+}
+
+impl Default for BarcodeConfig {
+    fn default() -> BarcodeConfig {
+        BarcodeConfig {
+            item: BarcodeFormat {
+                prefix: "LESB".to_owned(),
+                digits: 6,
+            },
+            user: BarcodeFormat {
+                prefix: "2".to_owned(),
+                digits: 8,
+            },
+        }
+    }
+}
+
+impl BarcodeFormat {
+    pub(crate) fn barcode(&self, sequence: u64) -> Fallible<String> {
+        let mut barcode = format!("{}{:02$}", self.prefix, sequence, self.digits);
+        ensure!(
+            barcode.len() == self.prefix.len() + self.digits,
+            "sequence number {} does not fit in {} digits",
+            sequence,
+            self.digits
+        );
+        barcode.push(char::from(b'0' + check_digit(&barcode)));
+        Ok(barcode)
+    }
+
+    /// Checks that a scanned barcode has this format's prefix and length and a valid check digit.
+    pub(crate) fn validate(&self, barcode: &str) -> bool {
+        barcode.len() == self.prefix.len() + self.digits + 1
+            && barcode.starts_with(&self.prefix)
+            && barcode[self.prefix.len()..]
+                .bytes()
+                .all(|b| b.is_ascii_digit())
+            && is_valid(barcode)
+    }
+
+    /// Allocates the next unused inventory barcode for an `Item`.
+    pub(crate) fn next_item_barcode(&self, db: &Db) -> Fallible<String> {
+        loop {
+            let barcode = self.barcode(db.next_sequence("item-barcode")?)?;
+            if db.load_by_barcode(&barcode)?.is_none() {
+                return Ok(barcode);
+            }
+        }
+    }
+
+    /// Allocates the next unused `User` barcode. The prefix must be numeric.
+    pub(crate) fn next_user_barcode(&self, db: &Db) -> Fallible<u64> {
+        loop {
+            let barcode = self.barcode(db.next_sequence("user-barcode")?)?.parse()?;
+            if db.load::<User>(barcode)?.is_none() {
+                return Ok(barcode);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{check_digit, is_valid, BarcodeFormat};
+    use crate::db::Db;
+    use crate::item::Item;
+    use failure::Fallible;
+
+    #[test]
+    fn test_check_digit() {
+        assert_eq!(check_digit("7992739871"), 3);
+        assert_eq!(check_digit("LESB000001"), 8);
+        assert!(is_valid("79927398713"));
+        assert!(!is_valid("79927398710"));
+        assert!(!is_valid("LESB"));
+    }
+
+    #[test]
+    fn test_allocate() -> Fallible<()> {
+        let mut db = Db::open_memory()?;
+        let format = BarcodeFormat {
+            prefix: "LESB".to_owned(),
+            digits: 6,
+        };
+        assert_eq!(format.next_item_barcode(&db)?, "LESB0000018");
+
+        let mut item = Item::test_item();
+        item.barcode = Some(format.barcode(2)?);
+        db.save(&mut item)?;
+        let barcode = format.next_item_barcode(&db)?;
+        assert_eq!(barcode, "LESB0000034");
+        assert!(format.validate(&barcode));
+        assert!(!format.validate("LESB0000035"));
+        assert!(!format.validate("LEZB0000034"));
+
+        let format = BarcodeFormat {
+            prefix: "2".to_owned(),
+            digits: 8,
+        };
+        assert_eq!(format.next_user_barcode(&db)?, 2_000_000_014);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,462 @@
+// SPDX-License-Identifier: AGPL-3.0-only
+
+use crate::db::Db;
+use crate::format::Format;
+use crate::hold::Hold;
+use crate::item::Item;
+use crate::lesb::LESBClassification;
+use crate::loan::{Loan, Renewal};
+use crate::user::User;
+use chrono::{DateTime, Duration, Utc};
+use failure::{bail, ensure, Fallible};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Deserializer};
+use std::collections::HashMap;
+use std::hash::Hash;
+
+/// How long items can be borrowed for and how many a user can have at once.
+///
+/// Rules for an item's classification take precedence over rules for its format, which take
+/// precedence over the defaults.
+#[derive(Debug, Deserialize)]
+#[serde(default)]
+pub(crate) struct LoanPolicy {
+    pub(crate) days: i64,
+    pub(crate) max_loans: usize,
+    pub(crate) max_renewals: u32,
+    #[serde(deserialize_with = "deserialize_rules")]
+    pub(crate) format: HashMap<Format, LoanRule>,
+    #[serde(deserialize_with = "deserialize_rules")]
+    pub(crate) classification: HashMap<LESBClassification, LoanRule>,
+}
+
+/// TOML table keys are always plain strings, so parse them the same way the values would be.
+fn deserialize_rules<'de, D, K>(deserializer: D) -> Result<HashMap<K, LoanRule>, D::Error>
+where
+    D: Deserializer<'de>,
+    K: DeserializeOwned + Eq + Hash,
+{
+    HashMap::<String, LoanRule>::deserialize(deserializer)?
+        .into_iter()
+        .map(|(key, rule)| {
+            serde_plain::from_str(&key)
+                .map(|key| (key, rule))
+                .map_err(serde::de::Error::custom)
+        })
+        .collect()
+}
+
+#[derive(Debug, Default, Deserialize)]
+pub(crate) struct LoanRule {
+    pub(crate) days: Option<i64>,
+    pub(crate) max_renewals: Option<u32>,
+}
+
+impl Default for LoanPolicy {
+    fn default() -> LoanPolicy {
+        LoanPolicy {
+            days: 21,
+            max_loans: 10,
+            max_renewals: 2,
+            format: HashMap::new(),
+            classification: HashMap::new(),
+        }
+    }
+}
+
+impl LoanPolicy {
+    fn rule<T, F>(&self, item: &Item, f: F) -> Option<T>
+    where
+        F: Fn(&LoanRule) -> Option<T>,
+    {
+        self.classification
+            .get(&item.classification)
+            .and_then(&f)
+            .or_else(|| self.format.get(&item.format).and_then(&f))
+    }
+
+    pub(crate) fn loan_period(&self, item: &Item) -> Duration {
+        Duration::days(self.rule(item, |rule| rule.days).unwrap_or(self.days))
+    }
+
+    pub(crate) fn max_renewals(&self, item: &Item) -> u32 {
+        self.rule(item, |rule| rule.max_renewals)
+            .unwrap_or(self.max_renewals)
+    }
+}
+
+pub(crate) fn check_out(
+    db: &mut Db,
+    policy: &LoanPolicy,
+    item: &mut Item,
+    user: &User,
+) -> Fallible<()> {
+    let id = item
+        .id()
+        .ok_or_else(|| failure::err_msg("item must be saved before it is checked out"))?;
+    ensure!(
+        !item.is_checked_out(),
+        "{:?} is already checked out",
+        item.title
+    );
+    check_not_blocked(user)?;
+    let hold = db.holds_for_item(id)?.into_iter().next();
+    if let Some(hold) = &hold {
+        ensure!(
+            hold.user == user.barcode && hold.is_ready(),
+            "{:?} is on hold for someone else",
+            item.title
+        );
+    }
+    let max_loans = user.loan_limit.unwrap_or(policy.max_loans);
+    ensure!(
+        db.loans_for_user(user.barcode)?.len() < max_loans,
+        "{} already has {} items checked out",
+        user.name,
+        max_loans
+    );
+
+    if let Some(hold_id) = hold.and_then(|hold| hold.id()) {
+        db.delete::<Hold>(hold_id)?;
+    }
+    let due = Utc::now() + policy.loan_period(item);
+    item.loan = Some(Loan::new(id, user.barcode, due));
+    db.save(item)
+}
+
+fn check_not_blocked(user: &User) -> Fallible<()> {
+    if user.blocked {
+        match &user.blocked_reason {
+            Some(reason) => bail!("{} is blocked: {}", user.name, reason),
+            None => bail!("{} is blocked", user.name),
+        }
+    }
+    Ok(())
+}
+
+/// Pushes back the due date of an item's current loan, as long as nobody is waiting for it, the
+/// loan hasn't been renewed too many times already, and the borrower isn't blocked.
+pub(crate) fn renew(db: &mut Db, policy: &LoanPolicy, item: &mut Item) -> Fallible<()> {
+    let id = item
+        .id()
+        .ok_or_else(|| failure::err_msg("item must be saved before it is renewed"))?;
+    ensure!(
+        db.holds_for_item(id)?.is_empty(),
+        "{:?} is on hold for someone else",
+        item.title
+    );
+    let max_renewals = policy.max_renewals(item);
+    let period = policy.loan_period(item);
+    let title = &item.title;
+    let loan = item
+        .loan
+        .as_mut()
+        .ok_or_else(|| failure::err_msg(format!("{:?} is not checked out", title)))?;
+    ensure!(
+        loan.renewals.len() < max_renewals as usize,
+        "{:?} has already been renewed {} times",
+        title,
+        loan.renewals.len()
+    );
+    if let Some(user) = db.load::<User>(loan.user)? {
+        check_not_blocked(&user)?;
+    }
+
+    let now = Utc::now();
+    loan.renewals.push(Renewal {
+        renewed: now,
+        previous_due: loan.due,
+    });
+    loan.due = std::cmp::max(loan.due, now + period);
+    db.save(item)
+}
+
+/// Returns an item, moving its current loan into the loan history. If anyone is waiting for the
+/// item, it goes on the hold shelf for the first person in line, whose hold is returned.
+pub(crate) fn check_in(db: &mut Db, item: &mut Item) -> Fallible<(Loan, Option<Hold>)> {
+    let mut loan = item
+        .loan
+        .take()
+        .ok_or_else(|| failure::err_msg(format!("{:?} is not checked out", item.title)))?;
+    loan.returned = Some(Utc::now());
+    db.save(&mut loan)?;
+    db.save(item)?;
+    let hold = shelve(db, loan.item, 0)?;
+    Ok((loan, hold))
+}
+
+/// Marks the hold at `position` in an item's queue as ready on the hold shelf.
+fn shelve(db: &mut Db, item: u64, position: usize) -> Fallible<Option<Hold>> {
+    Ok(match db.holds_for_item(item)?.into_iter().nth(position) {
+        Some(mut hold) => {
+            hold.ready = Some(Utc::now());
+            db.save(&mut hold)?;
+            Some(hold)
+        }
+        None => None,
+    })
+}
+
+/// Puts a user in line for an item that someone else has.
+pub(crate) fn place_hold(db: &mut Db, item: &Item, user: &User) -> Fallible<Hold> {
+    let id = item
+        .id()
+        .ok_or_else(|| failure::err_msg("item must be saved before it is put on hold"))?;
+    let holds = db.holds_for_item(id)?;
+    ensure!(
+        item.is_checked_out() || !holds.is_empty(),
+        "{:?} is available, so it can't be put on hold",
+        item.title
+    );
+    ensure!(
+        item.borrower() != Some(user.barcode),
+        "{} already has {:?} checked out",
+        user.name,
+        item.title
+    );
+    ensure!(
+        holds.iter().all(|hold| hold.user != user.barcode),
+        "{} already has a hold on {:?}",
+        user.name,
+        item.title
+    );
+
+    let mut hold = Hold::new(id, user.barcode);
+    db.save(&mut hold)?;
+    Ok(hold)
+}
+
+/// Takes a user out of line for an item. If the item was waiting on the hold shelf for them, it
+/// goes to the next person in line.
+pub(crate) fn cancel_hold(db: &mut Db, item: &Item, user: &User) -> Fallible<()> {
+    let id = item
+        .id()
+        .ok_or_else(|| failure::err_msg("item must be saved before it is put on hold"))?;
+    let hold = db
+        .holds_for_item(id)?
+        .into_iter()
+        .find(|hold| hold.user == user.barcode)
+        .ok_or_else(|| {
+            failure::err_msg(format!("{} has no hold on {:?}", user.name, item.title))
+        })?;
+    if let Some(hold_id) = hold.id() {
+        db.delete::<Hold>(hold_id)?;
+    }
+    if hold.is_ready() {
+        shelve(db, id, 0)?;
+    }
+    Ok(())
+}
+
+/// An item that should have come back by now.
+#[derive(Debug)]
+pub(crate) struct Overdue {
+    pub(crate) item: Item,
+    pub(crate) due: DateTime<Utc>,
+    pub(crate) borrower: String,
+}
+
+impl Overdue {
+    pub(crate) fn due_date(&self) -> String {
+        self.due.format("%Y-%m-%d").to_string()
+    }
+}
+
+/// Lists every item whose loan was due before `now`, most overdue first.
+pub(crate) fn overdue(db: &Db, now: DateTime<Utc>) -> Fallible<Vec<Overdue>> {
+    let mut report = Vec::new();
+    for item in db.iter::<Item>()? {
+        let item = item?;
+        let (due, user) = match &item.loan {
+            Some(loan) if loan.due < now => (loan.due, loan.user),
+            _ => continue,
+        };
+        let borrower = match db.load::<User>(user)? {
+            Some(user) => user.name,
+            None => format!("unknown user {}", user),
+        };
+        report.push(Overdue {
+            item,
+            due,
+            borrower,
+        });
+    }
+    report.sort_by_key(|entry| entry.due);
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        cancel_hold, check_in, check_out, overdue, place_hold, renew, LoanPolicy, LoanRule,
+    };
+    use crate::db::Db;
+    use crate::format::Format;
+    use crate::item::Item;
+    use crate::lesb::LESBClassification;
+    use crate::user::User;
+    use chrono::{Duration, Utc};
+    use failure::Fallible;
+
+    #[test]
+    fn test() -> Fallible<()> {
+        let mut db = Db::open_memory()?;
+        let policy = LoanPolicy::default();
+        let mut user = User::test_user();
+        db.save(&mut user)?;
+        let mut item = Item::test_item();
+        db.save(&mut item)?;
+
+        check_out(&mut db, &policy, &mut item, &user)?;
+        assert!(check_out(&mut db, &policy, &mut item, &user).is_err());
+        let loaded_item: Item = db.load(item.id().unwrap())?.unwrap();
+        assert_eq!(loaded_item.borrower(), Some(user.barcode));
+        assert_eq!(loaded_item.loan, item.loan);
+
+        assert!(overdue(&db, Utc::now())?.is_empty());
+        let report = overdue(&db, Utc::now() + Duration::days(22))?;
+        assert_eq!(report.len(), 1);
+        assert_eq!(report[0].borrower, user.name);
+
+        let (loan, hold) = check_in(&mut db, &mut item)?;
+        assert!(loan.returned.is_some());
+        assert!(hold.is_none());
+        assert!(check_in(&mut db, &mut item).is_err());
+        let loaded_item: Item = db.load(item.id().unwrap())?.unwrap();
+        assert!(!loaded_item.is_checked_out());
+        assert_eq!(db.loan_history(item.id().unwrap())?, vec![loan]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_policy() -> Fallible<()> {
+        let mut policy = LoanPolicy {
+            max_loans: 1,
+            ..LoanPolicy::default()
+        };
+        policy.format.insert(
+            Format::Hardcover,
+            LoanRule {
+                days: Some(7),
+                max_renewals: Some(1),
+            },
+        );
+        policy.classification.insert(
+            LESBClassification::NI,
+            LoanRule {
+                days: Some(30),
+                max_renewals: None,
+            },
+        );
+
+        let mut item = Item::test_item();
+        assert_eq!(policy.loan_period(&item), Duration::days(30));
+        assert_eq!(policy.max_renewals(&item), 1);
+        item.classification = LESBClassification::LX;
+        assert_eq!(policy.loan_period(&item), Duration::days(7));
+        item.format = Format::Zine;
+        assert_eq!(policy.loan_period(&item), Duration::days(21));
+        assert_eq!(policy.max_renewals(&item), 2);
+
+        let mut db = Db::open_memory()?;
+        let user = User::test_user();
+        let mut other = Item::test_item();
+        db.save(&mut item)?;
+        db.save(&mut other)?;
+        check_out(&mut db, &policy, &mut item, &user)?;
+        assert!(check_out(&mut db, &policy, &mut other, &user).is_err());
+        let user = User {
+            loan_limit: Some(2),
+            ..user
+        };
+        check_out(&mut db, &policy, &mut other, &user)?;
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_holds() -> Fallible<()> {
+        let mut db = Db::open_memory()?;
+        let policy = LoanPolicy::default();
+        let alice = User {
+            barcode: 1,
+            ..User::test_user()
+        };
+        let bea = User {
+            barcode: 2,
+            ..User::test_user()
+        };
+        let cass = User {
+            barcode: 3,
+            ..User::test_user()
+        };
+        let mut item = Item::test_item();
+        db.save(&mut item)?;
+        let id = item.id().unwrap();
+
+        assert!(place_hold(&mut db, &item, &bea).is_err());
+        check_out(&mut db, &policy, &mut item, &alice)?;
+        assert!(place_hold(&mut db, &item, &alice).is_err());
+        place_hold(&mut db, &item, &bea)?;
+        assert!(place_hold(&mut db, &item, &bea).is_err());
+        place_hold(&mut db, &item, &cass)?;
+        assert_eq!(db.holds_for_item(id)?.len(), 2);
+        assert_eq!(db.holds_for_user(bea.barcode)?.len(), 1);
+
+        let (_, hold) = check_in(&mut db, &mut item)?;
+        assert_eq!(hold.map(|hold| hold.user), Some(bea.barcode));
+        assert!(check_out(&mut db, &policy, &mut item, &cass).is_err());
+
+        cancel_hold(&mut db, &item, &bea)?;
+        let holds = db.holds_for_item(id)?;
+        assert_eq!(holds.len(), 1);
+        assert!(holds[0].is_ready());
+        check_out(&mut db, &policy, &mut item, &cass)?;
+        assert!(db.holds_for_item(id)?.is_empty());
+        assert!(db.holds_for_user(cass.barcode)?.is_empty());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_renew() -> Fallible<()> {
+        let mut db = Db::open_memory()?;
+        let policy = LoanPolicy {
+            max_renewals: 1,
+            ..LoanPolicy::default()
+        };
+        let mut user = User::test_user();
+        db.save(&mut user)?;
+        let other = User {
+            barcode: 1,
+            ..User::test_user()
+        };
+        let mut item = Item::test_item();
+        db.save(&mut item)?;
+
+        assert!(renew(&mut db, &policy, &mut item).is_err());
+        check_out(&mut db, &policy, &mut item, &user)?;
+        let due = item.loan.as_ref().unwrap().due;
+        renew(&mut db, &policy, &mut item)?;
+        assert!(item.loan.as_ref().unwrap().due > due);
+        assert!(renew(&mut db, &policy, &mut item).is_err());
+
+        let policy = LoanPolicy::default();
+        user.block(Some("lost card".to_owned()));
+        db.save(&mut user)?;
+        let err = renew(&mut db, &policy, &mut item).unwrap_err();
+        assert_eq!(err.to_string(), "test user is blocked: lost card");
+        user.unblock();
+        db.save(&mut user)?;
+        place_hold(&mut db, &item, &other)?;
+        assert!(renew(&mut db, &policy, &mut item).is_err());
+
+        let (loan, _) = check_in(&mut db, &mut item)?;
+        assert_eq!(loan.renewals.len(), 1);
+        assert_eq!(loan.renewals[0].previous_due, due);
+        assert_eq!(db.loan_history(item.id().unwrap())?, vec![loan]);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,355 @@
+// SPDX-License-Identifier: AGPL-3.0-only
+
+use crate::format::Format;
+use crate::item::Item;
+use failure::Fallible;
+use serde::Deserialize;
+use serde_json::{json, Map, Value};
+use std::collections::HashMap;
+use std::io::prelude::*;
+
+const MONTHS: [&str; 12] = [
+    "jan", "feb", "mar", "apr", "may", "jun", "jul", "aug", "sep", "oct", "nov", "dec",
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) enum CitationFormat {
+    Bibtex,
+    Ris,
+    CslJson,
+}
+
+impl CitationFormat {
+    /// Returns the format for a file extension, like the `bib` in `/item/1.bib`.
+    pub(crate) fn from_extension(extension: &str) -> Option<CitationFormat> {
+        match extension {
+            "bib" => Some(CitationFormat::Bibtex),
+            "ris" => Some(CitationFormat::Ris),
+            "csl.json" => Some(CitationFormat::CslJson),
+            _ => None,
+        }
+    }
+
+    pub(crate) fn content_type(self) -> &'static str {
+        match self {
+            CitationFormat::Bibtex => "application/x-bibtex; charset=utf-8",
+            CitationFormat::Ris => "application/x-research-info-systems; charset=utf-8",
+            CitationFormat::CslJson => "application/vnd.citationstyles.csl+json",
+        }
+    }
+}
+
+/// The kinds of work citation formats tell apart.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Kind {
+    Book,
+    Periodical,
+    Pamphlet,
+    Recording,
+}
+
+impl Kind {
+    fn new(format: Format) -> Kind {
+        match format {
+            Format::Paperback | Format::Hardcover => Kind::Book,
+            Format::Magazine => Kind::Periodical,
+            Format::Zine => Kind::Pamphlet,
+            Format::CD
+            | Format::Vinyl12Inch
+            | Format::Vinyl10Inch
+            | Format::Vinyl7Inch
+            | Format::Cassette => Kind::Recording,
+        }
+    }
+}
+
+/// Returns a citation key like "lorde1984", from the first author's family name or the first
+/// word of the title.
+fn base_key(item: &Item) -> String {
+    let name = match item.authors.first() {
+        Some(author) => author.split(',').next().unwrap_or_default(),
+        None => item.title.split_whitespace().next().unwrap_or_default(),
+    };
+    let mut key: String = deunicode::deunicode(name)
+        .chars()
+        .filter(char::is_ascii_alphanumeric)
+        .map(|c| c.to_ascii_lowercase())
+        .collect();
+    if key.is_empty() {
+        key.push_str("item");
+    }
+    if let Some(date) = item.original_date {
+        key.push_str(&date.year().to_string());
+    }
+    key
+}
+
+/// Gives every item a distinct key, adding "b", "c" and so on to repeats, like "lorde1984b".
+fn keys(items: &[Item]) -> Vec<String> {
+    let mut seen = HashMap::new();
+    items
+        .iter()
+        .map(|item| {
+            let key = base_key(item);
+            let count = seen.entry(key.clone()).or_insert(0);
+            *count += 1;
+            if *count == 1 {
+                return key;
+            }
+            match "abcdefghijklmnopqrstuvwxyz".chars().nth(*count - 1) {
+                Some(suffix) => format!("{}{}", key, suffix),
+                None => format!("{}-{}", key, count),
+            }
+        })
+        .collect()
+}
+
+fn bibtex_escape(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '\\' => escaped.push_str("\\textbackslash{}"),
+            '~' => escaped.push_str("\\textasciitilde{}"),
+            '^' => escaped.push_str("\\textasciicircum{}"),
+            '{' | '}' | '&' | '%' | '$' | '#' | '_' => {
+                escaped.push('\\');
+                escaped.push(c);
+            }
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+fn bibtex(item: &Item, key: &str) -> String {
+    let kind = Kind::new(item.format);
+    let entry_type = match kind {
+        Kind::Book => "book",
+        Kind::Periodical => "periodical",
+        Kind::Pamphlet => "booklet",
+        Kind::Recording => "misc",
+    };
+    let mut fields = Vec::new();
+    if !item.authors.is_empty() {
+        fields.push(("author", bibtex_escape(&item.authors.join(" and "))));
+    }
+    // Double braces keep styles from changing the title's capitalization.
+    fields.push(("title", format!("{{{}}}", bibtex_escape(&item.title))));
+    if let Some(date) = item.original_date {
+        fields.push(("year", date.year().to_string()));
+    }
+    if kind == Kind::Periodical {
+        if let Some((volume, issue)) = item.volume_and_issue {
+            fields.push(("volume", volume.to_string()));
+            fields.push(("number", issue.to_string()));
+        }
+    }
+    if kind == Kind::Recording {
+        fields.push(("howpublished", item.format.to_string()));
+    }
+    if let Some(isbn) = &item.isbn13 {
+        fields.push(("isbn", isbn.clone()));
+    }
+    if let Some(issn) = &item.issn {
+        fields.push(("issn", issn.clone()));
+    }
+
+    let mut entry = format!("@{}{{{},\n", entry_type, key);
+    for (name, value) in fields {
+        entry.push_str(&format!("  {} = {{{}}},\n", name, value));
+    }
+    // Month macros aren't braced.
+    if let Some((month, _)) = item.original_date.and_then(|date| date.1) {
+        if let Some(month) = MONTHS.get(usize::from(month).wrapping_sub(1)) {
+            entry.push_str(&format!("  month = {},\n", month));
+        }
+    }
+    entry.push_str("}\n");
+    entry
+}
+
+fn ris(item: &Item, key: &str) -> String {
+    let kind = Kind::new(item.format);
+    let entry_type = match kind {
+        Kind::Book => "BOOK",
+        Kind::Periodical => "MGZN",
+        Kind::Pamphlet => "PAMP",
+        Kind::Recording => "SOUND",
+    };
+    let mut tags = vec![("TY", entry_type.to_owned()), ("ID", key.to_owned())];
+    for author in &item.authors {
+        tags.push(("AU", author.clone()));
+    }
+    tags.push(("TI", item.title.clone()));
+    if let Some(date) = item.original_date {
+        tags.push(("PY", date.year().to_string()));
+        if let Some((month, day)) = date.1 {
+            let day = day.map(|day| format!("{:02}", day)).unwrap_or_default();
+            tags.push(("DA", format!("{:04}/{:02}/{}/", date.year(), month, day)));
+        }
+    }
+    if kind == Kind::Periodical {
+        if let Some((volume, issue)) = item.volume_and_issue {
+            tags.push(("VL", volume.to_string()));
+            tags.push(("IS", issue.to_string()));
+        }
+    }
+    if let Some(isbn) = &item.isbn13 {
+        tags.push(("SN", isbn.clone()));
+    }
+    if let Some(issn) = &item.issn {
+        tags.push(("SN", issn.clone()));
+    }
+    tags.push(("LA", item.language.clone()));
+    tags.push(("M3", item.format.to_string()));
+
+    let mut entry = String::new();
+    for (tag, value) in tags {
+        entry.push_str(&format!("{}  - {}\r\n", tag, value));
+    }
+    entry.push_str("ER  - \r\n");
+    entry
+}
+
+fn csl_name(author: &str) -> Value {
+    let mut parts = author.splitn(2, ',');
+    match (parts.next(), parts.next()) {
+        (Some(family), Some(given)) => json!({
+            "family": family.trim(),
+            "given": given.trim(),
+        }),
+        _ => json!({ "literal": author }),
+    }
+}
+
+fn csl_json(item: &Item, key: &str) -> Value {
+    let kind = Kind::new(item.format);
+    let entry_type = match kind {
+        Kind::Book => "book",
+        Kind::Periodical => "periodical",
+        Kind::Pamphlet => "pamphlet",
+        Kind::Recording => "song",
+    };
+    let mut csl = Map::new();
+    csl.insert("id".to_owned(), json!(key));
+    csl.insert("type".to_owned(), json!(entry_type));
+    csl.insert("title".to_owned(), json!(item.title));
+    if !item.authors.is_empty() {
+        let authors = item.authors.iter().map(|author| csl_name(author));
+        csl.insert("author".to_owned(), Value::Array(authors.collect()));
+    }
+    if let Some(date) = item.original_date {
+        let mut parts = vec![date.year()];
+        if let Some((month, day)) = date.1 {
+            parts.push(u16::from(month));
+            parts.extend(day.map(u16::from));
+        }
+        csl.insert("issued".to_owned(), json!({ "date-parts": [parts] }));
+    }
+    if kind == Kind::Periodical {
+        if let Some((volume, issue)) = item.volume_and_issue {
+            csl.insert("volume".to_owned(), json!(volume.to_string()));
+            csl.insert("issue".to_owned(), json!(issue.to_string()));
+        }
+    }
+    if let Some(isbn) = &item.isbn13 {
+        csl.insert("ISBN".to_owned(), json!(isbn));
+    }
+    if let Some(issn) = &item.issn {
+        csl.insert("ISSN".to_owned(), json!(issn));
+    }
+    csl.insert("language".to_owned(), json!(item.language));
+    csl.insert("medium".to_owned(), json!(item.format.to_string()));
+    Value::Object(csl)
+}
+
+/// Writes a citation for each item.
+pub(crate) fn write_citations<W: Write>(
+    mut writer: W,
+    format: CitationFormat,
+    items: &[Item],
+) -> Fallible<()> {
+    let keys = keys(items);
+    let entries = items.iter().zip(keys.iter());
+    match format {
+        CitationFormat::Bibtex => {
+            for (i, (item, key)) in entries.enumerate() {
+                if i > 0 {
+                    writer.write_all(b"\n")?;
+                }
+                writer.write_all(bibtex(item, key).as_bytes())?;
+            }
+        }
+        CitationFormat::Ris => {
+            for (item, key) in entries {
+                writer.write_all(ris(item, key).as_bytes())?;
+            }
+        }
+        CitationFormat::CslJson => {
+            let csl = entries
+                .map(|(item, key)| csl_json(item, key))
+                .collect::<Vec<_>>();
+            serde_json::to_writer_pretty(&mut writer, &csl)?;
+            writer.write_all(b"\n")?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::citation::{write_citations, CitationFormat};
+    use crate::date::PartialDate;
+    use crate::format::Format;
+    use crate::item::Item;
+    use failure::Fallible;
+    use serde_json::{json, Value};
+
+    fn render(format: CitationFormat, items: &[Item]) -> Fallible<String> {
+        let mut out = Vec::new();
+        write_citations(&mut out, format, items)?;
+        Ok(String::from_utf8(out)?)
+    }
+
+    #[test]
+    fn test() -> Fallible<()> {
+        let mut book = Item::test_item();
+        book.title = "Color problems & 100% more".to_owned();
+        let mut magazine = Item::test_item();
+        magazine.format = Format::Magazine;
+        magazine.authors.clear();
+        magazine.title = "Sinister Wisdom".to_owned();
+        magazine.original_date = Some(PartialDate(1976, Some((7, None))));
+        magazine.volume_and_issue = Some((1, 1));
+        magazine.isbn13 = None;
+        magazine.issn = Some("0196-1853".to_owned());
+        let items = vec![book, Item::test_item(), magazine];
+
+        let bibtex = render(CitationFormat::Bibtex, &items)?;
+        assert!(bibtex.starts_with("@book{vanderpoel1902,\n"));
+        assert!(bibtex.contains("  title = {{Color problems \\& 100\\% more}},\n"));
+        assert!(bibtex.contains("@book{vanderpoel1902b,\n"));
+        assert!(bibtex.contains("@periodical{sinister1976,\n"));
+        assert!(bibtex.contains("  number = {1},\n  issn = {0196-1853},\n  month = jul,\n}\n"));
+
+        let ris = render(CitationFormat::Ris, &items)?;
+        assert!(ris.starts_with("TY  - BOOK\r\nID  - vanderpoel1902\r\n"));
+        assert!(ris.contains("AU  - Vanderpoel, Emily Noyes\r\n"));
+        assert!(ris.contains("TY  - MGZN\r\n"));
+        assert!(ris.contains("DA  - 1976/07//\r\nVL  - 1\r\nIS  - 1\r\nSN  - 0196-1853\r\n"));
+        assert_eq!(ris.matches("ER  - \r\n").count(), 3);
+
+        let csl: Value = serde_json::from_str(&render(CitationFormat::CslJson, &items)?)?;
+        assert_eq!(
+            csl[0]["author"],
+            json!([{"family": "Vanderpoel", "given": "Emily Noyes"}])
+        );
+        assert_eq!(csl[0]["ISBN"], "9780999609934");
+        assert_eq!(csl[2]["type"], "periodical");
+        assert_eq!(csl[2]["issued"], json!({"date-parts": [[1976, 7]]}));
+        assert_eq!(csl[2]["issue"], "1");
+
+        Ok(())
+    }
+}
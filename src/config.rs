@@ -0,0 +1,75 @@
+// SPDX-License-Identifier: AGPL-3.0-only
+
+use crate::barcode::BarcodeConfig;
+use crate::circulation::LoanPolicy;
+use crate::lookup::LookupConfig;
+use crate::replication::Remote;
+use failure::Fallible;
+use serde::Deserialize;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+/// Site configuration, read from `config.toml` in the database directory if it exists.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+pub(crate) struct Config {
+    /// The public URL of the web interface, like `https://library.example`, for absolute links in
+    /// feeds. Defaults to the request's `Host` header.
+    pub(crate) base_url: Option<String>,
+    /// The contact address in the OAI-PMH `Identify` response.
+    pub(crate) admin_email: Option<String>,
+    pub(crate) barcodes: BarcodeConfig,
+    pub(crate) loans: LoanPolicy,
+    pub(crate) lookup: LookupConfig,
+    /// The primary server for `push` and `pull`.
+    pub(crate) remote: Option<Remote>,
+}
+
+impl Config {
+    pub(crate) fn load<P: AsRef<Path>>(db_path: P) -> Fallible<Config> {
+        match fs::read_to_string(db_path.as_ref().join("config.toml")) {
+            Ok(s) => Ok(toml::from_str(&s)?),
+            Err(ref err) if err.kind() == io::ErrorKind::NotFound => Ok(Config::default()),
+            Err(err) => Err(err.into()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Config;
+    use crate::format::Format;
+    use crate::lesb::LESBClassification;
+
+    #[test]
+    fn test() {
+        let config: Config = toml::from_str(
+            r#"
+            [loans]
+            days = 14
+
+            [loans.format.vinyl-7-inch]
+            days = 7
+
+            [loans.classification.LX]
+            days = 30
+            max_renewals = 0
+
+            [barcodes.item]
+            prefix = "QUEER"
+            digits = 5
+            "#,
+        )
+        .unwrap();
+        assert_eq!(config.loans.days, 14);
+        assert_eq!(config.loans.max_loans, 10);
+        assert_eq!(config.loans.format[&Format::Vinyl7Inch].days, Some(7));
+        assert_eq!(
+            config.loans.classification[&LESBClassification::LX].max_renewals,
+            Some(0)
+        );
+        assert_eq!(config.barcodes.item.prefix, "QUEER");
+        assert_eq!(config.barcodes.user.digits, 8);
+    }
+}
@@ -0,0 +1,312 @@
+// SPDX-License-Identifier: AGPL-3.0-only
+
+use failure::{bail, ensure, Fallible};
+use std::iter::Peekable;
+use std::vec::IntoIter;
+
+/// Relations that are words rather than symbols, like `dc.title any "velvet tipping"`.
+const NAMED_RELATIONS: &[&str] = &["adj", "all", "any", "encloses", "exact", "within"];
+
+/// How deeply parentheses can nest, so a query can't recurse through the stack.
+const MAX_DEPTH: usize = 32;
+
+/// How many clauses and booleans a query can have, since booleans chain into a tree that's as deep
+/// as it is long.
+const MAX_CLAUSES: usize = 256;
+
+#[derive(Debug, PartialEq)]
+enum Token {
+    Open,
+    Close,
+    Slash,
+    /// A comparison like `=` or `<>`.
+    Symbol(String),
+    Word {
+        text: String,
+        quoted: bool,
+    },
+}
+
+fn tokenize(s: &str) -> Fallible<Vec<Token>> {
+    let mut tokens = Vec::new();
+    let mut chars = s.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            c if c.is_whitespace() => {}
+            '(' => tokens.push(Token::Open),
+            ')' => tokens.push(Token::Close),
+            '/' => tokens.push(Token::Slash),
+            '=' | '<' | '>' => {
+                let mut symbol = c.to_string();
+                if let Some(&next) = chars.peek() {
+                    if (c == '=' && next == '=')
+                        || (c == '<' && (next == '=' || next == '>'))
+                        || (c == '>' && next == '=')
+                    {
+                        symbol.push(next);
+                        chars.next();
+                    }
+                }
+                tokens.push(Token::Symbol(symbol));
+            }
+            '"' => {
+                let mut text = String::new();
+                loop {
+                    match chars.next() {
+                        Some('"') => break,
+                        // Keep escapes other than `\"`, which mean something to masking.
+                        Some('\\') => match chars.next() {
+                            Some('"') => text.push('"'),
+                            Some(c) => {
+                                text.push('\\');
+                                text.push(c);
+                            }
+                            None => bail!("unterminated string"),
+                        },
+                        Some(c) => text.push(c),
+                        None => bail!("unterminated string"),
+                    }
+                }
+                tokens.push(Token::Word { text, quoted: true });
+            }
+            c => {
+                let mut text = c.to_string();
+                while let Some(&next) = chars.peek() {
+                    if next.is_whitespace() || "()/=<>\"".contains(next) {
+                        break;
+                    }
+                    text.push(next);
+                    chars.next();
+                }
+                tokens.push(Token::Word {
+                    text,
+                    quoted: false,
+                });
+            }
+        }
+    }
+    Ok(tokens)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum Boolean {
+    And,
+    Or,
+    Not,
+    Prox,
+}
+
+/// A search clause, like `dc.title = velvet`.
+#[derive(Debug, PartialEq)]
+pub(crate) struct Clause {
+    /// The lowercased index, or `None` for a bare term.
+    pub(crate) index: Option<String>,
+    /// The lowercased relation, which is `=` for a bare term.
+    pub(crate) relation: String,
+    /// Relation modifiers, like `relevant` in `=/relevant`.
+    pub(crate) modifiers: Vec<String>,
+    pub(crate) term: String,
+}
+
+#[derive(Debug, PartialEq)]
+pub(crate) enum Query {
+    Clause(Clause),
+    Boolean(Boolean, Box<Query>, Box<Query>),
+}
+
+struct Parser {
+    tokens: Peekable<IntoIter<Token>>,
+    /// How many parentheses are open.
+    depth: usize,
+    /// How many clauses and booleans have been parsed.
+    clauses: usize,
+}
+
+impl Parser {
+    fn count_clause(&mut self) -> Fallible<()> {
+        self.clauses += 1;
+        ensure!(
+            self.clauses <= MAX_CLAUSES,
+            "queries can only have {} clauses and booleans",
+            MAX_CLAUSES
+        );
+        Ok(())
+    }
+
+    fn word(&mut self) -> Fallible<String> {
+        match self.tokens.next() {
+            Some(Token::Word { text, .. }) => Ok(text),
+            Some(token) => bail!("expected a term, found {:?}", token),
+            None => bail!("expected a term"),
+        }
+    }
+
+    fn boolean(&mut self) -> Option<Boolean> {
+        let boolean = match self.tokens.peek() {
+            Some(Token::Word {
+                text,
+                quoted: false,
+            }) => match text.to_lowercase().as_str() {
+                "and" => Boolean::And,
+                "or" => Boolean::Or,
+                "not" => Boolean::Not,
+                "prox" => Boolean::Prox,
+                _ => return None,
+            },
+            _ => return None,
+        };
+        self.tokens.next();
+        Some(boolean)
+    }
+
+    fn relation(&mut self) -> Option<String> {
+        let relation = match self.tokens.peek() {
+            Some(Token::Symbol(symbol)) => symbol.clone(),
+            Some(Token::Word {
+                text,
+                quoted: false,
+            }) if NAMED_RELATIONS.contains(&text.to_lowercase().as_str()) => text.to_lowercase(),
+            _ => return None,
+        };
+        self.tokens.next();
+        Some(relation)
+    }
+
+    /// Search clauses joined by booleans, which all have the same precedence.
+    fn scoped_clause(&mut self) -> Fallible<Query> {
+        let mut query = self.search_clause()?;
+        while let Some(boolean) = self.boolean() {
+            ensure!(
+                self.tokens.peek() != Some(&Token::Slash),
+                "boolean modifiers aren't supported"
+            );
+            self.count_clause()?;
+            let right = self.search_clause()?;
+            query = Query::Boolean(boolean, Box::new(query), Box::new(right));
+        }
+        Ok(query)
+    }
+
+    fn search_clause(&mut self) -> Fallible<Query> {
+        if self.tokens.peek() == Some(&Token::Open) {
+            self.tokens.next();
+            self.depth += 1;
+            ensure!(
+                self.depth <= MAX_DEPTH,
+                "parentheses can only nest {} deep",
+                MAX_DEPTH
+            );
+            let query = self.scoped_clause()?;
+            ensure!(self.tokens.next() == Some(Token::Close), "expected )");
+            self.depth -= 1;
+            return Ok(query);
+        }
+
+        self.count_clause()?;
+        let first = self.word()?;
+        let relation = match self.relation() {
+            Some(relation) => relation,
+            None => {
+                return Ok(Query::Clause(Clause {
+                    index: None,
+                    relation: "=".to_owned(),
+                    modifiers: Vec::new(),
+                    term: first,
+                }))
+            }
+        };
+        let mut modifiers = Vec::new();
+        while self.tokens.peek() == Some(&Token::Slash) {
+            self.tokens.next();
+            let mut modifier = self.word()?.to_lowercase();
+            if let Some(Token::Symbol(_)) = self.tokens.peek() {
+                modifier.push_str(&self.relation().unwrap_or_default());
+                modifier.push_str(&self.word()?);
+            }
+            modifiers.push(modifier);
+        }
+        Ok(Query::Clause(Clause {
+            index: Some(first.to_lowercase()),
+            relation,
+            modifiers,
+            term: self.word()?,
+        }))
+    }
+}
+
+/// Parses a query in the Contextual Query Language, which SRU clients search with. Prefix
+/// assignments and `sortBy` aren't supported.
+pub(crate) fn parse(s: &str) -> Fallible<Query> {
+    let mut parser = Parser {
+        tokens: tokenize(s)?.into_iter().peekable(),
+        depth: 0,
+        clauses: 0,
+    };
+    let query = parser.scoped_clause()?;
+    if let Some(token) = parser.tokens.next() {
+        bail!("unexpected {:?}", token);
+    }
+    Ok(query)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse, Boolean, Clause, Query};
+
+    fn clause(index: Option<&str>, relation: &str, term: &str) -> Query {
+        Query::Clause(Clause {
+            index: index.map(str::to_owned),
+            relation: relation.to_owned(),
+            modifiers: Vec::new(),
+            term: term.to_owned(),
+        })
+    }
+
+    #[test]
+    fn test() {
+        assert_eq!(parse("velvet").unwrap(), clause(None, "=", "velvet"));
+        assert_eq!(
+            parse(r#"dc.Title ANY "tipping \"the\" velvet""#).unwrap(),
+            clause(Some("dc.title"), "any", "tipping \"the\" velvet")
+        );
+        assert_eq!(
+            parse("dc.creator=waters or (bath.isbn == 0895941414 NOT local.classification<>LF)")
+                .unwrap(),
+            Query::Boolean(
+                Boolean::Or,
+                Box::new(clause(Some("dc.creator"), "=", "waters")),
+                Box::new(Query::Boolean(
+                    Boolean::Not,
+                    Box::new(clause(Some("bath.isbn"), "==", "0895941414")),
+                    Box::new(clause(Some("local.classification"), "<>", "LF")),
+                )),
+            )
+        );
+        assert_eq!(
+            parse("a and b or c").unwrap(),
+            Query::Boolean(
+                Boolean::Or,
+                Box::new(Query::Boolean(
+                    Boolean::And,
+                    Box::new(clause(None, "=", "a")),
+                    Box::new(clause(None, "=", "b")),
+                )),
+                Box::new(clause(None, "=", "c")),
+            )
+        );
+        match parse("dc.title =/relevant/locale=en velvet").unwrap() {
+            Query::Clause(clause) => assert_eq!(clause.modifiers, vec!["relevant", "locale=en"]),
+            query => panic!("{:?}", query),
+        }
+
+        for query in &["", "(velvet", "dc.title =", "a and", "\"velvet", "a b"] {
+            assert!(parse(query).is_err(), "{:?}", query);
+        }
+        let nested = |depth| format!("{}velvet{}", "(".repeat(depth), ")".repeat(depth));
+        assert!(parse(&nested(32)).is_ok());
+        assert!(parse(&nested(100_000)).is_err());
+        let chained = |clauses| vec!["velvet"; clauses].join(" and ");
+        assert!(parse(&chained(128)).is_ok());
+        assert!(parse(&chained(100_000)).is_err());
+    }
+}
@@ -1,24 +1,34 @@
 // SPDX-License-Identifier: AGPL-3.0-only
 
+use crate::audit::{self, Action, AuditEntry};
+use crate::hold::Hold;
 use crate::item::Item;
+use crate::loan::Loan;
+use crate::replication::ReplicationState;
+use crate::token::{self, Token};
 use crate::user::User;
-use failure::{ensure, Fallible};
+use chrono::{DateTime, Utc};
+use failure::{bail, ensure, Fallible};
+use log::{info, warn};
+use rand::Rng;
 use serde::{Deserialize, Serialize};
 use sled::{IVec, Tree};
 use std::any::TypeId;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::convert::TryFrom;
 use std::fmt;
 use std::fs;
 use std::io::prelude::*;
 use std::marker::PhantomData;
+use std::ops::Bound;
 use std::path::Path;
 use std::sync::Arc;
 use std::sync::Mutex;
-use tantivy::collector::TopDocs;
+use tantivy::collector::{Count, TopDocs};
 use tantivy::directory::MmapDirectory;
-use tantivy::query::QueryParser;
-use tantivy::schema::{Field, Schema};
-use tantivy::{DocAddress, Document, Index, IndexWriter, Score, Term};
+use tantivy::query::{BooleanQuery, Occur, Query, QueryParser, RangeQuery};
+use tantivy::schema::{Field, Schema, Type};
+use tantivy::{DocAddress, Document, Index, IndexWriter, Searcher, Term};
 
 pub(crate) fn id_to_bytes(id: u64) -> [u8; 8] {
     id.to_ne_bytes()
@@ -31,27 +41,97 @@ pub(crate) fn id_to_u64(id: &[u8]) -> Fallible<u64> {
     Ok(u64::from_ne_bytes(array))
 }
 
-fn open_or_create_index<T: IndexedRow>(path: &Path) -> Fallible<(Index, Mutex<IndexWriter>)> {
+/// How many low bits of a row ID count rows, below the bits for the database that made it.
+const NODE_SHIFT: u32 = 40;
+
+/// How deeply a search's parentheses can nest. tantivy's query parser recurses through every
+/// level, and backtracks enough to take several times longer with each one.
+const MAX_QUERY_DEPTH: usize = 10;
+
+/// Refuses a search that nests too deeply for tantivy to parse.
+pub(crate) fn check_query(query: &str) -> Fallible<()> {
+    let mut depth: usize = 0;
+    let mut quoted = false;
+    for c in query.chars() {
+        match c {
+            '"' => quoted = !quoted,
+            '(' if !quoted => {
+                depth += 1;
+                ensure!(
+                    depth <= MAX_QUERY_DEPTH,
+                    "parentheses can only nest {} deep",
+                    MAX_QUERY_DEPTH
+                );
+            }
+            ')' if !quoted => depth = depth.saturating_sub(1),
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+fn audit_sequence(key: &[u8]) -> Fallible<u64> {
+    ensure!(key.len() == 8, "audit key {:?} is incorrect length", key);
+    let mut array = [0; 8];
+    array.copy_from_slice(key);
+    Ok(u64::from_be_bytes(array))
+}
+
+/// Where an incremental dump starts: after an audit log sequence number, or after a time.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Since {
+    Sequence(u64),
+    Time(DateTime<Utc>),
+}
+
+/// Opens the index for `T`, replacing it with an empty one if its schema has changed. Returns
+/// whether the index needs to be rebuilt from sled.
+fn open_or_create_index<T: IndexedRow>(
+    path: &Path,
+) -> Fallible<((Index, Mutex<IndexWriter>), bool)> {
     let path = path.join("idx").join(T::TREE);
     fs::create_dir_all(&path)?;
-    let index = Index::open_or_create(MmapDirectory::open(&path)?, T::schema())?;
+    let directory = MmapDirectory::open(&path)?;
+    let stale =
+        Index::exists(&directory) && Index::open(directory.clone())?.schema() != T::schema();
+    let index = if stale {
+        Index::create(directory, T::schema())?
+    } else {
+        Index::open_or_create(directory, T::schema())?
+    };
     let index_writer = index.writer(50_000_000)?;
-    Ok((index, Mutex::new(index_writer)))
+    Ok(((index, Mutex::new(index_writer)), stale))
 }
 
 #[cfg(test)]
 fn create_ram_index<T: IndexedRow>() -> Fallible<(Index, Mutex<IndexWriter>)> {
     let index = Index::create_in_ram(T::schema());
     let index_writer = index.writer(50_000_000)?;
+    // tantivy 0.9 panics merging segments in a RAM directory, and test indices stay small anyway.
+    index_writer.set_merge_policy(Box::new(tantivy::merge_policy::NoMergePolicy::default()));
     Ok((index, Mutex::new(index_writer)))
 }
 
+/// Compares two serialized rows, ignoring `updated_at`.
+fn unchanged(old: &serde_json::Value, new: &serde_json::Value) -> bool {
+    let strip = |value: &serde_json::Value| {
+        let mut value = value.clone();
+        if let Some(object) = value.as_object_mut() {
+            object.remove("updated_at");
+        }
+        value
+    };
+    strip(old) == strip(new)
+}
+
 #[derive(Debug)]
 pub(crate) struct SaveData {
     id: u64,
     blob: Vec<u8>,
     index: Option<IndexData>,
     secondary: HashMap<&'static str, Vec<u8>>,
+    unique: HashMap<&'static str, Vec<u8>>,
+    reverse: HashMap<&'static str, Vec<u8>>,
 }
 
 #[derive(Debug)]
@@ -67,6 +147,8 @@ impl SaveData {
             blob,
             index: None,
             secondary: HashMap::new(),
+            unique: HashMap::new(),
+            reverse: HashMap::new(),
         }
     }
 
@@ -81,16 +163,64 @@ impl SaveData {
         v.secondary.insert(tree_name, blob);
         v
     }
+
+    pub(crate) fn unique(self, tree_name: &'static str, key: Vec<u8>) -> SaveData {
+        let mut v = self;
+        v.unique.insert(tree_name, key);
+        v
+    }
+
+    pub(crate) fn reverse(self, tree_name: &'static str, key: Vec<u8>) -> SaveData {
+        let mut v = self;
+        v.reverse.insert(tree_name, key);
+        v
+    }
+}
+
+/// When a row was created and last changed. `Db::save` keeps these up to date for rows that have
+/// them.
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq, Eq)]
+pub(crate) struct Timestamps {
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) created_at: Option<DateTime<Utc>>,
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) updated_at: Option<DateTime<Utc>>,
 }
 
-pub(crate) trait Row: Sized {
+impl Timestamps {
+    pub(crate) fn last_changed(&self) -> Option<DateTime<Utc>> {
+        self.updated_at.or(self.created_at)
+    }
+
+    /// What the index sorts rows newest first by, since it can't sort by dates: `created_at` in
+    /// nanoseconds, or the row's ID for rows from before timestamps were kept. IDs are far
+    /// smaller than any recent time in nanoseconds, so undated rows come last, in a fixed order.
+    pub(crate) fn creation_order(&self, id: u64) -> u64 {
+        self.created_at
+            .and_then(|created_at| u64::try_from(created_at.timestamp_nanos()).ok())
+            .unwrap_or(id)
+    }
+}
+
+pub(crate) trait Row: Sized + Serialize {
     const TREE: &'static str;
     const SECONDARY: &'static [&'static str] = &[];
+    /// Trees mapping a unique key (e.g. an inventory barcode) back to a row ID.
+    const UNIQUE: &'static [&'static str] = &[];
+    /// Trees mapping a non-unique key (e.g. a borrower) back to any number of row IDs. Entries are
+    /// stored as the key followed by the row ID so they can be found with a prefix scan.
+    const REVERSE: &'static [&'static str] = &[];
 
     fn load(id: u64, blob: &[u8], secondary: HashMap<&'static str, IVec>) -> Fallible<Self>;
     fn save<F>(&mut self, id_gen: F) -> Fallible<SaveData>
     where
         F: FnOnce(Option<u64>) -> Fallible<u64>;
+
+    fn timestamps(&mut self) -> Option<&mut Timestamps> {
+        None
+    }
 }
 
 pub(crate) trait IndexedRow: Row {
@@ -99,27 +229,49 @@ pub(crate) trait IndexedRow: Row {
     fn query_parser_fields() -> Vec<Field>;
 }
 
+/// How to order search results.
+#[derive(Clone, Copy)]
+enum Order {
+    Relevance,
+    Newest,
+}
+
 pub(crate) struct Db {
     sled: sled::Db,
     indices: HashMap<TypeId, (Index, Mutex<IndexWriter>)>,
+    /// The user making changes, recorded in the audit log.
+    actor: Option<u64>,
+    /// Set while restoring a dump or applying changes pulled from a primary, which have their own
+    /// timestamps and audit entries.
+    restoring: bool,
 }
 
 impl Db {
     pub(crate) fn open<P: AsRef<Path>>(path: P) -> Fallible<Db> {
         let mut indices = HashMap::new();
-        indices.insert(
-            TypeId::of::<Item>(),
-            open_or_create_index::<Item>(path.as_ref())?,
-        );
-        indices.insert(
-            TypeId::of::<User>(),
-            open_or_create_index::<User>(path.as_ref())?,
-        );
+        let (index, item_stale) = open_or_create_index::<Item>(path.as_ref())?;
+        indices.insert(TypeId::of::<Item>(), index);
+        let (index, user_stale) = open_or_create_index::<User>(path.as_ref())?;
+        indices.insert(TypeId::of::<User>(), index);
 
-        Ok(Db {
+        let mut db = Db {
             sled: sled::Db::start_default(path.as_ref().join("sled"))?,
             indices,
-        })
+            actor: None,
+            restoring: false,
+        };
+        if item_stale {
+            db.rebuild_index::<Item>()?;
+        }
+        if user_stale {
+            db.rebuild_index::<User>()?;
+        }
+        db.migrate_legacy_loans()?;
+        db.rebuild_keys::<Item>()?;
+        db.rebuild_keys::<Loan>()?;
+        db.rebuild_keys::<Hold>()?;
+        db.rebuild_keys::<Token>()?;
+        Ok(db)
     }
 
     #[cfg(test)]
@@ -133,6 +285,8 @@ impl Db {
         Ok(Db {
             sled: sled::Db::start(config)?,
             indices,
+            actor: None,
+            restoring: false,
         })
     }
 
@@ -168,20 +322,59 @@ impl Db {
         T: 'static,
     {
         let tree = self.open_tree::<T>()?;
-        let save_data = row.save(|id_opt| match id_opt {
+        let now = Utc::now();
+        let mut previous_update = None;
+        if !self.restoring {
+            if let Some(timestamps) = row.timestamps() {
+                previous_update = timestamps.updated_at.replace(now);
+            }
+        }
+        let mut save_data = row.save(|id_opt| match id_opt {
             Some(id) => Ok(id),
-            None => self.sled.generate_id().map_err(failure::Error::from),
+            // Restored rows keep their IDs, so skip any the generator hands out again.
+            None => loop {
+                let id = self.generate_row_id()?;
+                if !tree.contains_key(id_to_bytes(id))? {
+                    break Ok(id);
+                }
+            },
         })?;
         let id_bytes = id_to_bytes(save_data.id);
+
+        let old_row = self.load::<T>(save_data.id)?;
+        // Rows from before timestamps were kept stay undated, rather than looking new the next
+        // time they're saved.
+        if old_row.is_none() && !self.restoring {
+            if let Some(timestamps) = row.timestamps() {
+                if timestamps.created_at.is_none() {
+                    timestamps.created_at = Some(now);
+                    let id = save_data.id;
+                    save_data = row.save(|_| Ok(id))?;
+                }
+            }
+        }
+        let old_value = old_row.as_ref().map(serde_json::to_value).transpose()?;
+        let new_value = serde_json::to_value(&*row)?;
+        if let Some(old_value) = &old_value {
+            if unchanged(old_value, &new_value) {
+                // Don't bump `updated_at` for a save that changes nothing else.
+                if let Some(timestamps) = row.timestamps() {
+                    timestamps.updated_at = previous_update;
+                }
+                return Ok(());
+            }
+        }
+        let old = match old_row {
+            Some(mut old) => Some(old.save(|_| Ok(save_data.id))?),
+            None => None,
+        };
+        if let Some(taken) = self.taken_unique_key::<T>(&save_data)? {
+            bail!(taken);
+        }
+
         tree.set(id_bytes, save_data.blob)?;
         if let Some(IndexData { id_field, document }) = save_data.index {
-            if let Some((_, ref mut index_writer)) = self.indices.get_mut(&TypeId::of::<T>()) {
-                let mut index_writer = index_writer.lock().unwrap();
-                index_writer.prepare_commit()?;
-                index_writer.delete_term(Term::from_field_u64(id_field, save_data.id));
-                index_writer.add_document(document);
-                index_writer.commit()?;
-            }
+            self.reindex::<T>(id_field, save_data.id, Some(document))?;
         }
         for tree_name in T::SECONDARY {
             let tree = self.open_secondary::<T>(tree_name)?;
@@ -190,10 +383,469 @@ impl Db {
                 None => tree.del(id_bytes)?,
             };
         }
+        for tree_name in T::UNIQUE {
+            let tree = self.open_secondary::<T>(tree_name)?;
+            let key = save_data.unique.get(tree_name);
+            if let Some(old_key) = old.as_ref().and_then(|old| old.unique.get(tree_name)) {
+                if key != Some(old_key) {
+                    self.release_unique_key::<T>(tree_name, old_key, save_data.id)?;
+                }
+            }
+            if let Some(key) = key {
+                tree.set(key, &id_bytes[..])?;
+            }
+        }
+        for tree_name in T::REVERSE {
+            let tree = self.open_secondary::<T>(tree_name)?;
+            if let Some(old_key) = old.as_ref().and_then(|old| old.reverse.get(tree_name)) {
+                tree.del([old_key.as_slice(), &id_bytes].concat())?;
+            }
+            if let Some(key) = save_data.reverse.get(tree_name) {
+                tree.set([key.as_slice(), &id_bytes].concat(), Vec::new())?;
+            }
+        }
+        self.record::<T>(save_data.id, old_value.as_ref(), Some(&new_value))
+    }
+
+    pub(crate) fn delete<T: Row>(&mut self, id: u64) -> Fallible<()>
+    where
+        T: 'static,
+    {
+        let (old, old_value) = match self.load::<T>(id)? {
+            Some(mut old) => {
+                let old_value = serde_json::to_value(&old)?;
+                (old.save(|_| Ok(id))?, old_value)
+            }
+            None => return Ok(()),
+        };
+        let id_bytes = id_to_bytes(id);
+
+        self.open_tree::<T>()?.del(id_bytes)?;
+        if let Some(IndexData { id_field, .. }) = old.index {
+            self.reindex::<T>(id_field, id, None)?;
+        }
+        for tree_name in T::SECONDARY {
+            self.open_secondary::<T>(tree_name)?.del(id_bytes)?;
+        }
+        for (tree_name, key) in &old.unique {
+            self.release_unique_key::<T>(tree_name, key, id)?;
+        }
+        for (tree_name, key) in &old.reverse {
+            self.open_secondary::<T>(tree_name)?
+                .del([key.as_slice(), &id_bytes].concat())?;
+        }
+        self.record::<T>(id, Some(&old_value), None)
+    }
+
+    /// Describes the first of a row's unique keys that a different row already has.
+    fn taken_unique_key<T: Row>(&self, save_data: &SaveData) -> Fallible<Option<String>> {
+        for tree_name in T::UNIQUE {
+            if let Some(key) = save_data.unique.get(tree_name) {
+                if let Some(existing) = self.open_secondary::<T>(tree_name)?.get(key)? {
+                    let existing = id_to_u64(&existing)?;
+                    if existing != save_data.id {
+                        return Ok(Some(format!(
+                            "{} {:?} is already used by row {}",
+                            tree_name,
+                            String::from_utf8_lossy(key),
+                            existing
+                        )));
+                    }
+                }
+            }
+        }
+        Ok(None)
+    }
+
+    /// Removes a unique key, unless it has already passed to a different row.
+    fn release_unique_key<T: Row>(
+        &self,
+        tree_name: &'static str,
+        key: &[u8],
+        id: u64,
+    ) -> Fallible<()> {
+        let tree = self.open_secondary::<T>(tree_name)?;
+        if let Some(existing) = tree.get(key)? {
+            if id_to_u64(&existing)? == id {
+                tree.del(key)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Frees the unique keys that the local copy of a row copied from elsewhere has but the copy
+    /// doesn't, so rows in a batch that pass keys between each other can be saved in any order.
+    pub(crate) fn release_unique_keys<T: Row>(&self, row: &mut T) -> Fallible<()> {
+        let save_data = row.save(|id| id.ok_or_else(|| failure::err_msg("row has no id")))?;
+        if let Some(mut local) = self.load::<T>(save_data.id)? {
+            let local = local.save(|_| Ok(save_data.id))?;
+            for (tree_name, key) in &local.unique {
+                if save_data.unique.get(tree_name) != Some(key) {
+                    self.release_unique_key::<T>(tree_name, key, save_data.id)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Describes the first unique key of a row copied from elsewhere that a different local row
+    /// already has, which would keep it from being saved.
+    pub(crate) fn unique_conflict<T: Row>(&self, row: &mut T) -> Fallible<Option<String>> {
+        let save_data = row.save(|id| id.ok_or_else(|| failure::err_msg("row has no id")))?;
+        self.taken_unique_key::<T>(&save_data)
+    }
+
+    /// Files a local row's unique keys again after `release_unique_keys`, for when its copy
+    /// isn't saved after all. Keys another row has taken in the meantime stay with that row.
+    pub(crate) fn claim_unique_keys<T: Row>(&self, id: u64) -> Fallible<()> {
+        if let Some(mut local) = self.load::<T>(id)? {
+            let local = local.save(|_| Ok(id))?;
+            for (tree_name, key) in &local.unique {
+                let tree = self.open_secondary::<T>(tree_name)?;
+                match tree.get(key)? {
+                    Some(existing) => {
+                        let existing = id_to_u64(&existing)?;
+                        if existing != id {
+                            warn!(
+                                "{} {:?} moved from row {} to row {}",
+                                tree_name,
+                                String::from_utf8_lossy(key),
+                                id,
+                                existing
+                            );
+                        }
+                    }
+                    None => {
+                        tree.set(key, &id_to_bytes(id)[..])?;
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Sets the user that later changes are attributed to in the audit log.
+    pub(crate) fn set_actor(&mut self, actor: Option<u64>) {
+        self.actor = actor;
+    }
+
+    fn record<T: Row>(
+        &self,
+        id: u64,
+        old: Option<&serde_json::Value>,
+        new: Option<&serde_json::Value>,
+    ) -> Fallible<()> {
+        if self.restoring {
+            return Ok(());
+        }
+        let action = match (old, new) {
+            (None, _) => Action::Create,
+            (_, None) => Action::Delete,
+            _ => Action::Update,
+        };
+        let mut changes = audit::diff(old, new);
+        // Every entry has its own timestamp.
+        changes.retain(|change| change.field != "updated_at");
+        if action == Action::Update && changes.is_empty() {
+            return Ok(());
+        }
+        self.append_audit(&AuditEntry {
+            timestamp: Utc::now(),
+            actor: self.actor,
+            tree: T::TREE.to_owned(),
+            id,
+            action,
+            changes,
+        })
+    }
+
+    /// Audit entries are keyed by a big-endian generated ID, so they scan in the order they were
+    /// written.
+    fn append_audit(&self, entry: &AuditEntry) -> Fallible<()> {
+        let key = self.sled.generate_id()?.to_be_bytes();
+        self.sled
+            .open_tree("audit")?
+            .set(key, serde_cbor::to_vec(entry)?)?;
+        Ok(())
+    }
+
+    /// Returns up to `limit` audit entries matching `filter`, newest first.
+    pub(crate) fn audit_log<F>(&self, filter: F, limit: usize) -> Fallible<Vec<AuditEntry>>
+    where
+        F: Fn(&AuditEntry) -> bool,
+    {
+        let mut entries = Vec::new();
+        for value in self.sled.open_tree("audit")?.iter().values().rev() {
+            let entry: AuditEntry = serde_cbor::from_slice(&value?)?;
+            if filter(&entry) {
+                entries.push(entry);
+                if entries.len() == limit {
+                    break;
+                }
+            }
+        }
+        Ok(entries)
+    }
+
+    fn reindex<T: 'static>(
+        &mut self,
+        id_field: Field,
+        id: u64,
+        document: Option<Document>,
+    ) -> Fallible<()> {
+        if let Some((_, ref mut index_writer)) = self.indices.get_mut(&TypeId::of::<T>()) {
+            let mut index_writer = index_writer.lock().unwrap();
+            index_writer.prepare_commit()?;
+            index_writer.delete_term(Term::from_field_u64(id_field, id));
+            if let Some(document) = document {
+                index_writer.add_document(document);
+            }
+            index_writer.commit()?;
+        }
+        Ok(())
+    }
+
+    /// Adds every row of `T` back to its index.
+    fn rebuild_index<T: IndexedRow>(&mut self) -> Fallible<()>
+    where
+        T: 'static,
+    {
+        info!("rebuilding {} index", T::TREE);
+        let rows = self.iter::<T>()?.collect::<Fallible<Vec<_>>>()?;
+        for mut row in rows {
+            let save_data = row.save(|id| id.ok_or_else(|| failure::err_msg("row has no id")))?;
+            if let Some(IndexData { id_field, document }) = save_data.index {
+                self.reindex::<T>(id_field, save_data.id, Some(document))?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Before loans had dates, an item's checkout tree only held the borrower's barcode. Turns
+    /// those into loans checked out when the item last changed, or when this runs if it never
+    /// has, so their due dates stay put.
+    fn migrate_legacy_loans(&self) -> Fallible<()> {
+        let items = self.open_tree::<Item>()?;
+        let checkouts = self.open_secondary::<Item>("checkout")?;
+        let legacy = checkouts
+            .iter()
+            .filter(|pair| pair.as_ref().map_or(true, |(_, value)| value.len() == 8))
+            .collect::<Result<Vec<_>, _>>()?;
+        if legacy.is_empty() {
+            return Ok(());
+        }
+
+        info!("migrating {} legacy loans", legacy.len());
+        let now = Utc::now();
+        for (key, user) in legacy {
+            let since = match items.get(&key)? {
+                Some(blob) => serde_cbor::from_slice::<Item>(&blob)?
+                    .timestamps
+                    .last_changed(),
+                None => None,
+            };
+            let loan = Loan::legacy(id_to_u64(&key)?, id_to_u64(&user)?, since.unwrap_or(now));
+            checkouts.set(key, serde_cbor::to_vec(&loan)?)?;
+        }
         Ok(())
     }
 
+    /// Refills the unique and reverse trees for `T` if they've changed since the database was
+    /// last opened, such as when a newer version adds one.
+    fn rebuild_keys<T: Row>(&self) -> Fallible<()> {
+        let names = serde_cbor::to_vec(&(T::UNIQUE, T::REVERSE))?;
+        let built = self.sled.open_tree("keys")?;
+        if built.get(T::TREE)?.map_or(false, |old| *old == *names) {
+            return Ok(());
+        }
+
+        info!("rebuilding {} keys", T::TREE);
+        for tree_name in T::UNIQUE.iter().chain(T::REVERSE) {
+            self.open_secondary::<T>(tree_name)?.clear()?;
+        }
+        for row in self.iter::<T>()? {
+            let save_data = row?.save(|id| id.ok_or_else(|| failure::err_msg("row has no id")))?;
+            let id_bytes = id_to_bytes(save_data.id);
+            for (tree_name, key) in &save_data.unique {
+                let tree = self.open_secondary::<T>(tree_name)?;
+                match tree.get(key)? {
+                    Some(existing) => warn!(
+                        "{} {:?} is used by rows {} and {}; keeping the first",
+                        tree_name,
+                        String::from_utf8_lossy(key),
+                        id_to_u64(&existing)?,
+                        save_data.id
+                    ),
+                    None => {
+                        tree.set(key, &id_bytes[..])?;
+                    }
+                }
+            }
+            for (tree_name, key) in &save_data.reverse {
+                self.open_secondary::<T>(tree_name)?
+                    .set([key.as_slice(), &id_bytes].concat(), Vec::new())?;
+            }
+        }
+        built.set(T::TREE, names)?;
+        Ok(())
+    }
+
+    fn load_by_unique<T: Row>(&self, tree_name: &'static str, key: &[u8]) -> Fallible<Option<T>> {
+        match self.open_secondary::<T>(tree_name)?.get(key)? {
+            Some(id) => self.load(id_to_u64(&id)?),
+            None => Ok(None),
+        }
+    }
+
+    /// Loads every row filed under `key` in a reverse tree, ordered by row ID. For rows with
+    /// generated IDs, that is the order they were created in.
+    fn load_by_reverse<T: Row>(&self, tree_name: &'static str, key: &[u8]) -> Fallible<Vec<T>> {
+        let mut ids = Vec::new();
+        for entry in self.open_secondary::<T>(tree_name)?.scan(key) {
+            let (entry_key, _) = entry?;
+            if !entry_key.starts_with(key) {
+                break;
+            }
+            ids.push(id_to_u64(&entry_key[key.len()..])?);
+        }
+        ids.sort_unstable();
+
+        let mut rows = Vec::with_capacity(ids.len());
+        for id in ids {
+            rows.push(
+                self.load(id)?
+                    .ok_or_else(|| failure::err_msg(format!("failed to find row {}", id)))?,
+            );
+        }
+        Ok(rows)
+    }
+
+    /// Lists the items currently checked out to a user.
+    pub(crate) fn loans_for_user(&self, barcode: u64) -> Fallible<Vec<Item>> {
+        self.load_by_reverse("loans", &id_to_bytes(barcode))
+    }
+
+    /// Lists the finished loans of an item, oldest first.
+    pub(crate) fn loan_history(&self, item: u64) -> Fallible<Vec<Loan>> {
+        self.load_by_reverse("item", &id_to_bytes(item))
+    }
+
+    /// Lists the holds queued for an item, first in line first.
+    pub(crate) fn holds_for_item(&self, item: u64) -> Fallible<Vec<Hold>> {
+        self.load_by_reverse("item", &id_to_bytes(item))
+    }
+
+    /// Lists the holds a user has placed, oldest first.
+    pub(crate) fn holds_for_user(&self, barcode: u64) -> Fallible<Vec<Hold>> {
+        self.load_by_reverse("user", &id_to_bytes(barcode))
+    }
+
+    /// Looks up an item by its inventory control barcode.
+    pub(crate) fn load_by_barcode(&self, barcode: &str) -> Fallible<Option<Item>> {
+        self.load_by_unique("barcode", barcode.as_bytes())
+    }
+
+    /// Looks up an API token by its secret.
+    pub(crate) fn load_token(&self, secret: &str) -> Fallible<Option<Token>> {
+        self.load_by_unique("hash", token::hash(secret).as_bytes())
+    }
+
+    /// Lists the API tokens belonging to a user, oldest first.
+    pub(crate) fn tokens_for_user(&self, barcode: u64) -> Fallible<Vec<Token>> {
+        self.load_by_reverse("user", &id_to_bytes(barcode))
+    }
+
+    /// Numbers a new row. The low bits count rows as they're created, and the high bits are a
+    /// random number picked once per database, so servers that replicate each other don't hand
+    /// out the same IDs.
+    fn generate_row_id(&self) -> Fallible<u64> {
+        let count = self.sled.generate_id()?;
+        ensure!(count < 1 << NODE_SHIFT, "ran out of row IDs");
+        Ok(self.node()? << NODE_SHIFT | count)
+    }
+
+    /// This database's random number for `generate_row_id`.
+    fn node(&self) -> Fallible<u64> {
+        let value =
+            self.sled
+                .open_tree("replication")?
+                .update_and_fetch("node", |old| match old {
+                    Some(old) => Some(old.to_vec()),
+                    None => Some(id_to_bytes(rand::thread_rng().gen_range(1, 1 << 16)).to_vec()),
+                })?;
+        id_to_u64(&value.ok_or_else(|| failure::err_msg("node was deleted"))?)
+    }
+
+    /// Returns the next value of a named counter, starting from 1.
+    pub(crate) fn next_sequence(&self, name: &str) -> Fallible<u64> {
+        let tree = self.sled.open_tree("sequence")?;
+        let value = tree.update_and_fetch(name, |old| {
+            let old = old.and_then(|old| id_to_u64(old).ok()).unwrap_or(0);
+            Some(id_to_bytes(old + 1).to_vec())
+        })?;
+        id_to_u64(&value.ok_or_else(|| failure::err_msg("sequence was deleted"))?)
+    }
+
     pub(crate) fn query<T: IndexedRow>(&mut self, query: &str) -> Fallible<Vec<T>>
+    where
+        T: 'static,
+    {
+        self.query_with_limit(query, 10)
+    }
+
+    pub(crate) fn query_with_limit<T: IndexedRow>(
+        &mut self,
+        query: &str,
+        limit: usize,
+    ) -> Fallible<Vec<T>>
+    where
+        T: 'static,
+    {
+        Ok(self.query_page(query, 0, limit)?.1)
+    }
+
+    /// Returns how many rows match `query`, and up to `limit` of them after skipping `offset`.
+    pub(crate) fn query_page<T: IndexedRow>(
+        &self,
+        query: &str,
+        offset: usize,
+        limit: usize,
+    ) -> Fallible<(usize, Vec<T>)>
+    where
+        T: 'static,
+    {
+        let (index, query) = self.parse_query::<T>(query)?;
+        self.search_page(
+            &index.reader()?.searcher(),
+            &*query,
+            offset,
+            limit,
+            Order::Relevance,
+        )
+    }
+
+    /// Like `query_page`, but newest rows first. Rows that match equally well come back in no
+    /// particular order, so this keeps pages from overlapping when they all match the same way.
+    pub(crate) fn newest_page<T: IndexedRow>(
+        &self,
+        query: &str,
+        offset: usize,
+        limit: usize,
+    ) -> Fallible<(usize, Vec<T>)>
+    where
+        T: 'static,
+    {
+        let (index, query) = self.parse_query::<T>(query)?;
+        self.search_page(
+            &index.reader()?.searcher(),
+            &*query,
+            offset,
+            limit,
+            Order::Newest,
+        )
+    }
+
+    fn parse_query<T: IndexedRow>(&self, query: &str) -> Fallible<(&Index, Box<dyn Query>)>
     where
         T: 'static,
     {
@@ -201,17 +853,50 @@ impl Db {
             .indices
             .get(&TypeId::of::<T>())
             .ok_or_else(|| failure::err_msg("no index for row type"))?;
-        let searcher = index.reader()?.searcher();
-
-        let query_parser = QueryParser::for_index(&index, T::query_parser_fields());
+        check_query(query)?;
+        let query_parser = QueryParser::for_index(index, T::query_parser_fields());
         let query = query_parser
             .parse_query(query)
             .map_err(tantivy::Error::from)?;
+        Ok((index, query))
+    }
 
-        let top_docs: Vec<(Score, DocAddress)> =
-            searcher.search(&query, &TopDocs::with_limit(10))?;
-        let mut docs = Vec::with_capacity(top_docs.len());
-        for (_, address) in top_docs {
+    fn search_page<T: IndexedRow>(
+        &self,
+        searcher: &Searcher,
+        query: &dyn Query,
+        offset: usize,
+        limit: usize,
+        order: Order,
+    ) -> Fallible<(usize, Vec<T>)>
+    where
+        T: 'static,
+    {
+        // `TopDocs` allocates room for its whole limit up front, so keep it within the matches.
+        let count = searcher.search(query, &Count)?;
+        if offset >= count || limit == 0 {
+            return Ok((count, Vec::new()));
+        }
+        let top_docs = TopDocs::with_limit(std::cmp::min(offset.saturating_add(limit), count));
+        let addresses: Vec<DocAddress> = match order {
+            Order::Relevance => searcher
+                .search(query, &top_docs)?
+                .into_iter()
+                .map(|(_, address)| address)
+                .collect(),
+            Order::Newest => {
+                let field = T::schema()
+                    .get_field("created_order")
+                    .ok_or_else(|| failure::err_msg("row type has no created_order field"))?;
+                searcher
+                    .search(query, &top_docs.order_by_field::<u64>(field))?
+                    .into_iter()
+                    .map(|(_, address)| address)
+                    .collect()
+            }
+        };
+        let mut docs = Vec::new();
+        for address in addresses.into_iter().skip(offset).take(limit) {
             let doc = searcher.doc(address)?;
             let id = doc
                 .get_first(T::id_field())
@@ -223,7 +908,53 @@ impl Db {
             );
         }
 
-        Ok(docs)
+        Ok((count, docs))
+    }
+
+    /// Finds the rows created at or after `since` using the index's `created_at` field, newest
+    /// first. `query` narrows them down further, and `limit` keeps only the newest.
+    pub(crate) fn added_since<T: IndexedRow>(
+        &self,
+        since: DateTime<Utc>,
+        query: Option<&str>,
+        limit: Option<usize>,
+    ) -> Fallible<Vec<T>>
+    where
+        T: 'static,
+    {
+        let (index, _) = self
+            .indices
+            .get(&TypeId::of::<T>())
+            .ok_or_else(|| failure::err_msg("no index for row type"))?;
+        let field = T::schema()
+            .get_field("created_at")
+            .ok_or_else(|| failure::err_msg("row type has no created_at field"))?;
+
+        let mut range: Box<dyn Query> = Box::new(RangeQuery::new_term_bounds(
+            field,
+            Type::Date,
+            &Bound::Included(Term::from_field_date(field, &since)),
+            &Bound::Unbounded,
+        ));
+        if let Some(query) = query {
+            let (_, query) = self.parse_query::<T>(query)?;
+            range = Box::new(BooleanQuery::from(vec![
+                (Occur::Must, range),
+                (Occur::Must, query),
+            ]));
+        }
+        let searcher = index.reader()?.searcher();
+        let limit = match limit {
+            Some(limit) => limit,
+            None => usize::try_from(searcher.num_docs())?,
+        };
+        let (_, rows) = self.search_page::<T>(&searcher, &*range, 0, limit, Order::Newest)?;
+        let mut rows = rows
+            .into_iter()
+            .map(|mut row| (row.timestamps().and_then(|t| t.created_at), row))
+            .collect::<Vec<_>>();
+        rows.sort_by(|(a, _), (b, _)| b.cmp(a));
+        Ok(rows.into_iter().map(|(_, row)| row).collect())
     }
 
     pub(crate) fn iter<T: Row>(&self) -> Fallible<Iter<T>> {
@@ -234,11 +965,62 @@ impl Db {
         Ok(Iter::new(self.open_tree::<T>()?, map))
     }
 
-    fn iter_all(&self) -> Fallible<impl Iterator<Item = Fallible<DumpRow>>> {
+    pub(crate) fn iter_all(&self) -> Fallible<impl Iterator<Item = Fallible<DumpRow>>> {
         Ok(self
             .iter::<Item>()?
             .map(|item| item.map(DumpRow::from))
-            .chain(self.iter::<User>()?.map(|user| user.map(DumpRow::from))))
+            .chain(self.iter::<User>()?.map(|user| user.map(DumpRow::from)))
+            .chain(self.iter::<Loan>()?.map(|loan| loan.map(DumpRow::from)))
+            .chain(self.iter::<Hold>()?.map(|hold| hold.map(DumpRow::from)))
+            .chain(self.iter::<Token>()?.map(|token| token.map(DumpRow::from)))
+            .chain(
+                self.audit_entries()?
+                    .into_iter()
+                    .map(|entry| Ok(DumpRow::from(entry))),
+            ))
+    }
+
+    /// Returns the sequence number of the newest audit entry, which `dump --since` can start from.
+    pub(crate) fn last_sequence(&self) -> Fallible<Option<u64>> {
+        match self.sled.open_tree("audit")?.iter().keys().next_back() {
+            Some(key) => Ok(Some(audit_sequence(&key?)?)),
+            None => Ok(None),
+        }
+    }
+
+    fn audit_entries_since(&self, since: Since) -> Fallible<Vec<AuditEntry>> {
+        let mut entries = Vec::new();
+        for pair in self.sled.open_tree("audit")?.iter() {
+            let (key, value) = pair?;
+            let entry: AuditEntry = serde_cbor::from_slice(&value)?;
+            let after = match since {
+                Since::Sequence(sequence) => audit_sequence(&key)? > sequence,
+                Since::Time(time) => entry.timestamp > time,
+            };
+            if after {
+                entries.push(entry);
+            }
+        }
+        Ok(entries)
+    }
+
+    pub(crate) fn load_dump_row(&self, tree: &str, id: u64) -> Fallible<Option<DumpRow>> {
+        Ok(match tree {
+            Item::TREE => self.load::<Item>(id)?.map(DumpRow::from),
+            User::TREE => self.load::<User>(id)?.map(DumpRow::from),
+            Loan::TREE => self.load::<Loan>(id)?.map(DumpRow::from),
+            Hold::TREE => self.load::<Hold>(id)?.map(DumpRow::from),
+            Token::TREE => self.load::<Token>(id)?.map(DumpRow::from),
+            _ => bail!("unknown tree {:?}", tree),
+        })
+    }
+
+    fn audit_entries(&self) -> Fallible<Vec<AuditEntry>> {
+        let mut entries = Vec::new();
+        for value in self.sled.open_tree("audit")?.iter().values() {
+            entries.push(serde_cbor::from_slice(&value?)?);
+        }
+        Ok(entries)
     }
 
     pub(crate) fn dump<W: Write>(&self, writer: W) -> Fallible<()> {
@@ -250,14 +1032,153 @@ impl Db {
         Ok(())
     }
 
+    /// Returns the rows changed since a point in the audit log, with a tombstone for each deleted
+    /// row, followed by the new audit entries.
+    pub(crate) fn changes_since(&self, since: Since) -> Fallible<Vec<DumpRow>> {
+        let entries = self.audit_entries_since(since)?;
+        // List each row once, in the order of its last change. No order works for every unique
+        // key that moves between rows, so restoring and merging free them before saving.
+        let mut last_change = HashMap::new();
+        for (i, entry) in entries.iter().enumerate() {
+            last_change.insert((entry.tree.as_str(), entry.id), i);
+        }
+        let mut changed = last_change.into_iter().collect::<Vec<_>>();
+        changed.sort_by_key(|(_, i)| *i);
+
+        let mut rows = Vec::new();
+        for ((tree, id), _) in changed {
+            rows.push(
+                self.load_dump_row(tree, id)?
+                    .unwrap_or_else(|| DumpRow::Deleted {
+                        tree: tree.to_owned(),
+                        id,
+                    }),
+            );
+        }
+        rows.extend(entries.into_iter().map(DumpRow::from));
+        Ok(rows)
+    }
+
+    /// Dumps the changes since a point in the audit log. Restoring the result over an older
+    /// restore of this database brings it up to date.
+    pub(crate) fn dump_since<W: Write>(&self, writer: W, since: Since) -> Fallible<()> {
+        let mut writer = writer;
+        for row in self.changes_since(since)? {
+            serde_json::to_writer(&mut writer, &row)?;
+            writer.write_all(b"\n")?;
+        }
+        Ok(())
+    }
+
+    /// Runs `f` without touching timestamps or writing audit entries, for rows copied from
+    /// elsewhere.
+    pub(crate) fn restoring<F, T>(&mut self, f: F) -> Fallible<T>
+    where
+        F: FnOnce(&mut Db) -> Fallible<T>,
+    {
+        self.restoring = true;
+        let result = f(self);
+        self.restoring = false;
+        result
+    }
+
     pub(crate) fn restore<R: Read>(&mut self, reader: R) -> Fallible<()> {
-        let stream = serde_json::Deserializer::from_reader(reader).into_iter();
-        for row in stream {
-            match row? {
-                DumpRow::Item(mut item) => self.save(&mut *item)?,
+        self.restoring(|db| db.restore_rows(reader))
+    }
+
+    fn restore_rows<R: Read>(&mut self, reader: R) -> Fallible<()> {
+        let rows = serde_json::Deserializer::from_reader(reader)
+            .into_iter()
+            .collect::<Result<Vec<DumpRow>, _>>()?;
+        // A unique key can pass between rows in either order, or be swapped between them, so
+        // delete rows first and then free the keys the other rows give up.
+        let (deleted, mut rows): (Vec<_>, Vec<_>) = rows.into_iter().partition(|row| match row {
+            DumpRow::Deleted { .. } => true,
+            _ => false,
+        });
+        for row in deleted {
+            if let DumpRow::Deleted { tree, id } = row {
+                self.delete_dump_row(&tree, id)?;
+            }
+        }
+        for row in &mut rows {
+            match row {
+                DumpRow::Item(item) => self.release_unique_keys(&mut **item)?,
+                DumpRow::Token(token) => self.release_unique_keys(&mut **token)?,
+                _ => {}
+            }
+        }
+
+        let mut undated = HashSet::new();
+        for row in rows {
+            match row {
+                DumpRow::Item(mut item) => {
+                    self.save(&mut *item)?;
+                    if item.timestamps.created_at.is_none() {
+                        undated.extend(item.id());
+                    }
+                }
                 DumpRow::User(mut user) => self.save(&mut *user)?,
+                DumpRow::Loan(mut loan) => self.save(&mut *loan)?,
+                DumpRow::Hold(mut hold) => self.save(&mut *hold)?,
+                DumpRow::Token(mut token) => self.save(&mut *token)?,
+                DumpRow::Audit(entry) => self.append_audit(&entry)?,
+                DumpRow::Deleted { tree, id } => self.delete_dump_row(&tree, id)?,
             };
         }
+        if !undated.is_empty() {
+            self.date_from_audit_log(undated)?;
+        }
+        Ok(())
+    }
+
+    /// Gives items from dumps made before rows had timestamps the time of their first audit entry
+    /// as their creation time, so they can be found by `added_since`. Items with no audit entries
+    /// stay undated.
+    fn date_from_audit_log(&mut self, undated: HashSet<u64>) -> Fallible<()> {
+        let mut undated = undated;
+        for value in self.sled.open_tree("audit")?.iter().values() {
+            let entry: AuditEntry = serde_cbor::from_slice(&value?)?;
+            if entry.tree == Item::TREE && undated.remove(&entry.id) {
+                if let Some(mut item) = self.load::<Item>(entry.id)? {
+                    item.timestamps.created_at = Some(entry.timestamp);
+                    self.save(&mut item)?;
+                }
+                if undated.is_empty() {
+                    break;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    pub(crate) fn delete_dump_row(&mut self, tree: &str, id: u64) -> Fallible<()> {
+        match tree {
+            Item::TREE => self.delete::<Item>(id),
+            User::TREE => self.delete::<User>(id),
+            Loan::TREE => self.delete::<Loan>(id),
+            Hold::TREE => self.delete::<Hold>(id),
+            Token::TREE => self.delete::<Token>(id),
+            _ => bail!("unknown tree {:?}", tree),
+        }
+    }
+
+    /// Returns how far this database has synced with a remote server.
+    pub(crate) fn replication_state(&self, url: &str) -> Fallible<ReplicationState> {
+        match self.sled.open_tree("replication")?.get(url)? {
+            Some(value) => Ok(serde_cbor::from_slice(&value)?),
+            None => Ok(ReplicationState::default()),
+        }
+    }
+
+    pub(crate) fn set_replication_state(
+        &self,
+        url: &str,
+        state: &ReplicationState,
+    ) -> Fallible<()> {
+        self.sled
+            .open_tree("replication")?
+            .set(url, serde_cbor::to_vec(state)?)?;
         Ok(())
     }
 }
@@ -269,9 +1190,18 @@ impl fmt::Debug for Db {
 }
 
 #[derive(Debug, Serialize, Deserialize)]
-enum DumpRow {
+pub(crate) enum DumpRow {
     Item(Box<Item>),
     User(Box<User>),
+    Loan(Box<Loan>),
+    Hold(Box<Hold>),
+    Token(Box<Token>),
+    Audit(Box<AuditEntry>),
+    /// A row deleted since the point an incremental dump starts from.
+    Deleted {
+        tree: String,
+        id: u64,
+    },
 }
 
 impl From<Item> for DumpRow {
@@ -286,6 +1216,30 @@ impl From<User> for DumpRow {
     }
 }
 
+impl From<Loan> for DumpRow {
+    fn from(x: Loan) -> DumpRow {
+        DumpRow::Loan(Box::new(x))
+    }
+}
+
+impl From<Hold> for DumpRow {
+    fn from(x: Hold) -> DumpRow {
+        DumpRow::Hold(Box::new(x))
+    }
+}
+
+impl From<Token> for DumpRow {
+    fn from(x: Token) -> DumpRow {
+        DumpRow::Token(Box::new(x))
+    }
+}
+
+impl From<AuditEntry> for DumpRow {
+    fn from(x: AuditEntry) -> DumpRow {
+        DumpRow::Audit(Box::new(x))
+    }
+}
+
 pub(crate) struct Iter<T> {
     tree: Arc<sled::Tree>,
     secondary: HashMap<&'static str, Arc<Tree>>,
@@ -341,3 +1295,225 @@ impl<T: Row> Iterator for Iter<T> {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::circulation::{check_out, LoanPolicy};
+    use crate::db::{check_query, id_to_bytes, Db, Since};
+    use crate::item::Item;
+    use crate::user::User;
+    use chrono::{TimeZone, Utc};
+    use failure::Fallible;
+
+    #[test]
+    fn test_dump_since() -> Fallible<()> {
+        let mut db = Db::open_memory()?;
+        let mut user = User::test_user();
+        db.save(&mut user)?;
+        let mut item = Item::test_item();
+        item.barcode = Some("1".to_owned());
+        db.save(&mut item)?;
+        let mut replica = Db::open_memory()?;
+        let mut dump = Vec::new();
+        db.dump(&mut dump)?;
+        replica.restore(dump.as_slice())?;
+        let sequence = db.last_sequence()?.unwrap();
+
+        // Move the barcode to a new item.
+        db.delete::<Item>(item.id().unwrap())?;
+        let mut new_item = Item::test_item();
+        new_item.barcode = Some("1".to_owned());
+        db.save(&mut new_item)?;
+        user.name = "renamed".to_owned();
+        db.save(&mut user)?;
+
+        let mut dump = Vec::new();
+        db.dump_since(&mut dump, Since::Sequence(sequence))?;
+        let dump = String::from_utf8(dump)?;
+        assert!(dump.contains("Deleted"));
+        replica.restore(dump.as_bytes())?;
+
+        assert_eq!(replica.load::<Item>(item.id().unwrap())?, None);
+        assert_eq!(replica.load_by_barcode("1")?, Some(new_item));
+        assert_eq!(replica.load::<User>(user.barcode)?, Some(user));
+        assert_eq!(
+            replica.audit_log(|_| true, 10)?,
+            db.audit_log(|_| true, 10)?
+        );
+
+        let mut dump = Vec::new();
+        db.dump_since(&mut dump, Since::Sequence(db.last_sequence()?.unwrap()))?;
+        assert!(dump.is_empty());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_restore_moved_keys() -> Fallible<()> {
+        let mut db = Db::open_memory()?;
+        let mut items = Vec::new();
+        for barcode in &["X", "P", "Q"] {
+            let mut item = Item::test_item();
+            item.barcode = Some((*barcode).to_owned());
+            db.save(&mut item)?;
+            items.push(item);
+        }
+        let mut replica = Db::open_memory()?;
+        let mut dump = Vec::new();
+        db.dump(&mut dump)?;
+        replica.restore(dump.as_slice())?;
+        let sequence = db.last_sequence()?.unwrap();
+
+        // The first item's barcode passes to a new item, and the first item is edited again
+        // afterwards, so the new item is dumped first.
+        items[0].barcode = Some("Y".to_owned());
+        db.save(&mut items[0])?;
+        let mut new_item = Item::test_item();
+        new_item.barcode = Some("X".to_owned());
+        db.save(&mut new_item)?;
+        items[0].title = "Edited".to_owned();
+        db.save(&mut items[0])?;
+        // The other two swap barcodes.
+        items[1].barcode = Some("T".to_owned());
+        db.save(&mut items[1])?;
+        items[2].barcode = Some("P".to_owned());
+        db.save(&mut items[2])?;
+        items[1].barcode = Some("Q".to_owned());
+        db.save(&mut items[1])?;
+
+        let mut dump = Vec::new();
+        db.dump_since(&mut dump, Since::Sequence(sequence))?;
+        replica.restore(dump.as_slice())?;
+        for barcode in &["X", "Y", "P", "Q", "T"] {
+            assert_eq!(
+                replica.load_by_barcode(barcode)?,
+                db.load_by_barcode(barcode)?
+            );
+        }
+        assert_eq!(replica.load_by_barcode("X")?, Some(new_item));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_undated_rows() -> Fallible<()> {
+        let mut db = Db::open_memory()?;
+        // Like an item from before rows had timestamps.
+        let mut old = Item::test_item();
+        db.restoring(|db| db.save(&mut old))?;
+        old.title = "Checked out".to_owned();
+        db.save(&mut old)?;
+        assert_eq!(old.timestamps.created_at, None);
+        assert!(old.timestamps.updated_at.is_some());
+
+        let mut new = Item::test_item();
+        db.save(&mut new)?;
+        assert!(new.timestamps.created_at.is_some());
+        let added = db.added_since::<Item>(Utc.timestamp(0, 0), None, None)?;
+        assert_eq!(added, vec![new]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_restore_undated() -> Fallible<()> {
+        let mut db = Db::open_memory()?;
+        let mut item = Item::test_item();
+        db.save(&mut item)?;
+        let mut other = Item::test_item();
+        db.save(&mut other)?;
+        let created_at = db.audit_log(|entry| Some(entry.id) == item.id(), 1)?[0].timestamp;
+
+        // Like a dump from before rows had timestamps, where only `item` has an audit entry.
+        let mut dump = Vec::new();
+        db.dump(&mut dump)?;
+        let mut old_dump = Vec::new();
+        for line in String::from_utf8(dump)?.lines() {
+            let mut row: serde_json::Value = serde_json::from_str(line)?;
+            if let Some(item) = row.get_mut("Item").and_then(|item| item.as_object_mut()) {
+                item.remove("created_at");
+                item.remove("updated_at");
+            }
+            if row.get("Audit").map_or(true, |entry| {
+                entry["id"] == serde_json::Value::from(item.id().unwrap())
+            }) {
+                serde_json::to_writer(&mut old_dump, &row)?;
+                old_dump.push(b'\n');
+            }
+        }
+        let mut restored = Db::open_memory()?;
+        restored.restore(old_dump.as_slice())?;
+
+        let new = restored.added_since::<Item>(Utc.timestamp(0, 0), None, None)?;
+        assert_eq!(new.len(), 1);
+        assert_eq!(new[0].id(), item.id());
+        assert_eq!(new[0].timestamps.created_at, Some(created_at));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_migrate_legacy_loans() -> Fallible<()> {
+        let mut db = Db::open_memory()?;
+        let mut item = Item::test_item();
+        db.save(&mut item)?;
+        let id = item.id().unwrap();
+
+        // Like a database from before loans had dates.
+        db.open_secondary::<Item>("checkout")?
+            .set(id_to_bytes(id), &id_to_bytes(7)[..])?;
+        db.migrate_legacy_loans()?;
+        let loan = db.load::<Item>(id)?.unwrap().loan.unwrap();
+        assert_eq!((loan.item, loan.user), (id, 7));
+        assert_eq!(Some(loan.checked_out), item.timestamps.last_changed());
+        assert!(loan.due > loan.checked_out);
+
+        // Loading it again doesn't move the due date.
+        db.migrate_legacy_loans()?;
+        assert_eq!(db.load::<Item>(id)?.unwrap().loan, Some(loan));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_rebuild_keys() -> Fallible<()> {
+        let mut db = Db::open_memory()?;
+        let user = User::test_user();
+        let mut item = Item::test_item();
+        item.barcode = Some("1".to_owned());
+        db.save(&mut item)?;
+        check_out(&mut db, &LoanPolicy::default(), &mut item, &user)?;
+        let mut other = Item::test_item();
+        other.barcode = Some("2".to_owned());
+        db.save(&mut other)?;
+
+        // Like a database from before barcodes were unique, with one used twice, and before
+        // loans were filed under their borrower.
+        db.open_secondary::<Item>("barcode")?.clear()?;
+        other.barcode = Some("1".to_owned());
+        db.save(&mut other)?;
+        db.open_secondary::<Item>("barcode")?.clear()?;
+        db.open_secondary::<Item>("loans")?.clear()?;
+        db.rebuild_keys::<Item>()?;
+        assert!(db.load_by_barcode("1")?.is_some());
+        assert!(db.load_by_barcode("2")?.is_none());
+        assert_eq!(db.loans_for_user(user.barcode)?, vec![item]);
+
+        // Once rebuilt, it isn't again.
+        db.open_secondary::<Item>("barcode")?.clear()?;
+        db.rebuild_keys::<Item>()?;
+        assert!(db.load_by_barcode("1")?.is_none());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_check_query() -> Fallible<()> {
+        let nested = |depth| format!("{}velvet{}", "(".repeat(depth), " tipping)".repeat(depth));
+        check_query(&nested(10))?;
+        check_query(&format!("title:\"{}\"", "(".repeat(100)))?;
+        assert!(check_query(&nested(11)).is_err());
+        assert!(Db::open_memory()?.query::<Item>(&nested(100_000)).is_err());
+        Ok(())
+    }
+}
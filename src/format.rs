@@ -3,7 +3,7 @@
 use serde::{Deserialize, Serialize};
 use std::fmt;
 
-#[derive(Debug, Serialize, Deserialize, PartialEq, Eq)]
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, Hash)]
 #[serde(rename_all = "kebab-case")]
 pub(crate) enum Format {
     Paperback,
@@ -21,7 +21,7 @@ pub(crate) enum Format {
 }
 
 impl Format {
-    pub(crate) fn search_terms(&self) -> Vec<&'static str> {
+    pub(crate) fn search_terms(self) -> Vec<&'static str> {
         use Format::*;
 
         match self {
@@ -36,6 +36,38 @@ impl Format {
             Cassette => vec!["cassette", "music"],
         }
     }
+
+    /// Guesses the format from a binding or media name in another catalog, like "Mass Market
+    /// Paperback" or "Audio CD".
+    pub(crate) fn from_binding(binding: &str) -> Option<Format> {
+        use Format::*;
+
+        let binding = binding.to_lowercase();
+        let has_word = |word| binding.split_whitespace().any(|w| w == word);
+        Some(if binding.contains("paperback") {
+            Paperback
+        } else if binding.contains("hardcover") || binding.contains("hardback") {
+            Hardcover
+        } else if binding.contains("magazine") {
+            Magazine
+        } else if binding.contains("zine") {
+            Zine
+        } else if has_word("cd") || binding.contains("compact disc") {
+            CD
+        } else if binding.contains("cassette") {
+            Cassette
+        } else if binding.contains("vinyl") || has_word("lp") {
+            if binding.contains('7') {
+                Vinyl7Inch
+            } else if binding.contains("10") {
+                Vinyl10Inch
+            } else {
+                Vinyl12Inch
+            }
+        } else {
+            return None;
+        })
+    }
 }
 
 impl fmt::Display for Format {
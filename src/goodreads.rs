@@ -0,0 +1,184 @@
+// SPDX-License-Identifier: AGPL-3.0-only
+
+use crate::date::PartialDate;
+use crate::format::Format;
+use crate::isbn::normalize_isbn;
+use crate::item::{sort_name, Item};
+use crate::lesb::LESBClassification;
+use crate::marc::ImportDefaults;
+use crate::spreadsheet::RowError;
+use failure::{ensure, Fallible};
+use log::info;
+use serde::Deserialize;
+use std::io::prelude::*;
+
+/// Shelves every Goodreads book is on one of, which aren't worth keeping as tags.
+const EXCLUSIVE_SHELVES: &[&str] = &["read", "currently-reading", "to-read"];
+
+/// A row of the CSV from Goodreads' "Import and export" page.
+#[derive(Debug, Deserialize)]
+struct Book {
+    #[serde(rename = "Title")]
+    title: String,
+    #[serde(rename = "Author l-f")]
+    author: String,
+    #[serde(rename = "Additional Authors", default)]
+    additional_authors: String,
+    #[serde(rename = "ISBN", default)]
+    isbn: String,
+    #[serde(rename = "ISBN13", default)]
+    isbn13: String,
+    #[serde(rename = "Binding", default)]
+    binding: String,
+    #[serde(rename = "Year Published", default)]
+    year_published: String,
+    #[serde(rename = "Original Publication Year", default)]
+    original_publication_year: String,
+    #[serde(rename = "Bookshelves", default)]
+    bookshelves: String,
+    #[serde(rename = "Exclusive Shelf", default)]
+    exclusive_shelf: String,
+}
+
+/// Goodreads writes ISBNs as spreadsheet formulas, like `="0895941414"`, to keep leading zeros.
+fn unquote_isbn(isbn: &str) -> &str {
+    isbn.trim_start_matches('=').trim_matches('"')
+}
+
+impl Book {
+    fn to_item(
+        &self,
+        classification: LESBClassification,
+        defaults: ImportDefaults,
+    ) -> Fallible<Item> {
+        let title = self.title.trim();
+        ensure!(!title.is_empty(), "no title");
+        let format = Format::from_binding(&self.binding)
+            .or(defaults.format)
+            .ok_or_else(|| failure::err_msg(format!("unknown binding {:?}", self.binding)))?;
+        let mut item = Item::new(
+            classification,
+            title.to_owned(),
+            "und".to_owned(),
+            format,
+            defaults.location,
+        );
+
+        item.authors = Some(self.author.trim())
+            .filter(|author| !author.is_empty())
+            .map(sort_name)
+            .into_iter()
+            .chain(
+                self.additional_authors
+                    .split(',')
+                    .map(str::trim)
+                    .filter(|author| !author.is_empty())
+                    .map(sort_name),
+            )
+            .collect();
+        item.original_date = [&self.original_publication_year, &self.year_published]
+            .iter()
+            .find_map(|year| year.trim().parse().ok())
+            .map(|year| PartialDate(year, None));
+        item.isbn13 = [&self.isbn13, &self.isbn]
+            .iter()
+            .find_map(|isbn| normalize_isbn(unquote_isbn(isbn)));
+        let tags = self
+            .bookshelves
+            .split(',')
+            .map(str::trim)
+            .filter(|shelf| !shelf.is_empty() && !EXCLUSIVE_SHELVES.contains(shelf))
+            .collect::<Vec<_>>();
+        if !tags.is_empty() {
+            item.notes = Some(format!("Tags: {}", tags.join(", ")));
+        }
+        Ok(item)
+    }
+}
+
+/// Reads the books in a Goodreads export, skipping the ones that are only on the "to-read"
+/// shelf. Goodreads doesn't know about LESB, so every book gets the default classification.
+pub(crate) fn read_books<R: Read>(
+    reader: R,
+    defaults: ImportDefaults,
+) -> Fallible<Vec<Result<Item, RowError>>> {
+    let classification = defaults
+        .classification
+        .ok_or_else(|| failure::err_msg("Goodreads exports need a default --classification"))?;
+    let mut reader = csv::Reader::from_reader(reader);
+    let headers = reader.headers()?.clone();
+    let mut rows = Vec::new();
+    let mut unread = 0;
+    for record in reader.records() {
+        let record = record?;
+        let row = format!("line {}", record.position().map_or(0, csv::Position::line));
+        let book: Book = match record.deserialize(Some(&headers)) {
+            Ok(book) => book,
+            Err(err) => {
+                rows.push(Err(RowError {
+                    row,
+                    error: err.into(),
+                }));
+                continue;
+            }
+        };
+        if book.exclusive_shelf == "to-read" {
+            unread += 1;
+            continue;
+        }
+        rows.push(
+            book.to_item(classification, defaults)
+                .map_err(|error| RowError { row, error }),
+        );
+    }
+    if unread > 0 {
+        info!("skipped {} books on the to-read shelf", unread);
+    }
+    Ok(rows)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::date::PartialDate;
+    use crate::format::Format;
+    use crate::goodreads::read_books;
+    use crate::lesb::LESBClassification;
+    use crate::location::Location;
+    use crate::marc::ImportDefaults;
+    use failure::Fallible;
+
+    #[test]
+    fn test() -> Fallible<()> {
+        let csv = r#"Book Id,Title,Author,Author l-f,Additional Authors,ISBN,ISBN13,My Rating,Average Rating,Publisher,Binding,Number of Pages,Year Published,Original Publication Year,Date Read,Date Added,Bookshelves,Bookshelves with positions,Exclusive Shelf,My Review,Spoiler,Private Notes,Read Count,Owned Copies
+17,Sister Outsider,Audre Lorde,"Lorde, Audre",Cheryl Clarke,"=""0895941414""","=""""",5,4.52,Crossing Press,Paperback,190,1984,1984,,2019/06/01,"essays, read","essays (#1), read (#2)",read,,,,1,1
+18,Zami,Audre Lorde,"Lorde, Audre",,"=""""","=""""",0,4.3,Crossing Press,Hardcover,256,1982,,,2019/06/02,to-read,to-read (#1),to-read,,,,0,0
+19,Dykes to Watch Out For,Alison Bechdel,"Bechdel, Alison",,"=""""","=""9781563412001""",0,4.1,Firebrand,Kindle Edition,,1986,,,2019/06/03,,,read,,,,0,0
+"#;
+        let defaults = ImportDefaults {
+            classification: Some(LESBClassification::NI),
+            format: None,
+            location: Location::Billy,
+        };
+        let rows = read_books(csv.as_bytes(), defaults)?;
+        assert_eq!(rows.len(), 2);
+        let item = rows[0].as_ref().unwrap();
+        assert_eq!(item.title, "Sister Outsider");
+        assert_eq!(item.authors, vec!["Lorde, Audre", "Clarke, Cheryl"]);
+        assert_eq!(item.isbn13.as_ref().unwrap(), "9780895941411");
+        assert_eq!(item.original_date, Some(PartialDate(1984, None)));
+        assert_eq!(item.format, Format::Paperback);
+        assert_eq!(item.notes.as_ref().unwrap(), "Tags: essays");
+        assert_eq!(
+            rows[1].as_ref().unwrap_err().to_string(),
+            "line 4: unknown binding \"Kindle Edition\""
+        );
+
+        let defaults = ImportDefaults {
+            classification: None,
+            ..defaults
+        };
+        assert!(read_books(csv.as_bytes(), defaults).is_err());
+
+        Ok(())
+    }
+}
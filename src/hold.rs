@@ -0,0 +1,66 @@
+// SPDX-License-Identifier: AGPL-3.0-only
+
+use crate::db::{id_to_bytes, Row, SaveData};
+use chrono::{DateTime, Utc};
+use failure::Fallible;
+use serde::{Deserialize, Serialize};
+use sled::IVec;
+use std::collections::HashMap;
+
+/// A user's place in line for a checked-out item.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub(crate) struct Hold {
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    id: Option<u64>,
+
+    pub(crate) item: u64,
+    pub(crate) user: u64,
+    pub(crate) placed: DateTime<Utc>,
+    /// When the item came back and was put on the hold shelf for this user.
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) ready: Option<DateTime<Utc>>,
+}
+
+impl Hold {
+    pub(crate) fn new(item: u64, user: u64) -> Hold {
+        Hold {
+            id: None,
+            item,
+            user,
+            placed: Utc::now(),
+            ready: None,
+        }
+    }
+
+    pub(crate) fn id(&self) -> Option<u64> {
+        self.id
+    }
+
+    pub(crate) fn is_ready(&self) -> bool {
+        self.ready.is_some()
+    }
+}
+
+impl Row for Hold {
+    const TREE: &'static str = "hold";
+    const REVERSE: &'static [&'static str] = &["item", "user"];
+
+    fn load(id: u64, blob: &[u8], _secondary: HashMap<&'static str, IVec>) -> Fallible<Hold> {
+        let mut hold: Hold = serde_cbor::from_slice(blob)?;
+        hold.id = Some(id);
+        Ok(hold)
+    }
+
+    fn save<F>(&mut self, id_gen: F) -> Fallible<SaveData>
+    where
+        F: FnOnce(Option<u64>) -> Fallible<u64>,
+    {
+        let id = id_gen(self.id)?;
+        self.id = Some(id);
+        Ok(SaveData::new(id, serde_cbor::to_vec(self)?)
+            .reverse("item", id_to_bytes(self.item).to_vec())
+            .reverse("user", id_to_bytes(self.user).to_vec()))
+    }
+}
@@ -19,11 +19,21 @@ pub(crate) fn isbn10_to_isbn13(isbn10: &str) -> Option<String> {
         .enumerate()
         .map(|(i, b)| (b - b'0') * if i % 2 == 0 { 1 } else { 3 })
         .sum();
-    isbn13[12] = (10 - (sum % 10)) + b'0';
+    isbn13[12] = (10 - (sum % 10)) % 10 + b'0';
 
     Some(String::from_utf8(isbn13.to_vec()).unwrap())
 }
 
+/// Returns the ISBN-13 for an ISBN-10 or ISBN-13, with or without hyphens.
+pub(crate) fn normalize_isbn(isbn: &str) -> Option<String> {
+    let isbn = isbn.trim().replace('-', "");
+    match isbn.len() {
+        10 => isbn10_to_isbn13(&isbn),
+        13 if isbn.bytes().all(|b| b.is_ascii_digit()) => Some(isbn),
+        _ => None,
+    }
+}
+
 #[allow(clippy::cast_possible_truncation)]
 pub(crate) fn isbn13_to_isbn10(isbn13: &str) -> Option<String> {
     let isbn13 = isbn13.replace('-', "").into_bytes();
@@ -52,7 +62,7 @@ pub(crate) fn isbn13_to_isbn10(isbn13: &str) -> Option<String> {
 
 #[cfg(test)]
 mod tests {
-    use super::{isbn10_to_isbn13, isbn13_to_isbn10};
+    use super::{isbn10_to_isbn13, isbn13_to_isbn10, normalize_isbn};
 
     #[test]
     fn test_isbn10_to_isbn13() {
@@ -64,6 +74,10 @@ mod tests {
             isbn10_to_isbn13("080442957X"),
             Some("9780804429573".to_owned())
         );
+        assert_eq!(
+            isbn10_to_isbn13("0895941228"),
+            Some("9780895941220".to_owned())
+        );
     }
 
     #[test]
@@ -77,4 +91,17 @@ mod tests {
             Some("080442957X".to_owned())
         );
     }
+
+    #[test]
+    fn test_normalize_isbn() {
+        assert_eq!(
+            normalize_isbn("0-306-40615-2"),
+            Some("9780306406157".to_owned())
+        );
+        assert_eq!(
+            normalize_isbn("978-0-306-40615-7"),
+            Some("9780306406157".to_owned())
+        );
+        assert_eq!(normalize_isbn("B00005N5PF"), None);
+    }
 }
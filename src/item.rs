@@ -1,10 +1,11 @@
 // SPDX-License-Identifier: AGPL-3.0-only
 
 use crate::date::PartialDate;
-use crate::db::{IndexedRow, Row, SaveData};
+use crate::db::{IndexedRow, Row, SaveData, Timestamps};
 use crate::format::Format;
 use crate::isbn::isbn13_to_isbn10;
 use crate::lesb::LESBClassification;
+use crate::loan::Loan;
 use crate::location::Location;
 use failure::Fallible;
 use lazy_static::lazy_static;
@@ -18,6 +19,7 @@ use tantivy::Document;
 struct ItemSchema {
     schema: Schema,
     id: Field,
+    classification: Field,
     title: Field,
     format: Field,
     volume: Field,
@@ -31,6 +33,9 @@ struct ItemSchema {
     mbid: Field,
     oclc_number: Field,
     openlibrary_id: Field,
+    created_at: Field,
+    created_order: Field,
+    updated_at: Field,
 }
 
 impl ItemSchema {
@@ -40,6 +45,7 @@ impl ItemSchema {
 
         let mut schema_builder = SchemaBuilder::default();
         let id = schema_builder.add_u64_field("id", INDEXED | STORED | FAST);
+        let classification = schema_builder.add_text_field("classification", STRING);
         let title = schema_builder.add_text_field("title", TEXT);
         let format = schema_builder.add_text_field("format", STRING);
         let volume = schema_builder.add_text_field("volume", STRING);
@@ -53,9 +59,13 @@ impl ItemSchema {
         let mbid = schema_builder.add_text_field("mbid", STRING);
         let oclc_number = schema_builder.add_text_field("oclc", STRING);
         let openlibrary_id = schema_builder.add_text_field("openlibrary", STRING);
+        let created_at = schema_builder.add_date_field("created_at", INDEXED | FAST);
+        let created_order = schema_builder.add_u64_field("created_order", FAST);
+        let updated_at = schema_builder.add_date_field("updated_at", INDEXED | FAST);
         ItemSchema {
             schema: schema_builder.build(),
             id,
+            classification,
             title,
             format,
             volume,
@@ -69,6 +79,9 @@ impl ItemSchema {
             mbid,
             oclc_number,
             openlibrary_id,
+            created_at,
+            created_order,
+            updated_at,
         }
     }
 }
@@ -79,7 +92,8 @@ lazy_static! {
 
 #[derive(Debug, Serialize, Deserialize, PartialEq, Eq)]
 pub(crate) struct Item {
-    #[serde(skip)]
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
     id: Option<u64>,
 
     pub(crate) classification: LESBClassification,
@@ -94,8 +108,13 @@ pub(crate) struct Item {
     #[serde(skip_serializing_if = "Option::is_none")]
     pub(crate) volume_and_issue: Option<(u64, u64)>,
     pub(crate) location: Location,
+    #[serde(default)]
+    #[serde(
+        alias = "borrower",
+        deserialize_with = "crate::loan::deserialize_item_loan"
+    )]
     #[serde(skip_serializing_if = "Option::is_none")]
-    pub(crate) borrower: Option<u64>,
+    pub(crate) loan: Option<Loan>,
 
     /// The inventory control barcode for this item. This is not necessarily the ISBN or UPC.
     #[serde(skip_serializing_if = "Option::is_none")]
@@ -149,15 +168,56 @@ pub(crate) struct Item {
     /// [Wikidata property P648](https://www.wikidata.org/wiki/Property:P648)
     #[serde(skip_serializing_if = "Option::is_none")]
     pub(crate) openlibrary_id: Option<String>,
+
+    #[serde(flatten)]
+    pub(crate) timestamps: Timestamps,
 }
 
 impl Item {
+    /// Creates an item with only the fields every item needs.
+    pub(crate) fn new(
+        classification: LESBClassification,
+        title: String,
+        language: String,
+        format: Format,
+        location: Location,
+    ) -> Item {
+        Item {
+            id: None,
+            classification,
+            authors: Vec::new(),
+            original_date: None,
+            title,
+            language,
+            format,
+            volume_and_issue: None,
+            location,
+            loan: None,
+            barcode: None,
+            notes: None,
+            discogs_release: None,
+            isbn13: None,
+            issn: None,
+            lccn: None,
+            musicbrainz_release_group: None,
+            oclc_number: None,
+            openlibrary_id: None,
+            timestamps: Timestamps::default(),
+        }
+    }
+
     fn document(&self) -> Document {
         let mut document = Document::new();
 
         if let Some(id) = self.id {
             document.add_u64(SCHEMA.id, id);
         }
+        // Index the category too, so "classification:L" finds all literature.
+        document.add_text(SCHEMA.classification, &self.classification.to_string());
+        document.add_text(
+            SCHEMA.classification,
+            &self.classification.category().to_string(),
+        );
         document.add_text(SCHEMA.title, &self.title);
         for term in self.format.search_terms() {
             document.add_text(SCHEMA.format, term);
@@ -199,6 +259,17 @@ impl Item {
             document.add_text(SCHEMA.mbid, &mbid);
         }
 
+        if let Some(created_at) = &self.timestamps.created_at {
+            document.add_date(SCHEMA.created_at, created_at);
+        }
+        document.add_u64(
+            SCHEMA.created_order,
+            self.timestamps.creation_order(self.id.unwrap_or(0)),
+        );
+        if let Some(updated_at) = &self.timestamps.updated_at {
+            document.add_date(SCHEMA.updated_at, updated_at);
+        }
+
         document
     }
 
@@ -246,8 +317,16 @@ impl Item {
         call_number
     }
 
+    pub(crate) fn id(&self) -> Option<u64> {
+        self.id
+    }
+
+    pub(crate) fn borrower(&self) -> Option<u64> {
+        self.loan.as_ref().map(|loan| loan.user)
+    }
+
     pub(crate) fn is_checked_out(&self) -> bool {
-        self.borrower.is_some()
+        self.loan.is_some()
     }
 
     #[cfg(test)]
@@ -262,7 +341,7 @@ impl Item {
             format: Format::Hardcover,
             volume_and_issue: None,
             location: Location::Billy,
-            borrower: None,
+            loan: None,
             barcode: None,
             notes: None,
             discogs_release: None,
@@ -272,19 +351,35 @@ impl Item {
             musicbrainz_release_group: None,
             oclc_number: Some("1087838699".to_owned()),
             openlibrary_id: None,
+            timestamps: Timestamps::default(),
         }
     }
 }
 
+/// Turns an author's name into the "Last, First" form `author_sort` expects, leaving names that
+/// already have a comma alone.
+pub(crate) fn sort_name(name: &str) -> String {
+    let name = name.split_whitespace().collect::<Vec<_>>().join(" ");
+    if name.contains(',') {
+        return name;
+    }
+    match name.rfind(' ') {
+        Some(index) => format!("{}, {}", &name[index + 1..], &name[..index]),
+        None => name,
+    }
+}
+
 impl Row for Item {
     const TREE: &'static str = "item";
     const SECONDARY: &'static [&'static str] = &["checkout"];
+    const UNIQUE: &'static [&'static str] = &["barcode"];
+    const REVERSE: &'static [&'static str] = &["loans"];
 
     fn load(id: u64, blob: &[u8], secondary: HashMap<&'static str, IVec>) -> Fallible<Item> {
         let mut item: Item = serde_cbor::from_slice(blob)?;
         item.id = Some(id);
-        if let Some(barcode) = secondary.get("checkout") {
-            item.borrower = Some(crate::db::id_to_u64(barcode)?);
+        if let Some(loan) = secondary.get("checkout") {
+            item.loan = Some(serde_cbor::from_slice(loan)?);
         }
         Ok(item)
     }
@@ -296,16 +391,26 @@ impl Row for Item {
         let id = id_gen(self.id)?;
         self.id = Some(id);
 
-        let old_borrower = std::mem::replace(&mut self.borrower, None);
+        let loan = std::mem::replace(&mut self.loan, None);
         let cbor = serde_cbor::to_vec(self);
-        self.borrower = old_borrower;
+        self.loan = loan;
 
         let mut save_data = SaveData::new(id, cbor?).index(Item::id_field(), self.document());
-        if let Some(borrower) = self.borrower {
-            save_data = save_data.secondary("checkout", crate::db::id_to_bytes(borrower).to_vec());
+        if let Some(loan) = &mut self.loan {
+            loan.item = id;
+            save_data = save_data
+                .secondary("checkout", serde_cbor::to_vec(loan)?)
+                .reverse("loans", crate::db::id_to_bytes(loan.user).to_vec());
+        }
+        if let Some(barcode) = &self.barcode {
+            save_data = save_data.unique("barcode", barcode.as_bytes().to_vec());
         }
         Ok(save_data)
     }
+
+    fn timestamps(&mut self) -> Option<&mut Timestamps> {
+        Some(&mut self.timestamps)
+    }
 }
 
 impl IndexedRow for Item {
@@ -352,7 +457,10 @@ impl Ord for Item {
 #[cfg(test)]
 mod tests {
     use crate::db::Db;
-    use crate::item::Item;
+    use crate::item::{sort_name, Item};
+    use crate::lesb::LESBClassification;
+    use crate::loan::Loan;
+    use chrono::{Duration, Utc};
     use failure::Fallible;
 
     #[test]
@@ -368,7 +476,7 @@ mod tests {
             assert!(!loaded_item.is_checked_out());
         }
 
-        item.borrower = Some(0);
+        item.loan = Some(Loan::new(item.id.unwrap(), 0, Utc::now()));
         db.save(&mut item)?;
         {
             let loaded_item: Item = db.load(item.id.unwrap())?.unwrap();
@@ -382,4 +490,124 @@ mod tests {
 
         Ok(())
     }
+
+    #[test]
+    fn test_timestamps() -> Fallible<()> {
+        let mut db = Db::open_memory()?;
+        let before = Utc::now();
+        let mut item = Item::test_item();
+        db.save(&mut item)?;
+        let created_at = item.timestamps.created_at.unwrap();
+        assert!(created_at >= before);
+        assert_eq!(item.timestamps.updated_at, Some(created_at));
+
+        db.save(&mut item)?;
+        assert_eq!(item.timestamps.updated_at, Some(created_at));
+        item.title = "Color problems".to_owned();
+        db.save(&mut item)?;
+        assert_eq!(item.timestamps.created_at, Some(created_at));
+        assert!(item.timestamps.updated_at.unwrap() > created_at);
+        assert_eq!(db.load::<Item>(item.id.unwrap())?.as_ref(), Some(&item));
+
+        let mut other = Item::test_item();
+        other.classification = LESBClassification::LF;
+        db.save(&mut other)?;
+        assert_eq!(
+            db.added_since::<Item>(before, Some("classification:L"), None)?,
+            vec![db.load::<Item>(other.id.unwrap())?.unwrap()]
+        );
+        // A row copied from another database can have a higher ID but be older.
+        let mut copied = Item::test_item();
+        copied.id = Some(u64::max_value() >> 8);
+        copied.timestamps.created_at = Some(created_at - Duration::seconds(1));
+        db.restoring(|db| db.save(&mut copied))?;
+        assert_eq!(
+            db.added_since::<Item>(before - Duration::seconds(1), None, Some(1))?,
+            vec![db.load::<Item>(other.id.unwrap())?.unwrap()]
+        );
+        assert_eq!(
+            db.added_since::<Item>(before - Duration::seconds(1), None, None)?,
+            vec![other, item, copied]
+        );
+        assert!(db
+            .added_since::<Item>(Utc::now() + Duration::days(1), None, None)?
+            .is_empty());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_loans() -> Fallible<()> {
+        let mut db = Db::open_memory()?;
+        let mut item = Item::test_item();
+        let mut other = Item::test_item();
+        db.save(&mut item)?;
+        db.save(&mut other)?;
+        assert!(db.loans_for_user(0)?.is_empty());
+
+        item.loan = Some(Loan::new(item.id.unwrap(), 0, Utc::now()));
+        other.loan = Some(Loan::new(other.id.unwrap(), 1, Utc::now()));
+        db.save(&mut item)?;
+        db.save(&mut other)?;
+        assert_eq!(db.loans_for_user(0)?, vec![item]);
+
+        other.loan = Some(Loan::new(other.id.unwrap(), 0, Utc::now()));
+        db.save(&mut other)?;
+        assert_eq!(db.loans_for_user(0)?.len(), 2);
+        assert!(db.loans_for_user(1)?.is_empty());
+
+        other.loan = None;
+        db.save(&mut other)?;
+        assert_eq!(db.loans_for_user(0)?.len(), 1);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_barcode() -> Fallible<()> {
+        let mut db = Db::open_memory()?;
+        let mut item = Item::test_item();
+        item.barcode = Some("LESB0001".to_owned());
+        db.save(&mut item)?;
+        assert_eq!(db.load_by_barcode("LESB0001")?, Some(item));
+
+        let mut other = Item::test_item();
+        other.barcode = Some("LESB0001".to_owned());
+        assert!(db.save(&mut other).is_err());
+        other.barcode = Some("LESB0002".to_owned());
+        db.save(&mut other)?;
+
+        other.barcode = Some("LESB0003".to_owned());
+        db.save(&mut other)?;
+        assert_eq!(db.load_by_barcode("LESB0002")?, None);
+        assert_eq!(db.load_by_barcode("LESB0003")?, Some(other));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_legacy_borrower() -> Fallible<()> {
+        let item = Item::test_item();
+        let mut dumped = serde_json::to_value(&item)?;
+        dumped["borrower"] = 7.into();
+        let restored: Item = serde_json::from_value(dumped)?;
+        assert_eq!(restored.borrower(), Some(7));
+        let loan = restored.loan.clone().unwrap();
+        assert!(loan.due > loan.checked_out);
+        let dumped = serde_json::to_value(&restored)?;
+        assert_eq!(serde_json::from_value::<Item>(dumped)?.loan, Some(loan));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_sort_name() {
+        assert_eq!(sort_name("Audre  Lorde"), "Lorde, Audre");
+        assert_eq!(
+            sort_name("Emily Noyes Vanderpoel"),
+            "Vanderpoel, Emily Noyes"
+        );
+        assert_eq!(sort_name("Lorde, Audre"), "Lorde, Audre");
+        assert_eq!(sort_name("Sapphire"), "Sapphire");
+    }
 }
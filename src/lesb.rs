@@ -8,7 +8,7 @@ use std::fmt;
 use std::str::FromStr;
 
 /// The _LCC Enhancement for the Sortation of Books_ classification system.
-#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
 pub(crate) enum LESBClassification {
     /// General Works -- Cookbooks.
     AC,
@@ -99,6 +99,53 @@ pub(crate) enum LESBClassification {
 }
 
 impl LESBClassification {
+    /// Every classification, in order.
+    pub(crate) const ALL: [LESBClassification; 43] = [
+        LESBClassification::AC,
+        LESBClassification::AF,
+        LESBClassification::HB,
+        LESBClassification::HG,
+        LESBClassification::HM,
+        LESBClassification::HR,
+        LESBClassification::HX,
+        LESBClassification::KA,
+        LESBClassification::KG,
+        LESBClassification::LF,
+        LESBClassification::LH,
+        LESBClassification::LL,
+        LESBClassification::LN,
+        LESBClassification::LP,
+        LESBClassification::LS,
+        LESBClassification::LX,
+        LESBClassification::NF,
+        LESBClassification::NG,
+        LESBClassification::NI,
+        LESBClassification::NJ,
+        LESBClassification::NM,
+        LESBClassification::NR,
+        LESBClassification::NV,
+        LESBClassification::NBookEmoji,
+        LESBClassification::PD,
+        LESBClassification::PG,
+        LESBClassification::QA,
+        LESBClassification::QB,
+        LESBClassification::QP,
+        LESBClassification::QS,
+        LESBClassification::QZ,
+        LESBClassification::RE,
+        LESBClassification::RF,
+        LESBClassification::RK,
+        LESBClassification::RP,
+        LESBClassification::WA,
+        LESBClassification::WE,
+        LESBClassification::WM,
+        LESBClassification::WP,
+        LESBClassification::WS,
+        LESBClassification::WW,
+        LESBClassification::WX,
+        LESBClassification::XQ,
+    ];
+
     pub(crate) fn description(self) -> &'static str {
         use LESBClassification::*;
 
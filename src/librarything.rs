@@ -0,0 +1,358 @@
+// SPDX-License-Identifier: AGPL-3.0-only
+
+use crate::date::PartialDate;
+use crate::format::Format;
+use crate::isbn::normalize_isbn;
+use crate::item::{sort_name, Item};
+use crate::marc::ImportDefaults;
+use crate::spreadsheet::RowError;
+use failure::{ensure, Fallible};
+use serde::Deserialize;
+use serde_json::Value;
+use std::io::prelude::*;
+
+/// MARC language codes for the language names in exports.
+const LANGUAGES: &[(&str, &str)] = &[
+    ("Chinese", "chi"),
+    ("Dutch", "dut"),
+    ("English", "eng"),
+    ("French", "fre"),
+    ("German", "ger"),
+    ("Italian", "ita"),
+    ("Japanese", "jpn"),
+    ("Korean", "kor"),
+    ("Portuguese", "por"),
+    ("Russian", "rus"),
+    ("Spanish", "spa"),
+    ("Swedish", "swe"),
+];
+
+/// A book from either kind of export.
+#[derive(Debug, Default)]
+struct Book {
+    id: String,
+    title: String,
+    /// Names in "Last, First" form.
+    authors: Vec<String>,
+    date: String,
+    isbns: Vec<String>,
+    media: String,
+    languages: Vec<String>,
+    tags: Vec<String>,
+    lccn: String,
+    oclc: String,
+    call_number: String,
+}
+
+/// A row of the tab-separated export.
+#[derive(Debug, Deserialize)]
+struct TsvBook {
+    #[serde(rename = "Book Id")]
+    id: String,
+    #[serde(rename = "Title")]
+    title: String,
+    #[serde(rename = "Primary Author", default)]
+    primary_author: String,
+    #[serde(rename = "Secondary Author", default)]
+    secondary_author: String,
+    #[serde(rename = "Date", default)]
+    date: String,
+    #[serde(rename = "Media", default)]
+    media: String,
+    #[serde(rename = "Tags", default)]
+    tags: String,
+    #[serde(rename = "Languages", default)]
+    languages: String,
+    #[serde(rename = "ISBNs", default)]
+    isbns: String,
+    #[serde(rename = "LCCN", default)]
+    lccn: String,
+    #[serde(rename = "OCLC", default)]
+    oclc: String,
+    #[serde(rename = "Other Call Number", default)]
+    call_number: String,
+}
+
+fn split(s: &str, separator: char) -> Vec<String> {
+    s.split(separator)
+        .map(str::trim)
+        .filter(|s| !s.is_empty())
+        .map(str::to_owned)
+        .collect()
+}
+
+impl From<TsvBook> for Book {
+    fn from(book: TsvBook) -> Book {
+        Book {
+            id: book.id,
+            title: book.title,
+            authors: split(&book.primary_author, '|')
+                .into_iter()
+                .chain(split(&book.secondary_author, '|'))
+                .collect(),
+            date: book.date,
+            isbns: split(&book.isbns, ','),
+            media: book.media,
+            languages: split(&book.languages, ','),
+            tags: split(&book.tags, ','),
+            lccn: book.lccn,
+            oclc: book.oclc,
+            call_number: book.call_number,
+        }
+    }
+}
+
+/// Returns the strings in a JSON value, which exports have as a string, an array or an
+/// object keyed by position depending on the field and how many values there are. Objects in an
+/// array, like authors and formats, are represented by one of their fields.
+fn json_strings(value: Option<&Value>, key: &str) -> Vec<String> {
+    let values: Vec<&Value> = match value {
+        Some(Value::Array(values)) => values.iter().collect(),
+        Some(Value::Object(values)) => values.values().collect(),
+        Some(value) => vec![value],
+        None => Vec::new(),
+    };
+    values
+        .into_iter()
+        .filter_map(|value| match value {
+            Value::String(s) => Some(s.as_str()),
+            Value::Object(object) => object.get(key).and_then(Value::as_str),
+            _ => None,
+        })
+        .map(str::trim)
+        .filter(|s| !s.is_empty())
+        .map(str::to_owned)
+        .collect()
+}
+
+fn json_string(book: &Value, key: &str) -> String {
+    match book.get(key) {
+        Some(Value::String(s)) => s.clone(),
+        Some(Value::Number(n)) => n.to_string(),
+        _ => String::new(),
+    }
+}
+
+impl Book {
+    fn from_json(id: &str, book: &Value) -> Book {
+        let mut authors = json_strings(book.get("authors"), "lf");
+        if authors.is_empty() {
+            authors = json_strings(book.get("primaryauthor"), "lf");
+        }
+        let mut isbns = json_strings(book.get("isbn"), "isbn");
+        isbns.extend(json_strings(book.get("originalisbn"), "isbn"));
+        Book {
+            id: id.to_owned(),
+            title: json_string(book, "title"),
+            authors,
+            date: json_string(book, "date"),
+            isbns,
+            media: json_strings(book.get("format"), "text").join(" "),
+            languages: json_strings(book.get("language"), "text"),
+            tags: json_strings(book.get("tags"), "text"),
+            lccn: json_string(book, "lccn"),
+            oclc: json_string(book, "oclc"),
+            call_number: String::new(),
+        }
+    }
+
+    fn to_item(&self, defaults: ImportDefaults) -> Fallible<Item> {
+        let title = self.title.trim();
+        ensure!(!title.is_empty(), "no title");
+        // Anyone who catalogs in LibraryThing and here might keep LESB call numbers there too.
+        let classification = self
+            .call_number
+            .split_whitespace()
+            .next()
+            .and_then(|classification| classification.parse().ok())
+            .or(defaults.classification)
+            .ok_or_else(|| failure::err_msg("no LESB call number"))?;
+        let format = Format::from_binding(&self.media)
+            .or(defaults.format)
+            .ok_or_else(|| failure::err_msg(format!("unknown media {:?}", self.media)))?;
+        let language = self
+            .languages
+            .iter()
+            .find_map(|name| LANGUAGES.iter().find(|(n, _)| n == name))
+            .map_or("und", |(_, code)| code);
+        let mut item = Item::new(
+            classification,
+            title.to_owned(),
+            language.to_owned(),
+            format,
+            defaults.location,
+        );
+
+        item.authors = self.authors.iter().map(|name| sort_name(name)).collect();
+        // Dates are usually a year, but can be like "c1984" or "1984-1985".
+        item.original_date = self
+            .date
+            .split(|c: char| !c.is_ascii_digit())
+            .find(|part| part.len() == 4)
+            .and_then(|year| year.parse().ok())
+            .map(|year| PartialDate(year, None));
+        item.isbn13 = self
+            .isbns
+            .iter()
+            .find_map(|isbn| normalize_isbn(isbn.trim_matches(|c| c == '[' || c == ']')));
+        if !self.tags.is_empty() {
+            item.notes = Some(format!("Tags: {}", self.tags.join(", ")));
+        }
+        let optional = |s: &str| Some(s.trim().to_owned()).filter(|s| !s.is_empty());
+        item.lccn = optional(&self.lccn);
+        item.oclc_number = optional(&self.oclc);
+        Ok(item)
+    }
+}
+
+/// Decodes an export, which is UTF-16 for older tab-separated exports.
+fn decode(bytes: &[u8]) -> Fallible<String> {
+    if bytes.starts_with(&[0xff, 0xfe]) {
+        let units = bytes[2..]
+            .chunks(2)
+            .map(|pair| u16::from(pair[0]) | (u16::from(*pair.get(1).unwrap_or(&0)) << 8))
+            .collect::<Vec<_>>();
+        Ok(String::from_utf16(&units)?)
+    } else {
+        let s = String::from_utf8(bytes.to_vec())?;
+        Ok(s.trim_start_matches('\u{feff}').to_owned())
+    }
+}
+
+/// Reads the books in an export, either the tab-separated or the JSON kind.
+pub(crate) fn read_books<R: Read>(
+    mut reader: R,
+    defaults: ImportDefaults,
+) -> Fallible<Vec<Result<Item, RowError>>> {
+    let mut bytes = Vec::new();
+    reader.read_to_end(&mut bytes)?;
+    let export = decode(&bytes)?;
+
+    let books = if export.trim_start().starts_with('{') {
+        let json: serde_json::Map<String, Value> = serde_json::from_str(&export)?;
+        json.iter()
+            .map(|(id, book)| Ok(Book::from_json(id, book)))
+            .collect()
+    } else {
+        let mut reader = csv::ReaderBuilder::new()
+            .delimiter(b'\t')
+            .quoting(false)
+            .flexible(true)
+            .from_reader(export.as_bytes());
+        let headers = reader.headers()?.clone();
+        let mut books = Vec::new();
+        for record in reader.records() {
+            let record = record?;
+            books.push(
+                record
+                    .deserialize::<TsvBook>(Some(&headers))
+                    .map(Book::from)
+                    .map_err(|err| RowError {
+                        row: format!("line {}", record.position().map_or(0, csv::Position::line)),
+                        error: err.into(),
+                    }),
+            );
+        }
+        books
+    };
+    Ok(books
+        .into_iter()
+        .map(|book| {
+            book.and_then(|book| {
+                book.to_item(defaults).map_err(|error| RowError {
+                    row: format!("book {}", book.id),
+                    error,
+                })
+            })
+        })
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::date::PartialDate;
+    use crate::format::Format;
+    use crate::lesb::LESBClassification;
+    use crate::librarything::read_books;
+    use crate::location::Location;
+    use crate::marc::ImportDefaults;
+    use failure::Fallible;
+
+    const DEFAULTS: ImportDefaults = ImportDefaults {
+        classification: Some(LESBClassification::NI),
+        format: None,
+        location: Location::Billy,
+    };
+
+    #[test]
+    fn test_tsv() -> Fallible<()> {
+        let tsv = "\u{feff}Book Id\tTitle\tSort Character\tPrimary Author\tPrimary Author Role\tSecondary Author\tSecondary Author Roles\tPublication\tDate\tMedia\tTags\tLanguages\tISBN\tISBNs\tLCCN\tOCLC\tOther Call Number
+101\tTipping the Velvet\t1\tWaters, Sarah\tAuthor\t\t\tVirago (1998), Paperback\t1998\tPaperback\tfiction, historical\tEnglish\t[1860495249]\t1860495249\t\t40283452\tLF WATER 1998 eng
+102\tThe \"Well\" of Loneliness\t1\tHall, Radclyffe\tAuthor\t\t\t\tc1928\tBook\t\tEnglish\t\t\t\t\t
+";
+        let rows = read_books(tsv.as_bytes(), DEFAULTS)?;
+        assert_eq!(rows.len(), 2);
+        let item = rows[0].as_ref().unwrap();
+        assert_eq!(item.classification, LESBClassification::LF);
+        assert_eq!(item.authors, vec!["Waters, Sarah"]);
+        assert_eq!(item.original_date, Some(PartialDate(1998, None)));
+        assert_eq!(item.isbn13.as_ref().unwrap(), "9781860495243");
+        assert_eq!(item.language, "eng");
+        assert_eq!(item.notes.as_ref().unwrap(), "Tags: fiction, historical");
+        assert_eq!(item.oclc_number.as_ref().unwrap(), "40283452");
+        assert_eq!(
+            rows[1].as_ref().unwrap_err().to_string(),
+            "book 102: unknown media \"Book\""
+        );
+
+        // Older exports are UTF-16.
+        let mut utf16 = vec![0xff, 0xfe];
+        for unit in tsv.trim_start_matches('\u{feff}').encode_utf16() {
+            utf16.extend_from_slice(&unit.to_le_bytes());
+        }
+        let rows = read_books(utf16.as_slice(), DEFAULTS)?;
+        assert_eq!(rows[0].as_ref().unwrap(), item);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_json() -> Fallible<()> {
+        let json = r#"{
+            "55": {
+                "books_id": "55",
+                "title": "Stone Butch Blues",
+                "primaryauthor": "Feinberg, Leslie",
+                "authors": [{"lf": "Feinberg, Leslie", "fl": "Leslie Feinberg", "role": "Author"}],
+                "date": "1993",
+                "format": [{"code": "1", "text": "Paperback"}],
+                "isbn": {"0": "1563410281", "2": "9781563410284"},
+                "language": ["English"],
+                "tags": ["fiction", "trans"],
+                "lccn": "92035637"
+            },
+            "56": {
+                "books_id": "56",
+                "title": "Untitled",
+                "authors": [[]],
+                "format": [{"code": "2", "text": "Hardcover"}]
+            }
+        }"#;
+        let rows = read_books(json.as_bytes(), DEFAULTS)?;
+        assert_eq!(rows.len(), 2);
+        let item = rows[0].as_ref().unwrap();
+        assert_eq!(item.title, "Stone Butch Blues");
+        assert_eq!(item.classification, LESBClassification::NI);
+        assert_eq!(item.authors, vec!["Feinberg, Leslie"]);
+        assert_eq!(item.original_date, Some(PartialDate(1993, None)));
+        assert_eq!(item.format, Format::Paperback);
+        assert_eq!(item.isbn13.as_ref().unwrap(), "9781563410284");
+        assert_eq!(item.notes.as_ref().unwrap(), "Tags: fiction, trans");
+        assert_eq!(item.lccn.as_ref().unwrap(), "92035637");
+        let item = rows[1].as_ref().unwrap();
+        assert!(item.authors.is_empty());
+        assert_eq!(item.language, "und");
+
+        Ok(())
+    }
+}
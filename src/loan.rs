@@ -0,0 +1,106 @@
+// SPDX-License-Identifier: AGPL-3.0-only
+
+use crate::circulation::LoanPolicy;
+use crate::db::{id_to_bytes, Row, SaveData};
+use chrono::{DateTime, Duration, Utc};
+use failure::Fallible;
+use serde::{Deserialize, Deserializer, Serialize};
+use sled::IVec;
+use std::collections::HashMap;
+
+/// A single checkout of an item by a user.
+///
+/// The current loan for an item lives in the item's `checkout` secondary tree. Once the item is
+/// returned, the loan is saved as its own row in the loan history.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub(crate) struct Loan {
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    id: Option<u64>,
+
+    pub(crate) item: u64,
+    pub(crate) user: u64,
+    pub(crate) checked_out: DateTime<Utc>,
+    pub(crate) due: DateTime<Utc>,
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) returned: Option<DateTime<Utc>>,
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub(crate) renewals: Vec<Renewal>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub(crate) struct Renewal {
+    pub(crate) renewed: DateTime<Utc>,
+    /// The due date before this renewal.
+    pub(crate) previous_due: DateTime<Utc>,
+}
+
+impl Loan {
+    pub(crate) fn new(item: u64, user: u64, due: DateTime<Utc>) -> Loan {
+        Loan {
+            id: None,
+            item,
+            user,
+            checked_out: Utc::now(),
+            due,
+            returned: None,
+            renewals: Vec::new(),
+        }
+    }
+
+    /// A loan from before loans had dates, when only the borrower was recorded. It counts as
+    /// checked out at `since` for the default loan period.
+    pub(crate) fn legacy(item: u64, user: u64, since: DateTime<Utc>) -> Loan {
+        Loan {
+            checked_out: since,
+            ..Loan::new(
+                item,
+                user,
+                since + Duration::days(LoanPolicy::default().days),
+            )
+        }
+    }
+}
+
+/// An item's loan in a dump, where older dumps only have the borrower's barcode.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum DumpedLoan {
+    Loan(Loan),
+    Borrower(u64),
+}
+
+pub(crate) fn deserialize_item_loan<'de, D>(deserializer: D) -> Result<Option<Loan>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    Ok(match Option::<DumpedLoan>::deserialize(deserializer)? {
+        Some(DumpedLoan::Loan(loan)) => Some(loan),
+        Some(DumpedLoan::Borrower(user)) => Some(Loan::legacy(0, user, Utc::now())),
+        None => None,
+    })
+}
+
+impl Row for Loan {
+    const TREE: &'static str = "loan";
+    const REVERSE: &'static [&'static str] = &["item", "user"];
+
+    fn load(id: u64, blob: &[u8], _secondary: HashMap<&'static str, IVec>) -> Fallible<Loan> {
+        let mut loan: Loan = serde_cbor::from_slice(blob)?;
+        loan.id = Some(id);
+        Ok(loan)
+    }
+
+    fn save<F>(&mut self, id_gen: F) -> Fallible<SaveData>
+    where
+        F: FnOnce(Option<u64>) -> Fallible<u64>,
+    {
+        let id = id_gen(self.id)?;
+        self.id = Some(id);
+        Ok(SaveData::new(id, serde_cbor::to_vec(self)?)
+            .reverse("item", id_to_bytes(self.item).to_vec())
+            .reverse("user", id_to_bytes(self.user).to_vec()))
+    }
+}
@@ -0,0 +1,745 @@
+// SPDX-License-Identifier: AGPL-3.0-only
+
+use crate::date::PartialDate;
+use crate::format::Format;
+use crate::isbn::normalize_isbn;
+use crate::item::{sort_name, Item};
+use crate::marc::ImportDefaults;
+use failure::Fallible;
+use reqwest::header::{AUTHORIZATION, USER_AGENT};
+use reqwest::{RequestBuilder, StatusCode};
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+use std::collections::HashMap;
+
+/// Both music databases turn away requests that don't say who's asking.
+const USER_AGENT_STRING: &str = concat!("lesbians/", env!("CARGO_PKG_VERSION"));
+
+/// Where `catalog` looks items up, from the `[lookup]` section of `config.toml`.
+#[derive(Debug, Deserialize)]
+#[serde(default)]
+pub(crate) struct LookupConfig {
+    pub(crate) openlibrary_url: String,
+    pub(crate) musicbrainz_url: String,
+    pub(crate) discogs_url: String,
+    /// A Discogs personal access token, which searching by barcode needs.
+    pub(crate) discogs_token: Option<String>,
+}
+
+impl Default for LookupConfig {
+    fn default() -> LookupConfig {
+        LookupConfig {
+            openlibrary_url: "https://openlibrary.org".to_owned(),
+            musicbrainz_url: "https://musicbrainz.org".to_owned(),
+            discogs_url: "https://api.discogs.com".to_owned(),
+            discogs_token: None,
+        }
+    }
+}
+
+/// An identifier to look an item up by.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum Identifier {
+    /// An ISBN-13.
+    Isbn(String),
+    Lccn(String),
+    Oclc(String),
+    MusicbrainzReleaseGroup(String),
+    DiscogsRelease(String),
+    /// A UPC or EAN from the back of a record or CD.
+    Upc(String),
+}
+
+/// Another catalog that can fill in a new item, so it doesn't have to be typed in by hand.
+pub(crate) trait MetadataProvider {
+    /// Returns a draft item for review, or `None` if the provider doesn't know the identifier.
+    /// Fields the provider doesn't know, like the classification, come from `defaults`.
+    fn lookup(&self, identifier: &Identifier, defaults: ImportDefaults) -> Fallible<Option<Item>>;
+}
+
+/// Every configured provider, in the order to try them.
+pub(crate) fn providers(config: &LookupConfig) -> Vec<Box<dyn MetadataProvider>> {
+    vec![
+        Box::new(OpenLibrary::new(&config.openlibrary_url)),
+        Box::new(MusicBrainz::new(&config.musicbrainz_url)),
+        Box::new(Discogs::new(
+            &config.discogs_url,
+            config.discogs_token.clone(),
+        )),
+    ]
+}
+
+/// Returns the first draft item any of the providers has for an identifier.
+pub(crate) fn lookup(
+    providers: &[Box<dyn MetadataProvider>],
+    identifier: &Identifier,
+    defaults: ImportDefaults,
+) -> Fallible<Option<Item>> {
+    for provider in providers {
+        if let Some(item) = provider.lookup(identifier, defaults)? {
+            return Ok(Some(item));
+        }
+    }
+    Ok(None)
+}
+
+/// Starts a draft item, taking what the provider doesn't know from `defaults`.
+fn draft(
+    provider: &str,
+    title: String,
+    language: &str,
+    format: Option<Format>,
+    defaults: ImportDefaults,
+) -> Fallible<Item> {
+    let classification = defaults.classification.ok_or_else(|| {
+        failure::err_msg(format!(
+            "{} doesn't know LESB, so items need a --classification",
+            provider
+        ))
+    })?;
+    let format = format.or(defaults.format).ok_or_else(|| {
+        failure::err_msg(format!(
+            "{} doesn't say what format {:?} is, so it needs an --item-format",
+            provider, title
+        ))
+    })?;
+    Ok(Item::new(
+        classification,
+        title,
+        language.to_owned(),
+        format,
+        defaults.location,
+    ))
+}
+
+/// Parses a date like "1998", "1998-05" or "1998-05-12". Discogs writes unknown parts as "00".
+fn release_date(date: &str) -> Option<PartialDate> {
+    date.trim_end_matches("-00").parse().ok()
+}
+
+/// Sends a request and parses the JSON response, or returns `None` if it's a 404.
+fn get_json<T: DeserializeOwned>(request: RequestBuilder) -> Fallible<Option<T>> {
+    let response = request.header(USER_AGENT, USER_AGENT_STRING).send()?;
+    if response.status() == StatusCode::NOT_FOUND {
+        return Ok(None);
+    }
+    Ok(Some(response.error_for_status()?.json()?))
+}
+
+/// The Open Library books API, which takes all three kinds of identifier.
+pub(crate) struct OpenLibrary {
+    base_url: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct Book {
+    details: Edition,
+}
+
+/// An Open Library edition record, which is one printing of a work.
+#[derive(Debug, Deserialize)]
+struct Edition {
+    /// Like "/books/OL7353617M".
+    key: String,
+    title: String,
+    subtitle: Option<String>,
+    #[serde(default)]
+    authors: Vec<Author>,
+    /// Free-form, like "1998", "April 2000" or "Mar 01, 1999".
+    publish_date: Option<String>,
+    /// Keys like "/languages/eng", which end in a MARC language code.
+    #[serde(default)]
+    languages: Vec<Key>,
+    /// Like "Paperback".
+    physical_format: Option<String>,
+    #[serde(default)]
+    isbn_13: Vec<String>,
+    #[serde(default)]
+    isbn_10: Vec<String>,
+    #[serde(default)]
+    lccn: Vec<String>,
+    #[serde(default)]
+    oclc_numbers: Vec<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Author {
+    name: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct Key {
+    key: String,
+}
+
+impl Edition {
+    fn to_item(&self, defaults: ImportDefaults) -> Fallible<Item> {
+        let title = match &self.subtitle {
+            Some(subtitle) => format!("{}: {}", self.title.trim(), subtitle.trim()),
+            None => self.title.trim().to_owned(),
+        };
+        let language = self
+            .languages
+            .first()
+            .and_then(|language| language.key.rsplit('/').next())
+            .unwrap_or("und");
+        let format = self
+            .physical_format
+            .as_ref()
+            .and_then(|format| Format::from_binding(format));
+        let mut item = draft("Open Library", title, language, format, defaults)?;
+
+        item.authors = self
+            .authors
+            .iter()
+            .map(|author| sort_name(&author.name))
+            .collect();
+        item.original_date = self.publish_date.as_ref().and_then(|date| {
+            date.split(|c: char| !c.is_ascii_digit())
+                .find(|part| part.len() == 4)
+                .and_then(|year| year.parse().ok())
+                .map(|year| PartialDate(year, None))
+        });
+        item.isbn13 = self
+            .isbn_13
+            .iter()
+            .chain(&self.isbn_10)
+            .find_map(|isbn| normalize_isbn(isbn));
+        item.lccn = self.lccn.first().cloned();
+        item.oclc_number = self.oclc_numbers.first().cloned();
+        item.openlibrary_id = Some(self.key.trim_start_matches("/books/").to_owned());
+        Ok(item)
+    }
+}
+
+impl OpenLibrary {
+    pub(crate) fn new(base_url: &str) -> OpenLibrary {
+        OpenLibrary {
+            base_url: base_url.trim_end_matches('/').to_owned(),
+        }
+    }
+}
+
+impl MetadataProvider for OpenLibrary {
+    fn lookup(&self, identifier: &Identifier, defaults: ImportDefaults) -> Fallible<Option<Item>> {
+        let bibkey = match identifier {
+            Identifier::Isbn(isbn) => format!("ISBN:{}", isbn),
+            Identifier::Lccn(lccn) => format!("LCCN:{}", lccn),
+            Identifier::Oclc(oclc) => format!("OCLC:{}", oclc),
+            _ => return Ok(None),
+        };
+        let mut books: HashMap<String, Book> = get_json(
+            reqwest::Client::new()
+                .get(&format!("{}/api/books", self.base_url))
+                .query(&[
+                    ("bibkeys", bibkey.as_str()),
+                    ("format", "json"),
+                    ("jscmd", "details"),
+                ]),
+        )?
+        .unwrap_or_default();
+        let book = match books.remove(&bibkey) {
+            Some(book) => book,
+            None => return Ok(None),
+        };
+        let mut item = book.details.to_item(defaults)?;
+        // Keep what was looked up, in case the edition record doesn't list it.
+        match identifier {
+            Identifier::Isbn(isbn) => item.isbn13 = Some(isbn.clone()),
+            Identifier::Lccn(lccn) => item.lccn = Some(lccn.clone()),
+            Identifier::Oclc(oclc) => item.oclc_number = Some(oclc.clone()),
+            _ => {}
+        }
+        Ok(Some(item))
+    }
+}
+
+/// The web service at musicbrainz.org, which takes release group IDs and barcodes.
+pub(crate) struct MusicBrainz {
+    base_url: String,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+struct ArtistCredit {
+    artist: Artist,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+struct Artist {
+    /// Like "Hanna, Kathleen" or "Bikini Kill".
+    sort_name: String,
+}
+
+/// An album, single or other work, which has a release for each pressing.
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+struct ReleaseGroup {
+    id: String,
+    title: String,
+    #[serde(default)]
+    first_release_date: String,
+    #[serde(default)]
+    artist_credit: Vec<ArtistCredit>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+struct Release {
+    title: String,
+    #[serde(default)]
+    date: String,
+    #[serde(default)]
+    artist_credit: Vec<ArtistCredit>,
+    #[serde(default)]
+    media: Vec<Medium>,
+    release_group: Option<ReleaseGroup>,
+    text_representation: Option<TextRepresentation>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Medium {
+    /// Like "CD", "7\" Vinyl" or "Cassette".
+    format: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct TextRepresentation {
+    /// An ISO 639-3 code, which is the MARC code for most languages.
+    language: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Releases {
+    releases: Vec<Release>,
+}
+
+fn sort_names(credits: &[ArtistCredit]) -> Vec<String> {
+    credits
+        .iter()
+        .map(|credit| credit.artist.sort_name.clone())
+        .collect()
+}
+
+impl Release {
+    fn format(&self) -> Option<Format> {
+        self.media
+            .iter()
+            .filter_map(|medium| medium.format.as_ref())
+            .find_map(|format| Format::from_binding(format))
+    }
+}
+
+impl MusicBrainz {
+    pub(crate) fn new(base_url: &str) -> MusicBrainz {
+        MusicBrainz {
+            base_url: base_url.trim_end_matches('/').to_owned(),
+        }
+    }
+
+    fn get<T: DeserializeOwned>(&self, path: &str, query: &[(&str, &str)]) -> Fallible<Option<T>> {
+        get_json(
+            reqwest::Client::new()
+                .get(&format!("{}/ws/2/{}", self.base_url, path))
+                .query(query)
+                .query(&[("fmt", "json")]),
+        )
+    }
+
+    /// A release group doesn't have a medium, but its releases might all agree on one.
+    fn release_group_format(&self, id: &str) -> Fallible<Option<Format>> {
+        let releases =
+            match self.get::<Releases>("release", &[("release-group", id), ("inc", "media")])? {
+                Some(releases) => releases.releases,
+                None => return Ok(None),
+            };
+        let mut formats = releases.iter().filter_map(Release::format);
+        Ok(formats
+            .next()
+            .filter(|first| formats.all(|format| format == *first)))
+    }
+
+    fn release_group(&self, id: &str, defaults: ImportDefaults) -> Fallible<Option<Item>> {
+        let group = match self.get::<ReleaseGroup>(
+            &format!("release-group/{}", id),
+            &[("inc", "artist-credits")],
+        )? {
+            Some(group) => group,
+            None => return Ok(None),
+        };
+        let format = match defaults.format {
+            Some(_) => None,
+            None => self.release_group_format(id)?,
+        };
+        let mut item = draft("MusicBrainz", group.title, "und", format, defaults)?;
+        item.authors = sort_names(&group.artist_credit);
+        item.original_date = release_date(&group.first_release_date);
+        item.musicbrainz_release_group = Some(group.id);
+        Ok(Some(item))
+    }
+
+    fn barcode(&self, upc: &str, defaults: ImportDefaults) -> Fallible<Option<Item>> {
+        let release = match self
+            .get::<Releases>("release", &[("query", &format!("barcode:{}", upc))])?
+            .and_then(|releases| releases.releases.into_iter().next())
+        {
+            Some(release) => release,
+            None => return Ok(None),
+        };
+        let language = release
+            .text_representation
+            .as_ref()
+            .and_then(|text| text.language.as_ref())
+            .map_or("und", String::as_str);
+        let format = release.format();
+        let mut item = draft("MusicBrainz", release.title, language, format, defaults)?;
+        item.authors = sort_names(&release.artist_credit);
+        item.original_date = release_date(&release.date);
+        item.musicbrainz_release_group = release.release_group.map(|group| group.id);
+        Ok(Some(item))
+    }
+}
+
+impl MetadataProvider for MusicBrainz {
+    fn lookup(&self, identifier: &Identifier, defaults: ImportDefaults) -> Fallible<Option<Item>> {
+        match identifier {
+            Identifier::MusicbrainzReleaseGroup(id) => self.release_group(id, defaults),
+            Identifier::Upc(upc) => self.barcode(upc, defaults),
+            _ => Ok(None),
+        }
+    }
+}
+
+/// The Discogs API, which takes release IDs, and barcodes if there's a token.
+pub(crate) struct Discogs {
+    base_url: String,
+    token: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct DiscogsRelease {
+    id: u64,
+    title: String,
+    #[serde(default)]
+    artists: Vec<DiscogsArtist>,
+    /// Like "1995", "1995-03-00" or "1995-03-14".
+    #[serde(default)]
+    released: String,
+    #[serde(default)]
+    formats: Vec<DiscogsFormat>,
+}
+
+#[derive(Debug, Deserialize)]
+struct DiscogsArtist {
+    /// Like "Slits, The", or "Bratmobile (2)" when several artists share a name.
+    name: String,
+    /// The name as credited on this release, if it's different.
+    #[serde(default)]
+    anv: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct DiscogsFormat {
+    /// Like "Vinyl", "CD" or "Cassette".
+    name: String,
+    /// Like `["7\"", "Single", "45 RPM"]`.
+    #[serde(default)]
+    descriptions: Vec<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct SearchResults {
+    results: Vec<SearchResult>,
+}
+
+#[derive(Debug, Deserialize)]
+struct SearchResult {
+    id: u64,
+}
+
+impl DiscogsArtist {
+    fn credit(&self) -> String {
+        let name = if self.anv.is_empty() {
+            &self.name
+        } else {
+            &self.anv
+        };
+        match name.rfind(" (") {
+            Some(index) if name.ends_with(')') => name[..index].to_owned(),
+            _ => name.clone(),
+        }
+    }
+}
+
+impl DiscogsFormat {
+    fn format(&self) -> Option<Format> {
+        // Only keep the size, so "78 RPM" doesn't look like a 7" record.
+        let mut medium = self.name.clone();
+        for description in self.descriptions.iter().filter(|d| d.ends_with('"')) {
+            medium.push(' ');
+            medium.push_str(description);
+        }
+        Format::from_binding(&medium)
+    }
+}
+
+impl Discogs {
+    pub(crate) fn new(base_url: &str, token: Option<String>) -> Discogs {
+        Discogs {
+            base_url: base_url.trim_end_matches('/').to_owned(),
+            token,
+        }
+    }
+
+    fn get<T: DeserializeOwned>(&self, path: &str, query: &[(&str, &str)]) -> Fallible<Option<T>> {
+        let mut request = reqwest::Client::new()
+            .get(&format!("{}/{}", self.base_url, path))
+            .query(query);
+        if let Some(token) = &self.token {
+            request = request.header(AUTHORIZATION, format!("Discogs token={}", token));
+        }
+        get_json(request)
+    }
+
+    fn release(&self, id: &str, defaults: ImportDefaults) -> Fallible<Option<Item>> {
+        let release = match self.get::<DiscogsRelease>(&format!("releases/{}", id), &[])? {
+            Some(release) => release,
+            None => return Ok(None),
+        };
+        let format = release.formats.iter().find_map(DiscogsFormat::format);
+        let mut item = draft("Discogs", release.title, "und", format, defaults)?;
+        item.authors = release.artists.iter().map(DiscogsArtist::credit).collect();
+        item.original_date = release_date(&release.released);
+        item.discogs_release = Some(release.id.to_string());
+        Ok(Some(item))
+    }
+}
+
+impl MetadataProvider for Discogs {
+    fn lookup(&self, identifier: &Identifier, defaults: ImportDefaults) -> Fallible<Option<Item>> {
+        match identifier {
+            Identifier::DiscogsRelease(id) => self.release(id, defaults),
+            // Discogs only lets signed-in users search.
+            Identifier::Upc(upc) if self.token.is_some() => {
+                match self
+                    .get::<SearchResults>(
+                        "database/search",
+                        &[("barcode", upc), ("type", "release")],
+                    )?
+                    .and_then(|results| results.results.into_iter().next())
+                {
+                    Some(result) => self.release(&result.id.to_string(), defaults),
+                    None => Ok(None),
+                }
+            }
+            _ => Ok(None),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{lookup, Identifier, LookupConfig, MetadataProvider, OpenLibrary};
+    use crate::date::PartialDate;
+    use crate::format::Format;
+    use crate::lesb::LESBClassification;
+    use crate::location::Location;
+    use crate::marc::ImportDefaults;
+    use failure::Fallible;
+    use rouille::{Request, Response, Server};
+    use std::thread;
+
+    const BOOKS: &str = r#"{
+        "ISBN:9781573227889": {
+            "bib_key": "ISBN:9781573227889",
+            "details": {
+                "key": "/books/OL24274306M",
+                "title": "Tipping the velvet",
+                "subtitle": "a novel",
+                "authors": [{"key": "/authors/OL35885A", "name": "Sarah Waters"}],
+                "publish_date": "April 2000",
+                "languages": [{"key": "/languages/eng"}],
+                "physical_format": "Paperback",
+                "isbn_10": ["1573227889"],
+                "lccn": ["99055436"],
+                "oclc_numbers": ["43060581"]
+            }
+        }
+    }"#;
+
+    const RELEASE_GROUP: &str = r#"{
+        "id": "5ed7b2a6-ecb2-3e1c-8bc0-ec3e4bd6b4b9",
+        "title": "Pussy Whipped",
+        "first-release-date": "1993-10-26",
+        "primary-type": "Album",
+        "artist-credit": [{
+            "name": "Bikini Kill",
+            "joinphrase": "",
+            "artist": {"id": "ad0ee9d2-b4f0-4b65-9d1c-7b5ec4b1a9c8", "name": "Bikini Kill", "sort-name": "Bikini Kill"}
+        }]
+    }"#;
+
+    const RELEASES: &str = r#"{
+        "releases": [
+            {"title": "Pussy Whipped", "media": [{"format": "12\" Vinyl"}]},
+            {"title": "Pussy Whipped", "media": [{"format": "CD"}]}
+        ]
+    }"#;
+
+    const BARCODE_RELEASES: &str = r#"{
+        "releases": [{
+            "title": "Tegan and Sara Present The Con X: Covers",
+            "date": "2018-01-26",
+            "artist-credit": [{"name": "Various Artists", "artist": {"sort-name": "Various Artists"}}],
+            "media": [{"format": "CD", "track-count": 14}],
+            "release-group": {"id": "0b4e2e6f-3a5b-4b5a-9d62-5b5f8a3c5e7f", "title": "The Con X: Covers"},
+            "text-representation": {"language": "eng", "script": "Latn"}
+        }]
+    }"#;
+
+    const DISCOGS_RELEASE: &str = r#"{
+        "id": 1210380,
+        "title": "Kaleidoscope",
+        "artists": [{"name": "Kelis (2)", "anv": "", "join": ""}],
+        "released": "1999-12-00",
+        "formats": [{"name": "Vinyl", "qty": "2", "descriptions": ["12\"", "78 RPM", "Album"]}]
+    }"#;
+
+    fn json(body: &'static str) -> Response {
+        Response::from_data("application/json", body)
+    }
+
+    /// Answers for a few fixtures the way the real services do.
+    fn fixtures(request: &Request) -> Response {
+        let param = |name| request.get_param(name).unwrap_or_default();
+        match request.url().as_str() {
+            "/api/books" if param("bibkeys") == "ISBN:9781573227889" => json(BOOKS),
+            "/api/books" => json("{}"),
+            "/ws/2/release-group/5ed7b2a6-ecb2-3e1c-8bc0-ec3e4bd6b4b9" => json(RELEASE_GROUP),
+            "/ws/2/release" if param("query") == "barcode:067003114528" => json(BARCODE_RELEASES),
+            "/ws/2/release" if param("release-group").is_empty() => json(r#"{"releases": []}"#),
+            "/ws/2/release" => json(RELEASES),
+            "/releases/1210380" => json(DISCOGS_RELEASE),
+            "/database/search"
+                if request.header("Authorization") == Some("Discogs token=secret") =>
+            {
+                json(r#"{"results": [{"id": 1210380}]}"#)
+            }
+            _ => Response::empty_404(),
+        }
+    }
+
+    fn fixture_config() -> LookupConfig {
+        let server = Server::new("127.0.0.1:0", fixtures).unwrap();
+        let url = format!("http://{}", server.server_addr());
+        thread::spawn(move || server.run());
+        LookupConfig {
+            openlibrary_url: url.clone(),
+            musicbrainz_url: url.clone(),
+            discogs_url: url,
+            discogs_token: None,
+        }
+    }
+
+    fn defaults(classification: LESBClassification) -> ImportDefaults {
+        ImportDefaults {
+            classification: Some(classification),
+            format: None,
+            location: Location::Billy,
+        }
+    }
+
+    #[test]
+    fn test_openlibrary() -> Fallible<()> {
+        let providers = super::providers(&fixture_config());
+        let defaults = defaults(LESBClassification::LF);
+
+        let isbn = Identifier::Isbn("9781573227889".to_owned());
+        let item = lookup(&providers, &isbn, defaults)?.unwrap();
+        assert_eq!(item.title, "Tipping the velvet: a novel");
+        assert_eq!(item.authors, vec!["Waters, Sarah"]);
+        assert_eq!(item.original_date, Some(PartialDate(2000, None)));
+        assert_eq!(item.language, "eng");
+        assert_eq!(item.format, Format::Paperback);
+        assert_eq!(item.isbn13, Some("9781573227889".to_owned()));
+        assert_eq!(item.lccn, Some("99055436".to_owned()));
+        assert_eq!(item.openlibrary_id, Some("OL24274306M".to_owned()));
+        assert_eq!(item.call_number(), "LF WATER 2000 eng");
+
+        assert!(lookup(
+            &providers,
+            &Identifier::Lccn("99055436".to_owned()),
+            defaults
+        )?
+        .is_none());
+        let unclassified = ImportDefaults {
+            classification: None,
+            ..defaults
+        };
+        assert!(lookup(&providers, &isbn, unclassified).is_err());
+        assert!(OpenLibrary::new("http://127.0.0.1:1")
+            .lookup(&isbn, defaults)
+            .is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_music() -> Fallible<()> {
+        let mut config = fixture_config();
+        let defaults = defaults(LESBClassification::NR);
+
+        // The release group's releases don't agree on a format.
+        let group =
+            Identifier::MusicbrainzReleaseGroup("5ed7b2a6-ecb2-3e1c-8bc0-ec3e4bd6b4b9".to_owned());
+        assert!(lookup(&super::providers(&config), &group, defaults).is_err());
+        let item = lookup(
+            &super::providers(&config),
+            &group,
+            ImportDefaults {
+                format: Some(Format::Cassette),
+                ..defaults
+            },
+        )?
+        .unwrap();
+        assert_eq!(item.title, "Pussy Whipped");
+        assert_eq!(item.authors, vec!["Bikini Kill"]);
+        assert_eq!(
+            item.original_date,
+            Some(PartialDate(1993, Some((10, Some(26)))))
+        );
+        assert_eq!(item.format, Format::Cassette);
+        assert_eq!(
+            item.musicbrainz_release_group,
+            Some("5ed7b2a6-ecb2-3e1c-8bc0-ec3e4bd6b4b9".to_owned())
+        );
+
+        let upc = Identifier::Upc("067003114528".to_owned());
+        let item = lookup(&super::providers(&config), &upc, defaults)?.unwrap();
+        assert_eq!(item.format, Format::CD);
+        assert_eq!(item.language, "eng");
+        assert_eq!(
+            item.musicbrainz_release_group,
+            Some("0b4e2e6f-3a5b-4b5a-9d62-5b5f8a3c5e7f".to_owned())
+        );
+
+        let discogs = Identifier::DiscogsRelease("1210380".to_owned());
+        let item = lookup(&super::providers(&config), &discogs, defaults)?.unwrap();
+        assert_eq!(item.title, "Kaleidoscope");
+        assert_eq!(item.authors, vec!["Kelis"]);
+        assert_eq!(
+            item.original_date,
+            Some(PartialDate(1999, Some((12, None))))
+        );
+        assert_eq!(item.format, Format::Vinyl12Inch);
+        assert_eq!(item.discogs_release, Some("1210380".to_owned()));
+
+        // MusicBrainz doesn't know this barcode, and Discogs needs a token to search.
+        let upc = Identifier::Upc("602537351169".to_owned());
+        assert!(lookup(&super::providers(&config), &upc, defaults)?.is_none());
+        config.discogs_token = Some("secret".to_owned());
+        let item = lookup(&super::providers(&config), &upc, defaults)?.unwrap();
+        assert_eq!(item.discogs_release, Some("1210380".to_owned()));
+        Ok(())
+    }
+}
@@ -3,19 +3,50 @@
 #![warn(clippy::pedantic)]
 #![allow(clippy::use_self)]
 
+mod audit;
+mod barcode;
+mod circulation;
+mod citation;
+mod config;
+mod cql;
 mod date;
 mod db;
 mod format;
+mod goodreads;
+mod hold;
 mod isbn;
 mod item;
 mod lesb;
+mod librarything;
+mod loan;
 mod location;
+mod lookup;
+mod marc;
+mod replication;
+mod spreadsheet;
+mod token;
 mod user;
 mod web;
 
-use crate::db::Db;
+use crate::barcode::BarcodeFormat;
+use crate::citation::CitationFormat;
+use crate::config::Config;
+use crate::db::{Db, Since};
+use crate::format::Format;
+use crate::isbn::normalize_isbn;
 use crate::item::Item;
-use failure::Fallible;
+use crate::lesb::LESBClassification;
+use crate::location::Location;
+use crate::lookup::Identifier;
+use crate::marc::{ImportDefaults, Record};
+use crate::replication::Remote;
+use crate::spreadsheet::Mapping;
+use crate::token::{Scope, Token};
+use crate::user::User;
+use chrono::{DateTime, Datelike, NaiveDate, Utc};
+use failure::{bail, ensure, Fallible};
+use log::info;
+use serde::Deserialize;
 use std::io;
 use std::io::prelude::*;
 use std::path::PathBuf;
@@ -25,41 +56,736 @@ use structopt::StructOpt;
 struct Opt {
     #[structopt(parse(from_os_str))]
     db_path: PathBuf,
+    /// Barcode of the user making changes, for the audit log
+    #[structopt(long = "as")]
+    actor: Option<u64>,
     #[structopt(subcommand)]
     cmd: SubCommand,
 }
 
 #[derive(Debug, StructOpt)]
 enum SubCommand {
+    #[structopt(name = "audit")]
+    Audit {
+        /// Only show changes to rows in this tree, like "item" or "users"
+        #[structopt(long = "tree")]
+        tree: Option<String>,
+        /// Only show changes to the row with this ID
+        #[structopt(long = "id")]
+        id: Option<u64>,
+        #[structopt(short = "n", long = "limit", default_value = "50")]
+        limit: usize,
+    },
+    #[structopt(name = "barcode")]
+    Barcode {
+        #[structopt(subcommand)]
+        cmd: BarcodeCommand,
+    },
+    /// Looks up a new item by one of its identifiers and prints it for review
+    #[structopt(name = "catalog")]
+    Catalog {
+        #[structopt(long = "isbn")]
+        isbn: Option<String>,
+        #[structopt(long = "lccn")]
+        lccn: Option<String>,
+        #[structopt(long = "oclc")]
+        oclc: Option<String>,
+        /// A MusicBrainz release group ID
+        #[structopt(long = "musicbrainz")]
+        musicbrainz: Option<String>,
+        /// A Discogs release ID
+        #[structopt(long = "discogs")]
+        discogs: Option<String>,
+        /// The UPC or EAN barcode on a record, CD or tape
+        #[structopt(long = "upc")]
+        upc: Option<String>,
+        #[structopt(long = "classification")]
+        classification: LESBClassification,
+        /// The format if the record doesn't say, like paperback or cd
+        #[structopt(long = "item-format", parse(try_from_str = "serde_plain::from_str"))]
+        item_format: Option<Format>,
+        #[structopt(
+            long = "location",
+            default_value = "billy",
+            parse(try_from_str = "serde_plain::from_str")
+        )]
+        location: Location,
+        /// Adds the item after printing it
+        #[structopt(long = "save")]
+        save: bool,
+    },
+    #[structopt(name = "checkin")]
+    CheckIn { item: String },
+    #[structopt(name = "checkout")]
+    CheckOut { item: String, user: u64 },
     #[structopt(name = "dump")]
-    Dump,
+    Dump {
+        /// Only dump changes after an audit log sequence number or a time, including tombstones
+        /// for deleted rows
+        #[structopt(long = "since", parse(try_from_str = "parse_since"))]
+        since: Option<Since>,
+    },
+    /// Writes every item, or the results of a search, as bibliographic records or citations
+    #[structopt(name = "export")]
+    Export {
+        /// marc (ISO 2709), marcxml, csv, bibtex, ris or csl-json
+        #[structopt(long = "format", parse(try_from_str = "serde_plain::from_str"))]
+        format: RecordFormat,
+        /// Only export the items matching a search
+        #[structopt(long = "query")]
+        query: Option<String>,
+        /// The most search results to export
+        #[structopt(short = "n", long = "limit", default_value = "100")]
+        limit: usize,
+    },
+    #[structopt(name = "history")]
+    History { item: String },
+    #[structopt(name = "hold")]
+    Hold {
+        item: String,
+        user: u64,
+        #[structopt(long = "cancel")]
+        cancel: bool,
+    },
+    #[structopt(name = "holds")]
+    Holds { item: String },
+    /// Adds items from bibliographic records on standard input
+    #[structopt(name = "import")]
+    Import {
+        /// marc (ISO 2709), marcxml, csv, goodreads (CSV), or librarything (TSV or JSON)
+        #[structopt(long = "format", parse(try_from_str = "serde_plain::from_str"))]
+        format: RecordFormat,
+        /// A TOML file naming the CSV column for each item field, if they aren't named like
+        /// `export` names them
+        #[structopt(long = "map", parse(from_os_str))]
+        map: Option<PathBuf>,
+        /// The classification for records without a LESB call number, which is every record from
+        /// Goodreads
+        #[structopt(long = "classification")]
+        classification: Option<LESBClassification>,
+        /// The format for records that don't say, like paperback or cd
+        #[structopt(long = "item-format", parse(try_from_str = "serde_plain::from_str"))]
+        item_format: Option<Format>,
+        #[structopt(
+            long = "location",
+            default_value = "billy",
+            parse(try_from_str = "serde_plain::from_str")
+        )]
+        location: Location,
+    },
+    #[structopt(name = "loans")]
+    Loans { barcode: u64 },
+    /// Lists items added since a date, by default the start of this month
+    #[structopt(name = "new")]
+    New {
+        /// A date (2019-06-01) or RFC 3339 timestamp
+        #[structopt(long = "since", parse(try_from_str = "parse_time"))]
+        since: Option<DateTime<Utc>>,
+    },
+    #[structopt(name = "overdue")]
+    Overdue,
+    /// Sends changed items and users to the `[remote]` server
+    #[structopt(name = "push")]
+    Push,
+    /// Applies changes from the `[remote]` server
+    #[structopt(name = "pull")]
+    Pull,
+    #[structopt(name = "renew")]
+    Renew { item: String },
     #[structopt(name = "restore")]
     Restore,
     #[structopt(name = "search")]
-    Search { query: String },
+    Search {
+        query: String,
+        #[structopt(short = "n", long = "limit", default_value = "10")]
+        limit: usize,
+    },
     #[structopt(name = "serve")]
     Serve {
         #[structopt(short = "a", long = "addr", default_value = "localhost:3000")]
         addr: String,
     },
+    #[structopt(name = "token")]
+    Token {
+        #[structopt(subcommand)]
+        cmd: TokenCommand,
+    },
+    #[structopt(name = "user")]
+    User {
+        #[structopt(subcommand)]
+        cmd: UserCommand,
+    },
+}
+
+/// A format for `export` and `import`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+enum RecordFormat {
+    Marc,
+    Marcxml,
+    Csv,
+    /// Goodreads' library export, which can only be imported
+    Goodreads,
+    /// The TSV or JSON export from LibraryThing.com, which can only be imported
+    Librarything,
+    Bibtex,
+    Ris,
+    CslJson,
+}
+
+#[derive(Debug, StructOpt)]
+enum BarcodeCommand {
+    #[structopt(name = "item")]
+    Item {
+        /// Overrides the prefix from config.toml
+        #[structopt(long = "prefix")]
+        prefix: Option<String>,
+        /// Overrides the number of digits from config.toml
+        #[structopt(long = "digits")]
+        digits: Option<usize>,
+        #[structopt(short = "n", long = "count", default_value = "1")]
+        count: u64,
+    },
+    #[structopt(name = "user")]
+    User {
+        /// Overrides the prefix from config.toml
+        #[structopt(long = "prefix")]
+        prefix: Option<String>,
+        /// Overrides the number of digits from config.toml
+        #[structopt(long = "digits")]
+        digits: Option<usize>,
+        #[structopt(short = "n", long = "count", default_value = "1")]
+        count: u64,
+    },
+    #[structopt(name = "check")]
+    Check { barcode: String },
+}
+
+#[derive(Debug, StructOpt)]
+enum TokenCommand {
+    #[structopt(name = "create")]
+    Create {
+        user: u64,
+        name: String,
+        /// catalog, circulation or admin; can be given more than once
+        #[structopt(long = "scope")]
+        scopes: Vec<Scope>,
+    },
+    #[structopt(name = "list")]
+    List { user: u64 },
+    #[structopt(name = "revoke")]
+    Revoke { id: u64 },
+}
+
+#[derive(Debug, StructOpt)]
+enum UserCommand {
+    #[structopt(name = "add")]
+    Add {
+        barcode: u64,
+        name: String,
+        #[structopt(long = "admin")]
+        admin: bool,
+    },
+    #[structopt(name = "block")]
+    Block {
+        barcode: u64,
+        #[structopt(long = "reason")]
+        reason: Option<String>,
+    },
+    #[structopt(name = "delete")]
+    Delete {
+        barcode: u64,
+        #[structopt(long = "force")]
+        force: bool,
+    },
+    #[structopt(name = "demote")]
+    Demote { barcode: u64 },
+    #[structopt(name = "edit")]
+    Edit {
+        barcode: u64,
+        #[structopt(long = "name")]
+        name: Option<String>,
+        #[structopt(long = "display-name")]
+        display_name: Option<String>,
+        #[structopt(long = "email")]
+        email: Option<String>,
+        /// A number, or "none" to use the loan policy's limit
+        #[structopt(long = "loan-limit")]
+        loan_limit: Option<String>,
+        #[structopt(long = "notes")]
+        notes: Option<String>,
+    },
+    #[structopt(name = "list")]
+    List,
+    #[structopt(name = "promote")]
+    Promote { barcode: u64 },
+    #[structopt(name = "search")]
+    Search { query: String },
+    #[structopt(name = "unblock")]
+    Unblock { barcode: u64 },
+}
+
+fn user_command(db: &mut Db, cmd: UserCommand) -> Fallible<()> {
+    match cmd {
+        UserCommand::Add {
+            barcode,
+            name,
+            admin,
+        } => {
+            let mut user = User::new(barcode, name);
+            user.admin = admin;
+            user::add_user(db, &mut user)
+        }
+        UserCommand::Block { barcode, reason } => {
+            let mut user = load_user(db, barcode)?;
+            user.block(reason);
+            db.save(&mut user)
+        }
+        UserCommand::Delete { barcode, force } => {
+            let user = load_user(db, barcode)?;
+            user::delete_user(db, &user, force)
+        }
+        UserCommand::Demote { barcode } => {
+            let mut user = load_user(db, barcode)?;
+            user.admin = false;
+            db.save(&mut user)
+        }
+        UserCommand::Edit {
+            barcode,
+            name,
+            display_name,
+            email,
+            loan_limit,
+            notes,
+        } => {
+            let mut user = load_user(db, barcode)?;
+            if let Some(name) = name {
+                user.name = name;
+            }
+            if let Some(display_name) = display_name {
+                user.display_name = user::non_empty(display_name);
+            }
+            if let Some(email) = email {
+                user.email = user::non_empty(email);
+            }
+            if let Some(notes) = notes {
+                user.notes = user::non_empty(notes);
+            }
+            if let Some(loan_limit) = loan_limit {
+                user.loan_limit = user::parse_loan_limit(&loan_limit)?;
+            }
+            db.save(&mut user)
+        }
+        UserCommand::List => {
+            for user in db.iter::<User>()? {
+                serde_json::to_writer(&mut io::stdout(), &user?)?;
+                io::stdout().write_all(b"\n")?;
+            }
+            Ok(())
+        }
+        UserCommand::Promote { barcode } => {
+            let mut user = load_user(db, barcode)?;
+            user.admin = true;
+            db.save(&mut user)
+        }
+        UserCommand::Search { query } => {
+            for user in db.query::<User>(&query)? {
+                serde_json::to_writer(&mut io::stdout(), &user)?;
+                io::stdout().write_all(b"\n")?;
+            }
+            Ok(())
+        }
+        UserCommand::Unblock { barcode } => {
+            let mut user = load_user(db, barcode)?;
+            user.unblock();
+            db.save(&mut user)
+        }
+    }
+}
+
+fn token_command(db: &mut Db, cmd: TokenCommand) -> Fallible<()> {
+    match cmd {
+        TokenCommand::Create { user, name, scopes } => {
+            println!("{}", token::create_token(db, user, name, scopes)?);
+            Ok(())
+        }
+        TokenCommand::List { user } => {
+            for token in db.tokens_for_user(user)? {
+                serde_json::to_writer(&mut io::stdout(), &token)?;
+                io::stdout().write_all(b"\n")?;
+            }
+            Ok(())
+        }
+        TokenCommand::Revoke { id } => {
+            ensure!(db.load::<Token>(id)?.is_some(), "no token with id {}", id);
+            db.delete::<Token>(id)
+        }
+    }
+}
+
+fn parse_time(s: &str) -> Fallible<DateTime<Utc>> {
+    Ok(match DateTime::parse_from_rfc3339(s) {
+        Ok(time) => time.with_timezone(&Utc),
+        Err(_) => DateTime::from_utc(
+            NaiveDate::parse_from_str(s, "%Y-%m-%d")?.and_hms(0, 0, 0),
+            Utc,
+        ),
+    })
+}
+
+fn parse_since(s: &str) -> Fallible<Since> {
+    match s.parse() {
+        Ok(sequence) => Ok(Since::Sequence(sequence)),
+        Err(_) => Ok(Since::Time(parse_time(s)?)),
+    }
+}
+
+fn load_remote(config: &Config) -> Fallible<&Remote> {
+    config
+        .remote
+        .as_ref()
+        .ok_or_else(|| failure::err_msg("no [remote] server in config.toml"))
+}
+
+fn load_item(db: &Db, barcode: &str) -> Fallible<Item> {
+    db.load_by_barcode(barcode)?
+        .ok_or_else(|| failure::err_msg(format!("no item with barcode {:?}", barcode)))
+}
+
+fn load_user(db: &Db, barcode: u64) -> Fallible<User> {
+    db.load(barcode)?
+        .ok_or_else(|| failure::err_msg(format!("no user with barcode {}", barcode)))
 }
 
+#[allow(clippy::too_many_lines)]
 fn main() -> Fallible<()> {
     env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("lesbians=info"))
         .init();
 
     let opt = Opt::from_args();
+    let config = Config::load(&opt.db_path)?;
     let mut db = Db::open(opt.db_path)?;
+    db.set_actor(opt.actor);
     match opt.cmd {
-        SubCommand::Dump => db.dump(io::stdout()),
+        SubCommand::Audit { tree, id, limit } => {
+            let entries = db.audit_log(
+                |entry| {
+                    tree.as_ref().map_or(true, |tree| &entry.tree == tree)
+                        && id.map_or(true, |id| entry.id == id)
+                },
+                limit,
+            )?;
+            for entry in entries {
+                serde_json::to_writer(&mut io::stdout(), &entry)?;
+                io::stdout().write_all(b"\n")?;
+            }
+            Ok(())
+        }
+        SubCommand::Barcode { cmd } => match cmd {
+            BarcodeCommand::Item {
+                prefix,
+                digits,
+                count,
+            } => {
+                let format = BarcodeFormat {
+                    prefix: prefix.unwrap_or_else(|| config.barcodes.item.prefix.clone()),
+                    digits: digits.unwrap_or(config.barcodes.item.digits),
+                };
+                for _ in 0..count {
+                    println!("{}", format.next_item_barcode(&db)?);
+                }
+                Ok(())
+            }
+            BarcodeCommand::User {
+                prefix,
+                digits,
+                count,
+            } => {
+                let format = BarcodeFormat {
+                    prefix: prefix.unwrap_or_else(|| config.barcodes.user.prefix.clone()),
+                    digits: digits.unwrap_or(config.barcodes.user.digits),
+                };
+                for _ in 0..count {
+                    println!("{}", format.next_user_barcode(&db)?);
+                }
+                Ok(())
+            }
+            BarcodeCommand::Check { barcode } => {
+                ensure!(
+                    barcode::is_valid(&barcode),
+                    "{} has a bad check digit",
+                    barcode
+                );
+                ensure!(
+                    config.barcodes.item.validate(&barcode)
+                        || config.barcodes.user.validate(&barcode),
+                    "{} is not an item or user barcode",
+                    barcode
+                );
+                Ok(())
+            }
+        },
+        SubCommand::Catalog {
+            isbn,
+            lccn,
+            oclc,
+            musicbrainz,
+            discogs,
+            upc,
+            classification,
+            item_format,
+            location,
+            save,
+        } => {
+            let isbn = isbn
+                .map(|isbn| {
+                    normalize_isbn(&isbn)
+                        .map(Identifier::Isbn)
+                        .ok_or_else(|| failure::err_msg(format!("{:?} isn't an ISBN", isbn)))
+                })
+                .transpose()?;
+            let mut identifiers = vec![
+                isbn,
+                lccn.map(Identifier::Lccn),
+                oclc.map(Identifier::Oclc),
+                musicbrainz.map(Identifier::MusicbrainzReleaseGroup),
+                discogs.map(Identifier::DiscogsRelease),
+                upc.map(Identifier::Upc),
+            ]
+            .into_iter()
+            .flatten();
+            let identifier = match (identifiers.next(), identifiers.next()) {
+                (Some(identifier), None) => identifier,
+                _ => bail!("give one of --isbn, --lccn, --oclc, --musicbrainz, --discogs or --upc"),
+            };
+            let defaults = ImportDefaults {
+                classification: Some(classification),
+                format: item_format,
+                location,
+            };
+            let mut item =
+                lookup::lookup(&lookup::providers(&config.lookup), &identifier, defaults)?
+                    .ok_or_else(|| {
+                        failure::err_msg(format!("nothing found for {:?}", identifier))
+                    })?;
+            if save {
+                db.save(&mut item)?;
+            }
+            serde_json::to_writer_pretty(&mut io::stdout(), &item)?;
+            io::stdout().write_all(b"\n")?;
+            Ok(())
+        }
+        SubCommand::CheckIn { item } => {
+            let mut item = load_item(&db, &item)?;
+            let (loan, hold) = circulation::check_in(&mut db, &mut item)?;
+            if let Some(hold) = hold {
+                let user = load_user(&db, hold.user)?;
+                info!("{:?} goes on the hold shelf for {}", item.title, user.name);
+            }
+            serde_json::to_writer(&mut io::stdout(), &loan)?;
+            io::stdout().write_all(b"\n")?;
+            Ok(())
+        }
+        SubCommand::CheckOut { item, user } => {
+            let mut item = load_item(&db, &item)?;
+            let user = load_user(&db, user)?;
+            circulation::check_out(&mut db, &config.loans, &mut item, &user)
+        }
+        SubCommand::Dump { since } => {
+            let sequence = db.last_sequence()?;
+            match since {
+                Some(since) => db.dump_since(io::stdout(), since)?,
+                None => db.dump(io::stdout())?,
+            }
+            if let Some(sequence) = sequence {
+                info!("dumped through sequence {}", sequence);
+            }
+            Ok(())
+        }
+        SubCommand::Export {
+            format,
+            query,
+            limit,
+        } => {
+            let items = match query {
+                Some(query) => db.query_with_limit::<Item>(&query, limit)?,
+                None => db.iter::<Item>()?.collect::<Fallible<Vec<_>>>()?,
+            };
+            let stdout = io::stdout();
+            let mut stdout = stdout.lock();
+            match format {
+                RecordFormat::Marc => {
+                    for item in &items {
+                        Record::from_item(item).write_iso2709(&mut stdout)?;
+                    }
+                }
+                RecordFormat::Marcxml => {
+                    let records = items.iter().map(Record::from_item).collect::<Vec<_>>();
+                    marc::write_marcxml(&mut stdout, &records)?;
+                }
+                RecordFormat::Csv => spreadsheet::write_items(&mut stdout, &items)?,
+                RecordFormat::Bibtex => {
+                    citation::write_citations(&mut stdout, CitationFormat::Bibtex, &items)?;
+                }
+                RecordFormat::Ris => {
+                    citation::write_citations(&mut stdout, CitationFormat::Ris, &items)?;
+                }
+                RecordFormat::CslJson => {
+                    citation::write_citations(&mut stdout, CitationFormat::CslJson, &items)?;
+                }
+                RecordFormat::Goodreads | RecordFormat::Librarything => {
+                    bail!("Goodreads and LibraryThing exports can only be imported")
+                }
+            }
+            Ok(())
+        }
+        SubCommand::History { item } => {
+            let item = load_item(&db, &item)?;
+            if let Some(id) = item.id() {
+                for loan in db.loan_history(id)? {
+                    serde_json::to_writer(&mut io::stdout(), &loan)?;
+                    io::stdout().write_all(b"\n")?;
+                }
+            }
+            Ok(())
+        }
+        SubCommand::Hold { item, user, cancel } => {
+            let item = load_item(&db, &item)?;
+            let user = load_user(&db, user)?;
+            if cancel {
+                circulation::cancel_hold(&mut db, &item, &user)
+            } else {
+                circulation::place_hold(&mut db, &item, &user).map(|_| ())
+            }
+        }
+        SubCommand::Holds { item } => {
+            let item = load_item(&db, &item)?;
+            if let Some(id) = item.id() {
+                for hold in db.holds_for_item(id)? {
+                    serde_json::to_writer(&mut io::stdout(), &hold)?;
+                    io::stdout().write_all(b"\n")?;
+                }
+            }
+            Ok(())
+        }
+        SubCommand::Import {
+            format,
+            map,
+            classification,
+            item_format,
+            location,
+        } => {
+            ensure!(
+                map.is_none() || format == RecordFormat::Csv,
+                "--map is only for CSV files"
+            );
+            let defaults = ImportDefaults {
+                classification,
+                format: item_format,
+                location,
+            };
+            // Convert every record first, so a bad one doesn't leave half an import behind.
+            let items = match format {
+                RecordFormat::Marc | RecordFormat::Marcxml => {
+                    let records = match format {
+                        RecordFormat::Marc => marc::read_iso2709(io::stdin().lock())?,
+                        _ => marc::read_marcxml(io::stdin().lock())?,
+                    };
+                    records
+                        .iter()
+                        .map(|record| record.to_item(defaults))
+                        .collect::<Fallible<Vec<_>>>()?
+                }
+                RecordFormat::Csv => {
+                    let mapping = map.map(Mapping::load).transpose()?;
+                    spreadsheet::valid_items(spreadsheet::read_items(
+                        io::stdin().lock(),
+                        mapping.as_ref(),
+                        defaults,
+                    )?)?
+                }
+                RecordFormat::Goodreads => {
+                    spreadsheet::valid_items(goodreads::read_books(io::stdin().lock(), defaults)?)?
+                }
+                RecordFormat::Librarything => spreadsheet::valid_items(librarything::read_books(
+                    io::stdin().lock(),
+                    defaults,
+                )?)?,
+                RecordFormat::Bibtex | RecordFormat::Ris | RecordFormat::CslJson => {
+                    bail!("citations can only be exported")
+                }
+            };
+            let count = items.len();
+            for mut item in items {
+                db.save(&mut item)?;
+            }
+            info!("imported {} items", count);
+            Ok(())
+        }
+        SubCommand::Loans { barcode } => {
+            for item in db.loans_for_user(barcode)? {
+                serde_json::to_writer(&mut io::stdout(), &item)?;
+                io::stdout().write_all(b"\n")?;
+            }
+            Ok(())
+        }
+        SubCommand::New { since } => {
+            let since = since.unwrap_or_else(|| {
+                let today = Utc::today();
+                today.with_day(1).unwrap_or(today).and_hms(0, 0, 0)
+            });
+            for item in db.added_since::<Item>(since, None, None)? {
+                serde_json::to_writer(&mut io::stdout(), &item)?;
+                io::stdout().write_all(b"\n")?;
+            }
+            Ok(())
+        }
+        SubCommand::Overdue => {
+            for entry in circulation::overdue(&db, Utc::now())? {
+                println!(
+                    "{}\t{}\t{}\t{}",
+                    entry.due_date(),
+                    entry.item.call_number(),
+                    entry.item.title,
+                    entry.borrower
+                );
+            }
+            Ok(())
+        }
+        SubCommand::Renew { item } => {
+            let mut item = load_item(&db, &item)?;
+            circulation::renew(&mut db, &config.loans, &mut item)
+        }
+        SubCommand::Pull => {
+            let remote = load_remote(&config)?;
+            let merged = replication::pull(&mut db, remote)?;
+            info!(
+                "pulled {} changes from {}, keeping {} newer local rows and rejecting {} with \
+                 conflicting IDs",
+                merged.applied, remote.url, merged.skipped, merged.rejected
+            );
+            Ok(())
+        }
+        SubCommand::Push => {
+            let remote = load_remote(&config)?;
+            let merged = replication::push(&mut db, remote)?;
+            info!(
+                "pushed {} changes to {}, which kept {} newer rows and rejected {} with \
+                 conflicting IDs",
+                merged.applied, remote.url, merged.skipped, merged.rejected
+            );
+            Ok(())
+        }
         SubCommand::Restore => db.restore(io::stdin().lock()),
-        SubCommand::Search { query } => {
-            for item in db.query::<Item>(&query)? {
+        SubCommand::Search { query, limit } => {
+            for item in db.query_with_limit::<Item>(&query, limit)? {
                 serde_json::to_writer(&mut io::stdout(), &item)?;
                 io::stdout().write_all(b"\n")?;
             }
             Ok(())
         }
-        SubCommand::Serve { addr } => crate::web::serve(addr, db),
+        SubCommand::Serve { addr } => crate::web::serve(addr, db, config),
+        SubCommand::Token { cmd } => token_command(&mut db, cmd),
+        SubCommand::User { cmd } => user_command(&mut db, cmd),
     }
 }
@@ -0,0 +1,662 @@
+// SPDX-License-Identifier: AGPL-3.0-only
+
+use crate::date::PartialDate;
+use crate::format::Format;
+use crate::isbn::normalize_isbn;
+use crate::item::Item;
+use crate::lesb::LESBClassification;
+use crate::location::Location;
+use chrono::Utc;
+use failure::{ensure, Fallible};
+use std::io::prelude::*;
+use std::str;
+use xml::escape::{escape_str_attribute, escape_str_pcdata};
+use xml::reader::{EventReader, XmlEvent};
+
+const SUBFIELD_DELIMITER: u8 = 0x1f;
+const FIELD_TERMINATOR: u8 = 0x1e;
+const RECORD_TERMINATOR: u8 = 0x1d;
+
+/// The local field for what MARC has no standard home for: `$a` is the call number and `$f` is
+/// the item's format.
+const LOCAL_FIELD: &str = "099";
+
+const OCLC_PREFIX: &str = "(OCoLC)";
+
+const MARCXML_NAMESPACE: &str = "http://www.loc.gov/MARC21/slim";
+
+/// A MARC 21 bibliographic record.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Record {
+    pub(crate) leader: String,
+    pub(crate) fields: Vec<Field>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum Field {
+    /// A 00X field, which has a value instead of subfields.
+    Control { tag: String, value: String },
+    Data {
+        tag: String,
+        indicators: [char; 2],
+        subfields: Vec<(char, String)>,
+    },
+}
+
+impl Field {
+    fn control(tag: &str, value: String) -> Field {
+        Field::Control {
+            tag: tag.to_owned(),
+            value,
+        }
+    }
+
+    fn data(tag: &str, indicators: [char; 2], subfields: Vec<(char, String)>) -> Field {
+        Field::Data {
+            tag: tag.to_owned(),
+            indicators,
+            subfields,
+        }
+    }
+
+    pub(crate) fn tag(&self) -> &str {
+        match self {
+            Field::Control { tag, .. } | Field::Data { tag, .. } => tag,
+        }
+    }
+
+    fn to_iso2709(&self) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        match self {
+            Field::Control { value, .. } => bytes.extend_from_slice(value.as_bytes()),
+            Field::Data {
+                indicators,
+                subfields,
+                ..
+            } => {
+                for indicator in indicators {
+                    bytes.extend_from_slice(indicator.encode_utf8(&mut [0; 4]).as_bytes());
+                }
+                for (code, value) in subfields {
+                    bytes.push(SUBFIELD_DELIMITER);
+                    bytes.extend_from_slice(code.encode_utf8(&mut [0; 4]).as_bytes());
+                    bytes.extend_from_slice(value.as_bytes());
+                }
+            }
+        }
+        bytes.push(FIELD_TERMINATOR);
+        bytes
+    }
+
+    fn from_iso2709(tag: &str, bytes: &[u8]) -> Field {
+        let bytes = match bytes.split_last() {
+            Some((&FIELD_TERMINATOR, bytes)) => bytes,
+            _ => bytes,
+        };
+        if tag.starts_with("00") {
+            return Field::control(tag, String::from_utf8_lossy(bytes).into_owned());
+        }
+        let mut parts = bytes.split(|b| *b == SUBFIELD_DELIMITER);
+        let indicators = String::from_utf8_lossy(parts.next().unwrap_or_default());
+        let mut indicators = indicators.chars();
+        let indicators = [
+            indicators.next().unwrap_or(' '),
+            indicators.next().unwrap_or(' '),
+        ];
+        let subfields = parts
+            .filter_map(|part| {
+                let part = String::from_utf8_lossy(part);
+                let mut chars = part.chars();
+                let code = chars.next()?;
+                Some((code, chars.as_str().to_owned()))
+            })
+            .collect();
+        Field::data(tag, indicators, subfields)
+    }
+
+    fn to_marcxml(&self) -> String {
+        match self {
+            Field::Control { tag, value } => format!(
+                "<controlfield tag=\"{}\">{}</controlfield>",
+                escape_str_attribute(tag),
+                escape_str_pcdata(value)
+            ),
+            Field::Data {
+                tag,
+                indicators,
+                subfields,
+            } => {
+                let mut xml = format!(
+                    "<datafield tag=\"{}\" ind1=\"{}\" ind2=\"{}\">",
+                    escape_str_attribute(tag),
+                    escape_str_attribute(&indicators[0].to_string()),
+                    escape_str_attribute(&indicators[1].to_string())
+                );
+                for (code, value) in subfields {
+                    xml.push_str(&format!(
+                        "<subfield code=\"{}\">{}</subfield>",
+                        escape_str_attribute(&code.to_string()),
+                        escape_str_pcdata(value)
+                    ));
+                }
+                xml.push_str("</datafield>");
+                xml
+            }
+        }
+    }
+}
+
+/// What to use for the fields of an imported item that records from other catalogs don't have.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct ImportDefaults {
+    pub(crate) classification: Option<LESBClassification>,
+    pub(crate) format: Option<Format>,
+    pub(crate) location: Location,
+}
+
+/// Strips the ISBD punctuation that ends subfields, like the " /" before a statement of
+/// responsibility.
+fn trim_punctuation(s: &str) -> &str {
+    let s = s.trim_end_matches(|c: char| c.is_whitespace() || "/:;,=".contains(c));
+    // Keep the period after an initial, like "Tolkien, J. R. R."
+    match s.rsplit(' ').next() {
+        Some(word) if word.ends_with('.') && word.chars().count() > 2 => &s[..s.len() - 1],
+        _ => s,
+    }
+}
+
+impl Record {
+    pub(crate) fn control(&self, tag: &str) -> Option<&str> {
+        self.fields.iter().find_map(|field| match field {
+            Field::Control { tag: t, value } if t == tag => Some(value.as_str()),
+            _ => None,
+        })
+    }
+
+    /// Returns the values of a subfield in every field with a tag, in order.
+    pub(crate) fn subfields<'a>(
+        &'a self,
+        tag: &'a str,
+        code: char,
+    ) -> impl Iterator<Item = &'a str> {
+        self.fields
+            .iter()
+            .filter_map(move |field| match field {
+                Field::Data {
+                    tag: t, subfields, ..
+                } if t == tag => Some(subfields),
+                _ => None,
+            })
+            .flatten()
+            .filter(move |(c, _)| *c == code)
+            .map(|(_, value)| value.as_str())
+    }
+
+    fn first<'a>(&'a self, tag: &'a str, code: char) -> Option<&'a str> {
+        self.subfields(tag, code)
+            .map(str::trim)
+            .find(|value| !value.is_empty())
+    }
+
+    pub(crate) fn from_item(item: &Item) -> Record {
+        let (record_type, level) = match item.format {
+            Format::Paperback | Format::Hardcover | Format::Zine => ('a', 'm'),
+            Format::Magazine => ('a', 's'),
+            Format::CD
+            | Format::Vinyl12Inch
+            | Format::Vinyl10Inch
+            | Format::Vinyl7Inch
+            | Format::Cassette => ('j', 'm'),
+        };
+        // Minimal-level, non-ISBD, Unicode.
+        let leader = format!("00000n{}{} a22000007  4500", record_type, level);
+
+        let mut fields = Vec::new();
+        if let Some(id) = item.id() {
+            fields.push(Field::control("001", id.to_string()));
+        }
+        if let Some(updated_at) = item.timestamps.updated_at {
+            fields.push(Field::control(
+                "005",
+                updated_at.format("%Y%m%d%H%M%S.0").to_string(),
+            ));
+        }
+        let (date_type, year) = match item.original_date {
+            Some(date) => ('s', format!("{:04}", date.year())),
+            None => ('n', "uuuu".to_owned()),
+        };
+        fields.push(Field::control(
+            "008",
+            format!(
+                "{}{}{}    xx {:17}{:3.3} d",
+                item.timestamps
+                    .created_at
+                    .unwrap_or_else(Utc::now)
+                    .format("%y%m%d"),
+                date_type,
+                year,
+                "",
+                item.language
+            ),
+        ));
+
+        let mut add = |tag: &str, indicators: [char; 2], code: char, value: &Option<String>| {
+            if let Some(value) = value {
+                fields.push(Field::data(tag, indicators, vec![(code, value.clone())]));
+            }
+        };
+        add("010", [' ', ' '], 'a', &item.lccn);
+        add("020", [' ', ' '], 'a', &item.isbn13);
+        add("022", [' ', ' '], 'a', &item.issn);
+        add(
+            "035",
+            [' ', ' '],
+            'a',
+            &item
+                .oclc_number
+                .as_ref()
+                .map(|oclc_number| format!("{}{}", OCLC_PREFIX, oclc_number)),
+        );
+        add("041", [' ', ' '], 'a', &Some(item.language.clone()));
+        fields.push(Field::data(
+            LOCAL_FIELD,
+            [' ', ' '],
+            vec![
+                ('a', item.call_number()),
+                ('f', serde_plain::to_string(&item.format).unwrap()),
+            ],
+        ));
+
+        let mut authors = item.authors.iter();
+        if let Some(author) = authors.next() {
+            fields.push(Field::data("100", ['1', ' '], vec![('a', author.clone())]));
+        }
+        fields.push(Field::data(
+            "245",
+            [if item.authors.is_empty() { '0' } else { '1' }, '0'],
+            vec![('a', item.title.clone())],
+        ));
+        for author in authors {
+            fields.push(Field::data("700", ['1', ' '], vec![('a', author.clone())]));
+        }
+
+        Record { leader, fields }
+    }
+
+    fn title(&self) -> String {
+        let mut title = String::new();
+        let mut separator = "";
+        for (code, value) in self
+            .fields
+            .iter()
+            .filter_map(|field| match field {
+                Field::Data { tag, subfields, .. } if tag == "245" => Some(subfields),
+                _ => None,
+            })
+            .flatten()
+        {
+            if !"abnp".contains(*code) {
+                continue;
+            }
+            let value = value.trim();
+            title.push_str(separator);
+            title.push_str(trim_punctuation(value));
+            separator = match value.chars().last() {
+                Some(':') => ": ",
+                Some(';') => "; ",
+                Some('=') => " = ",
+                Some('.') => ". ",
+                _ => " ",
+            };
+        }
+        title
+    }
+
+    /// Converts a record to a new item. Records exported from LESBIANS keep their classification
+    /// and format; `defaults` fills them in for records from anywhere else.
+    pub(crate) fn to_item(&self, defaults: ImportDefaults) -> Fallible<Item> {
+        let title = self.title();
+        ensure!(
+            !title.is_empty(),
+            "record {:?} has no title",
+            self.control("001").unwrap_or_default()
+        );
+        let classification = self
+            .first(LOCAL_FIELD, 'a')
+            .and_then(|call_number| call_number.split_whitespace().next())
+            .and_then(|classification| classification.parse().ok())
+            .or(defaults.classification)
+            .ok_or_else(|| failure::err_msg(format!("{:?} has no LESB call number", title)))?;
+        let format = self
+            .first(LOCAL_FIELD, 'f')
+            .and_then(|format| serde_plain::from_str(format).ok())
+            .or(defaults.format)
+            .ok_or_else(|| failure::err_msg(format!("{:?} has no format", title)))?;
+        let language = self
+            .first("041", 'a')
+            .or_else(|| self.control("008").and_then(|field| field.get(35..38)))
+            .map(str::trim)
+            .filter(|language| !language.is_empty())
+            .unwrap_or("und");
+
+        let mut item = Item::new(
+            classification,
+            title,
+            language.to_owned(),
+            format,
+            defaults.location,
+        );
+        item.authors = self
+            .fields
+            .iter()
+            .filter(|field| ["100", "110", "111", "700", "710", "711"].contains(&field.tag()))
+            .filter_map(|field| match field {
+                Field::Data { subfields, .. } => subfields
+                    .iter()
+                    .find(|(code, _)| *code == 'a')
+                    .map(|(_, name)| trim_punctuation(name.trim()).to_owned()),
+                Field::Control { .. } => None,
+            })
+            .collect();
+        item.original_date = self
+            .control("008")
+            .and_then(|field| field.get(7..11))
+            .and_then(|year| year.parse().ok())
+            .map(|year| PartialDate(year, None));
+        item.isbn13 = self
+            .subfields("020", 'a')
+            .find_map(|isbn| normalize_isbn(isbn.split_whitespace().next()?));
+        item.issn = self.first("022", 'a').map(str::to_owned);
+        item.lccn = self.first("010", 'a').map(str::to_owned);
+        // Other 035s are numbers from other systems, like "(DLC)84012345".
+        item.oclc_number = self.subfields("035", 'a').find_map(|number| {
+            let number = number.trim();
+            if !number.starts_with(OCLC_PREFIX) {
+                return None;
+            }
+            // Older numbers are written with a prefix, like "ocm10807312".
+            let number =
+                number[OCLC_PREFIX.len()..].trim_start_matches(|c: char| c.is_ascii_alphabetic());
+            if number.is_empty() {
+                None
+            } else {
+                Some(number.to_owned())
+            }
+        });
+        Ok(item)
+    }
+
+    pub(crate) fn write_iso2709<W: Write>(&self, writer: &mut W) -> Fallible<()> {
+        let mut directory = Vec::new();
+        let mut data = Vec::new();
+        for field in &self.fields {
+            let bytes = field.to_iso2709();
+            ensure!(
+                field.tag().len() == 3 && bytes.len() < 10_000,
+                "field {} can't be written as ISO 2709",
+                field.tag()
+            );
+            directory.extend_from_slice(
+                format!("{}{:04}{:05}", field.tag(), bytes.len(), data.len()).as_bytes(),
+            );
+            data.extend(bytes);
+        }
+        directory.push(FIELD_TERMINATOR);
+        let base_address = 24 + directory.len();
+        let length = base_address + data.len() + 1;
+        ensure!(length < 100_000, "record is too long for ISO 2709");
+        let leader = self.leader.as_bytes();
+        ensure!(
+            leader.len() == 24 && leader.is_ascii(),
+            "leader must be 24 ASCII characters"
+        );
+
+        writer.write_all(format!("{:05}", length).as_bytes())?;
+        writer.write_all(&leader[5..12])?;
+        writer.write_all(format!("{:05}", base_address).as_bytes())?;
+        writer.write_all(&leader[17..])?;
+        writer.write_all(&directory)?;
+        writer.write_all(&data)?;
+        writer.write_all(&[RECORD_TERMINATOR])?;
+        Ok(())
+    }
+
+    fn from_iso2709(bytes: &[u8]) -> Fallible<Record> {
+        ensure!(bytes.len() >= 24, "record is shorter than its leader");
+        let leader = str::from_utf8(&bytes[..24])?.to_owned();
+        let base_address: usize = str::from_utf8(&bytes[12..17])?.parse()?;
+        ensure!(
+            base_address > 24 && base_address <= bytes.len(),
+            "base address {} is out of range",
+            base_address
+        );
+
+        let mut fields = Vec::new();
+        for entry in bytes[24..base_address - 1].chunks(12) {
+            ensure!(entry.len() == 12, "directory entry is truncated");
+            let tag = str::from_utf8(&entry[..3])?;
+            let length: usize = str::from_utf8(&entry[3..7])?.parse()?;
+            let start: usize = str::from_utf8(&entry[7..12])?.parse()?;
+            let field = bytes
+                .get(base_address + start..base_address + start + length)
+                .ok_or_else(|| failure::err_msg(format!("field {} is out of range", tag)))?;
+            fields.push(Field::from_iso2709(tag, field));
+        }
+        Ok(Record { leader, fields })
+    }
+
+    /// Returns the record as a MARCXML `record` element.
+    pub(crate) fn to_marcxml(&self) -> String {
+        let mut xml = format!(
+            "<record xmlns=\"{}\"><leader>{}</leader>",
+            MARCXML_NAMESPACE,
+            escape_str_pcdata(&self.leader)
+        );
+        for field in &self.fields {
+            xml.push_str(&field.to_marcxml());
+        }
+        xml.push_str("</record>");
+        xml
+    }
+}
+
+/// Reads every record in an ISO 2709 file.
+///
+/// Only Unicode records are read correctly; MARC-8 records are read as if they were UTF-8, which
+/// is only right for plain ASCII.
+pub(crate) fn read_iso2709<R: Read>(reader: R) -> Fallible<Vec<Record>> {
+    let mut reader = reader;
+    let mut bytes = Vec::new();
+    reader.read_to_end(&mut bytes)?;
+    bytes
+        .split(|b| *b == RECORD_TERMINATOR)
+        .filter(|record| !record.iter().all(u8::is_ascii_whitespace))
+        .map(|record| {
+            let start = record
+                .iter()
+                .position(|b| !b.is_ascii_whitespace())
+                .unwrap_or_default();
+            Record::from_iso2709(&record[start..])
+        })
+        .collect()
+}
+
+pub(crate) fn write_marcxml<W: Write>(writer: W, records: &[Record]) -> Fallible<()> {
+    let mut writer = writer;
+    writeln!(writer, "<?xml version=\"1.0\" encoding=\"UTF-8\"?>")?;
+    writeln!(writer, "<collection xmlns=\"{}\">", MARCXML_NAMESPACE)?;
+    for record in records {
+        writeln!(writer, "{}", record.to_marcxml())?;
+    }
+    writeln!(writer, "</collection>")?;
+    Ok(())
+}
+
+/// Reads every `record` element in a MARCXML document.
+pub(crate) fn read_marcxml<R: Read>(reader: R) -> Fallible<Vec<Record>> {
+    let mut records = Vec::new();
+    let mut record = None;
+    let mut text = String::new();
+    let mut tag = String::new();
+    let mut code = ' ';
+    for event in EventReader::new(reader) {
+        match event? {
+            XmlEvent::StartElement {
+                name, attributes, ..
+            } => {
+                text.clear();
+                let attribute = |name: &str| {
+                    attributes
+                        .iter()
+                        .find(|attribute| attribute.name.local_name == name)
+                        .map(|attribute| attribute.value.as_str())
+                        .unwrap_or_default()
+                };
+                let indicator = |name: &str| attribute(name).chars().next().unwrap_or(' ');
+                match name.local_name.as_str() {
+                    "record" => {
+                        record = Some(Record {
+                            leader: String::new(),
+                            fields: Vec::new(),
+                        });
+                    }
+                    "controlfield" => tag = attribute("tag").to_owned(),
+                    "datafield" => {
+                        if let Some(record) = &mut record {
+                            record.fields.push(Field::data(
+                                attribute("tag"),
+                                [indicator("ind1"), indicator("ind2")],
+                                Vec::new(),
+                            ));
+                        }
+                    }
+                    "subfield" => code = attribute("code").chars().next().unwrap_or(' '),
+                    _ => {}
+                }
+            }
+            XmlEvent::Characters(s) | XmlEvent::CData(s) | XmlEvent::Whitespace(s) => {
+                text.push_str(&s);
+            }
+            XmlEvent::EndElement { name } => {
+                if name.local_name == "record" {
+                    records.extend(record.take());
+                } else if let Some(record) = &mut record {
+                    match name.local_name.as_str() {
+                        "leader" => record.leader = text.clone(),
+                        "controlfield" => record.fields.push(Field::control(&tag, text.clone())),
+                        "subfield" => {
+                            if let Some(Field::Data { subfields, .. }) = record.fields.last_mut() {
+                                subfields.push((code, text.clone()));
+                            }
+                        }
+                        _ => {}
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+    Ok(records)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::date::PartialDate;
+    use crate::format::Format;
+    use crate::item::Item;
+    use crate::lesb::LESBClassification;
+    use crate::location::Location;
+    use crate::marc::{read_iso2709, read_marcxml, write_marcxml, ImportDefaults, Record};
+    use failure::Fallible;
+
+    const DEFAULTS: ImportDefaults = ImportDefaults {
+        classification: None,
+        format: None,
+        location: Location::Billy,
+    };
+
+    #[test]
+    fn test_iso2709() -> Fallible<()> {
+        let mut item = Item::test_item();
+        item.original_date = Some(PartialDate(1902, None));
+        item.authors.push("Noyes, Émilie".to_owned());
+        item.lccn = Some("2019900001".to_owned());
+        let record = Record::from_item(&item);
+        assert_eq!(record.control("008").unwrap().len(), 40);
+
+        let mut bytes = Vec::new();
+        record.write_iso2709(&mut bytes)?;
+        record.write_iso2709(&mut bytes)?;
+        assert_eq!(&bytes[..5], format!("{:05}", bytes.len() / 2).as_bytes());
+        let records = read_iso2709(bytes.as_slice())?;
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[0].fields, record.fields);
+        assert_eq!(records[0].to_item(DEFAULTS)?, item);
+
+        // Malformed records are errors, not panics.
+        bytes[24 + 5] = 0xff;
+        assert!(read_iso2709(bytes.as_slice()).is_err());
+        let mut record = record;
+        record.leader = format!("0000é{:18}", "");
+        assert!(record.write_iso2709(&mut Vec::new()).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_marcxml() -> Fallible<()> {
+        let mut item = Item::test_item();
+        item.original_date = Some(PartialDate(1902, None));
+        item.title = "Zines & <records>".to_owned();
+        let mut xml = Vec::new();
+        write_marcxml(&mut xml, &[Record::from_item(&item)])?;
+        let records = read_marcxml(xml.as_slice())?;
+        assert_eq!(records, vec![Record::from_item(&item)]);
+        assert_eq!(records[0].to_item(DEFAULTS)?, item);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_import() -> Fallible<()> {
+        let xml = r#"<?xml version="1.0" encoding="UTF-8"?>
+<collection xmlns="http://www.loc.gov/MARC21/slim">
+  <record>
+    <leader>01142cam  2200301 a 4500</leader>
+    <controlfield tag="001">   84012345 </controlfield>
+    <controlfield tag="008">840808s1984    nyu           000 0 eng  </controlfield>
+    <datafield tag="020" ind1=" " ind2=" ">
+      <subfield code="a">0895941414 (pbk.)</subfield>
+    </datafield>
+    <datafield tag="035" ind1=" " ind2=" ">
+      <subfield code="a">(OCoLC)ocm10807312</subfield>
+    </datafield>
+    <datafield tag="100" ind1="1" ind2=" ">
+      <subfield code="a">Lorde, Audre.</subfield>
+    </datafield>
+    <datafield tag="245" ind1="1" ind2="0">
+      <subfield code="a">Sister outsider :</subfield>
+      <subfield code="b">essays and speeches /</subfield>
+      <subfield code="c">by Audre Lorde.</subfield>
+    </datafield>
+  </record>
+</collection>"#;
+        let records = read_marcxml(xml.as_bytes())?;
+        assert_eq!(records.len(), 1);
+        assert!(records[0].to_item(DEFAULTS).is_err());
+
+        let item = records[0].to_item(ImportDefaults {
+            classification: Some(LESBClassification::NI),
+            format: Some(Format::Paperback),
+            location: Location::Billy,
+        })?;
+        assert_eq!(item.title, "Sister outsider: essays and speeches");
+        assert_eq!(item.authors, vec!["Lorde, Audre"]);
+        assert_eq!(item.language, "eng");
+        assert_eq!(item.original_date.map(PartialDate::year), Some(1984));
+        assert_eq!(item.isbn13.as_ref().unwrap(), "9780895941411");
+        assert_eq!(item.oclc_number.as_ref().unwrap(), "10807312");
+
+        Ok(())
+    }
+}
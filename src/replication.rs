@@ -0,0 +1,483 @@
+// SPDX-License-Identifier: AGPL-3.0-only
+
+use crate::db::{Db, DumpRow, Row, Since, Timestamps};
+use crate::item::Item;
+use crate::token::Token;
+use crate::user::{self, User};
+use failure::{bail, ensure, Fallible};
+use log::warn;
+use serde::{Deserialize, Serialize};
+
+/// A primary server to sync this database with, from the `[remote]` section of `config.toml`.
+///
+/// The primary is authoritative for circulation: loans and holds only flow from it, and pushed
+/// items keep the primary's loan state. Conflicting edits to items and users are settled by
+/// keeping whichever was changed last.
+#[derive(Debug, Deserialize)]
+pub(crate) struct Remote {
+    /// The primary's base URL, like `https://library.example`.
+    pub(crate) url: String,
+    /// An API token for the primary with the admin scope.
+    pub(crate) token: String,
+}
+
+impl Remote {
+    fn changes_url(&self) -> String {
+        format!("{}/api/changes", self.url.trim_end_matches('/'))
+    }
+}
+
+/// How far this database has synced with a remote, by audit log sequence number.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub(crate) struct ReplicationState {
+    /// The primary's sequence number as of the last pull.
+    #[serde(default)]
+    pulled: Option<u64>,
+    /// This database's sequence number as of the last push.
+    #[serde(default)]
+    pushed: Option<u64>,
+}
+
+/// The body of `GET /api/changes` and `POST /api/changes`.
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) struct Changes {
+    /// The sender's audit log sequence number as of these changes.
+    pub(crate) sequence: Option<u64>,
+    pub(crate) rows: Vec<DumpRow>,
+}
+
+/// How many rows a merge applied, how many it skipped for being older than the local copy, and
+/// how many it rejected for having the ID or barcode of a different local row.
+#[derive(Debug, Default, Serialize, Deserialize, PartialEq, Eq)]
+pub(crate) struct Merged {
+    pub(crate) applied: usize,
+    pub(crate) skipped: usize,
+    #[serde(default)]
+    pub(crate) rejected: usize,
+}
+
+/// What merging one row did.
+#[derive(Clone, Copy)]
+enum Outcome {
+    Applied,
+    Skipped,
+    Rejected,
+}
+
+impl Merged {
+    fn count(&mut self, outcome: Outcome) {
+        match outcome {
+            Outcome::Applied => self.applied += 1,
+            Outcome::Skipped => self.skipped += 1,
+            Outcome::Rejected => self.rejected += 1,
+        }
+    }
+}
+
+/// Tokens and audit entries stay on the server they were made on.
+fn replicated(row: &DumpRow) -> bool {
+    match row {
+        DumpRow::Item(_) | DumpRow::User(_) | DumpRow::Loan(_) | DumpRow::Hold(_) => true,
+        DumpRow::Deleted { tree, .. } => tree != Token::TREE,
+        DumpRow::Token(_) | DumpRow::Audit(_) => false,
+    }
+}
+
+/// Only catalog and user changes can be pushed to the primary.
+fn pushable(row: &DumpRow) -> bool {
+    match row {
+        DumpRow::Item(_) | DumpRow::User(_) => true,
+        DumpRow::Deleted { tree, .. } => tree == Item::TREE || tree == User::TREE,
+        _ => false,
+    }
+}
+
+/// Returns the rows changed since a sequence number, or every row if there isn't one.
+pub(crate) fn changes(db: &Db, since: Option<u64>) -> Fallible<Changes> {
+    let sequence = db.last_sequence()?;
+    let rows = match since {
+        Some(since) => db.changes_since(Since::Sequence(since))?,
+        None => db.iter_all()?.collect::<Fallible<Vec<_>>>()?,
+    };
+    Ok(Changes {
+        sequence,
+        rows: rows.into_iter().filter(replicated).collect(),
+    })
+}
+
+/// Whether a local row was changed after an incoming copy of it.
+fn newer(local: &Timestamps, incoming: &Timestamps) -> bool {
+    local.last_changed() > incoming.last_changed()
+}
+
+/// Whether a local row and an incoming one share an ID but were created separately. Rows keep
+/// their creation time when they're copied, so this only happens if two servers picked the same
+/// ID, and the incoming row would overwrite an unrelated one.
+fn collides(local: &Timestamps, incoming: &Timestamps) -> bool {
+    match (local.created_at, incoming.created_at) {
+        (Some(local), Some(incoming)) => local != incoming,
+        _ => false,
+    }
+}
+
+/// Whether an incoming item replaces the local copy with the same ID, if there is one.
+fn replaces(local: Option<&Item>, item: &Item) -> bool {
+    local.map_or(true, |local| {
+        !collides(&local.timestamps, &item.timestamps)
+            && !newer(&local.timestamps, &item.timestamps)
+    })
+}
+
+/// Applies rows from another server. With `primary` set, this database is the primary receiving
+/// a push, which only accepts items and users and keeps its own loan state.
+pub(crate) fn merge(db: &mut Db, rows: Vec<DumpRow>, primary: bool) -> Fallible<Merged> {
+    if primary {
+        ensure!(
+            rows.iter().all(pushable),
+            "only items and users can be pushed"
+        );
+    }
+    let mut merged = Merged::default();
+    // A barcode can pass between items in either order, or be swapped between them, so delete
+    // rows first and then free the barcodes the incoming items give up.
+    let (deleted, mut rows): (Vec<_>, Vec<_>) = rows.into_iter().partition(|row| match row {
+        DumpRow::Deleted { .. } => true,
+        _ => false,
+    });
+    for row in deleted {
+        merged.count(merge_row(db, row, primary)?);
+    }
+    for row in &mut rows {
+        if let DumpRow::Item(item) = row {
+            if let Some(id) = item.id() {
+                if replaces(db.load::<Item>(id)?.as_ref(), item) {
+                    db.release_unique_keys(&mut **item)?;
+                }
+            }
+        }
+    }
+    for row in rows {
+        merged.count(merge_row(db, row, primary)?);
+    }
+    Ok(merged)
+}
+
+/// Applies one row from another server.
+fn merge_row(db: &mut Db, row: DumpRow, primary: bool) -> Fallible<Outcome> {
+    Ok(match row {
+        DumpRow::Item(mut item) => {
+            let id = match item.id() {
+                Some(id) => id,
+                None => bail!("item {:?} has no ID", item.title),
+            };
+            let local = db.load::<Item>(id)?;
+            if primary {
+                item.loan = local.as_ref().and_then(|local| local.loan.clone());
+            }
+            match local {
+                Some(ref local) if collides(&local.timestamps, &item.timestamps) => {
+                    warn!(
+                        "not merging {:?}, which has the same ID as {:?}",
+                        item.title, local.title
+                    );
+                    Outcome::Rejected
+                }
+                Some(mut local) if newer(&local.timestamps, &item.timestamps) => {
+                    if local.loan != item.loan {
+                        local.loan = item.loan;
+                        db.save(&mut local)?;
+                    }
+                    Outcome::Skipped
+                }
+                _ => {
+                    // Likely both servers gave out the same barcode.
+                    if let Some(conflict) = db.unique_conflict(&mut *item)? {
+                        warn!("not merging {:?}: {}", item.title, conflict);
+                        db.claim_unique_keys::<Item>(id)?;
+                        Outcome::Rejected
+                    } else {
+                        db.save(&mut *item)?;
+                        Outcome::Applied
+                    }
+                }
+            }
+        }
+        DumpRow::User(mut user) => match db.load::<User>(user.barcode)? {
+            Some(ref local) if collides(&local.timestamps, &user.timestamps) => {
+                warn!(
+                    "not merging {:?}, who has the same barcode as {:?}",
+                    user.name, local.name
+                );
+                Outcome::Rejected
+            }
+            Some(ref local) if newer(&local.timestamps, &user.timestamps) => Outcome::Skipped,
+            _ => {
+                db.save(&mut *user)?;
+                Outcome::Applied
+            }
+        },
+        DumpRow::Loan(mut loan) => {
+            db.save(&mut *loan)?;
+            Outcome::Applied
+        }
+        DumpRow::Hold(mut hold) => {
+            db.save(&mut *hold)?;
+            Outcome::Applied
+        }
+        DumpRow::Deleted { ref tree, id } if primary && tree == Item::TREE => {
+            match db.load::<Item>(id)? {
+                Some(ref item) if item.loan.is_some() => {
+                    warn!("not deleting {:?}, which is checked out", item.title);
+                    Outcome::Skipped
+                }
+                _ => {
+                    db.delete::<Item>(id)?;
+                    Outcome::Applied
+                }
+            }
+        }
+        DumpRow::Deleted { ref tree, id } if primary && tree == User::TREE => {
+            match db.load::<User>(id)? {
+                Some(user) => match user::delete_user(db, &user, false) {
+                    Ok(()) => Outcome::Applied,
+                    Err(err) => {
+                        warn!("not deleting user {}: {}", id, err);
+                        Outcome::Skipped
+                    }
+                },
+                None => Outcome::Skipped,
+            }
+        }
+        DumpRow::Deleted { tree, id } => {
+            db.delete_dump_row(&tree, id)?;
+            Outcome::Applied
+        }
+        DumpRow::Token(_) | DumpRow::Audit(_) => {
+            bail!("tokens and audit entries aren't synced")
+        }
+    })
+}
+
+fn check(response: reqwest::Response) -> Fallible<reqwest::Response> {
+    let mut response = response;
+    if !response.status().is_success() {
+        bail!(
+            "{} returned {}: {}",
+            response.url().clone(),
+            response.status(),
+            response.text()?
+        );
+    }
+    Ok(response)
+}
+
+/// Applies the primary's changes since the last pull.
+pub(crate) fn pull(db: &mut Db, remote: &Remote) -> Fallible<Merged> {
+    let mut state = db.replication_state(&remote.url)?;
+    let mut request = reqwest::Client::new()
+        .get(&remote.changes_url())
+        .bearer_auth(&remote.token);
+    if let Some(pulled) = state.pulled {
+        request = request.query(&[("since", pulled)]);
+    }
+    let Changes { sequence, rows } = check(request.send()?)?.json()?;
+    // Pulled rows keep the primary's timestamps, and aren't audited here so they aren't pushed
+    // back.
+    let merged = db.restoring(|db| merge(db, rows, false))?;
+    if sequence.is_some() {
+        state.pulled = sequence;
+    }
+    db.set_replication_state(&remote.url, &state)?;
+    Ok(merged)
+}
+
+/// Sends the items and users changed here since the last push to the primary. The first push has
+/// nowhere to start from, so it sends every item and user, including ones pulled from the primary,
+/// which the primary skips since they're no newer than its own copies.
+pub(crate) fn push(db: &mut Db, remote: &Remote) -> Fallible<Merged> {
+    let mut state = db.replication_state(&remote.url)?;
+    let mut changes = changes(db, state.pushed)?;
+    changes.rows.retain(pushable);
+    let merged = check(
+        reqwest::Client::new()
+            .post(&remote.changes_url())
+            .bearer_auth(&remote.token)
+            .json(&changes)
+            .send()?,
+    )?
+    .json()?;
+    if changes.sequence.is_some() {
+        state.pushed = changes.sequence;
+    }
+    db.set_replication_state(&remote.url, &state)?;
+    Ok(merged)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::circulation::{self, LoanPolicy};
+    use crate::db::{Db, Timestamps};
+    use crate::item::Item;
+    use crate::replication::{changes, merge, pushable, Merged};
+    use crate::user::User;
+    use failure::Fallible;
+
+    fn pull(replica: &mut Db, primary: &Db, since: Option<u64>) -> Fallible<Merged> {
+        let changes = changes(primary, since)?;
+        replica.restoring(|db| merge(db, changes.rows, false))
+    }
+
+    fn push(primary: &mut Db, replica: &Db, since: Option<u64>) -> Fallible<Merged> {
+        let mut changes = changes(replica, since)?;
+        changes.rows.retain(pushable);
+        merge(primary, changes.rows, true)
+    }
+
+    #[test]
+    fn test() -> Fallible<()> {
+        let mut primary = Db::open_memory()?;
+        let mut replica = Db::open_memory()?;
+        let mut user = User::test_user();
+        primary.save(&mut user)?;
+        let mut item = Item::test_item();
+        primary.save(&mut item)?;
+        circulation::check_out(&mut primary, &LoanPolicy::default(), &mut item, &user)?;
+
+        pull(&mut replica, &primary, None)?;
+        let pulled = primary.last_sequence()?;
+        let mut copy = replica.load::<Item>(item.id().unwrap())?.unwrap();
+        assert_eq!(copy, item);
+        assert!(replica.audit_log(|_| true, 10)?.is_empty());
+
+        // The replica's edit wins over the older primary copy, but not its loan state.
+        copy.title = "Color problems".to_owned();
+        copy.loan = None;
+        replica.save(&mut copy)?;
+        let mut renamed = replica.load::<User>(user.barcode)?.unwrap();
+        renamed.name = "replica".to_owned();
+        replica.save(&mut renamed)?;
+        user.name = "primary".to_owned();
+        primary.save(&mut user)?;
+        assert_eq!(
+            push(&mut primary, &replica, None)?,
+            Merged {
+                applied: 1,
+                skipped: 1,
+                rejected: 0
+            }
+        );
+        let merged = primary.load::<Item>(item.id().unwrap())?.unwrap();
+        assert_eq!(merged.title, "Color problems");
+        assert_eq!(merged.loan, item.loan);
+        assert_eq!(primary.load::<User>(user.barcode)?.unwrap().name, "primary");
+
+        let mut item = merged;
+        circulation::check_in(&mut primary, &mut item)?;
+        pull(&mut replica, &primary, pulled)?;
+        let copy = replica.load::<Item>(item.id().unwrap())?.unwrap();
+        assert_eq!(copy.title, "Color problems");
+        assert_eq!(copy.loan, None);
+        assert_eq!(replica.load::<User>(user.barcode)?.unwrap().name, "primary");
+        assert_eq!(replica.loan_history(item.id().unwrap())?.len(), 1);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_collision() -> Fallible<()> {
+        let mut primary = Db::open_memory()?;
+        let mut replica = Db::open_memory()?;
+        let mut item = Item::test_item();
+        primary.save(&mut item)?;
+        let mut other = primary.load::<Item>(item.id().unwrap())?.unwrap();
+        other.title = "Something else".to_owned();
+        other.timestamps = Timestamps::default();
+        replica.save(&mut other)?;
+        let mut user = User::test_user();
+        primary.save(&mut user)?;
+        let mut stranger = User::test_user();
+        stranger.name = "stranger".to_owned();
+        replica.save(&mut stranger)?;
+
+        assert_eq!(
+            pull(&mut replica, &primary, None)?,
+            Merged {
+                applied: 0,
+                skipped: 0,
+                rejected: 2
+            }
+        );
+        assert_eq!(replica.load::<Item>(item.id().unwrap())?.unwrap(), other);
+        assert_eq!(replica.load::<User>(user.barcode)?.unwrap(), stranger);
+
+        // Each database numbers new rows differently, so this doesn't happen to new items.
+        let mut new = Item::test_item();
+        replica.save(&mut new)?;
+        assert_ne!(new.id(), item.id());
+        assert!(primary.load::<Item>(new.id().unwrap())?.is_none());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_moved_barcodes() -> Fallible<()> {
+        let mut primary = Db::open_memory()?;
+        let mut replica = Db::open_memory()?;
+        let mut item = Item::test_item();
+        item.barcode = Some("1".to_owned());
+        primary.save(&mut item)?;
+        let mut other = Item::test_item();
+        other.barcode = Some("2".to_owned());
+        primary.save(&mut other)?;
+        pull(&mut replica, &primary, None)?;
+        let pulled = primary.last_sequence()?;
+
+        // The items swap barcodes, and the first item is edited again afterwards.
+        item.barcode = Some("3".to_owned());
+        primary.save(&mut item)?;
+        other.barcode = Some("1".to_owned());
+        primary.save(&mut other)?;
+        item.barcode = Some("2".to_owned());
+        primary.save(&mut item)?;
+        item.title = "Edited".to_owned();
+        primary.save(&mut item)?;
+
+        assert_eq!(pull(&mut replica, &primary, pulled)?.applied, 2);
+        assert_eq!(replica.load_by_barcode("1")?, Some(other));
+        assert_eq!(replica.load_by_barcode("2")?, Some(item));
+        assert_eq!(replica.load_by_barcode("3")?, None);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_barcode_conflict() -> Fallible<()> {
+        let mut primary = Db::open_memory()?;
+        let mut replica = Db::open_memory()?;
+
+        // Both servers catalog a different item under the same barcode.
+        let mut local = Item::test_item();
+        local.barcode = Some("1".to_owned());
+        replica.save(&mut local)?;
+        let mut remote = Item::test_item();
+        remote.barcode = Some("1".to_owned());
+        remote.title = "Remote".to_owned();
+        primary.save(&mut remote)?;
+        let mut other = Item::test_item();
+        other.barcode = Some("2".to_owned());
+        primary.save(&mut other)?;
+
+        assert_eq!(
+            pull(&mut replica, &primary, None)?,
+            Merged {
+                applied: 1,
+                skipped: 0,
+                rejected: 1
+            }
+        );
+        assert_eq!(replica.load_by_barcode("1")?, Some(local));
+        assert_eq!(replica.load_by_barcode("2")?, Some(other));
+
+        Ok(())
+    }
+}
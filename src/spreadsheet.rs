@@ -0,0 +1,421 @@
+// SPDX-License-Identifier: AGPL-3.0-only
+
+use crate::date::PartialDate;
+use crate::isbn::normalize_isbn;
+use crate::item::Item;
+use crate::marc::ImportDefaults;
+use failure::{bail, ensure, Fallible};
+use log::error;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::io::prelude::*;
+use std::path::Path;
+
+/// The item fields a CSV file can have, in the order `export` writes them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum Column {
+    Id,
+    CallNumber,
+    Classification,
+    Authors,
+    OriginalDate,
+    Title,
+    Language,
+    Format,
+    Volume,
+    Issue,
+    Location,
+    Barcode,
+    Notes,
+    DiscogsRelease,
+    Isbn13,
+    Issn,
+    Lccn,
+    MusicbrainzReleaseGroup,
+    OclcNumber,
+    OpenlibraryId,
+}
+
+const COLUMNS: &[Column] = &[
+    Column::Id,
+    Column::CallNumber,
+    Column::Classification,
+    Column::Authors,
+    Column::OriginalDate,
+    Column::Title,
+    Column::Language,
+    Column::Format,
+    Column::Volume,
+    Column::Issue,
+    Column::Location,
+    Column::Barcode,
+    Column::Notes,
+    Column::DiscogsRelease,
+    Column::Isbn13,
+    Column::Issn,
+    Column::Lccn,
+    Column::MusicbrainzReleaseGroup,
+    Column::OclcNumber,
+    Column::OpenlibraryId,
+];
+
+impl Column {
+    /// IDs and call numbers are assigned here, so importing them would be misleading.
+    fn is_importable(self) -> bool {
+        self != Column::Id && self != Column::CallNumber
+    }
+}
+
+fn default_separator() -> String {
+    ";".to_owned()
+}
+
+/// Which CSV column holds each item field, read from a TOML file like:
+///
+/// ```toml
+/// authors_separator = "&"
+///
+/// [columns]
+/// title = "Title"
+/// authors = "Author(s)"
+/// original_date = "Year"
+/// ```
+#[derive(Debug)]
+pub(crate) struct Mapping {
+    columns: HashMap<Column, String>,
+    /// What separates several authors in one cell.
+    authors_separator: String,
+}
+
+#[derive(Deserialize)]
+struct MappingFile {
+    columns: HashMap<String, String>,
+    #[serde(default = "default_separator")]
+    authors_separator: String,
+}
+
+impl Mapping {
+    pub(crate) fn load<P: AsRef<Path>>(path: P) -> Fallible<Mapping> {
+        Mapping::from_toml(&fs::read_to_string(path)?)
+    }
+
+    fn from_toml(s: &str) -> Fallible<Mapping> {
+        let file: MappingFile = toml::from_str(s)?;
+        let mut columns = HashMap::new();
+        for (field, header) in file.columns {
+            let column: Column = serde_plain::from_str(&field)
+                .map_err(|_| failure::err_msg(format!("there's no item field {:?}", field)))?;
+            ensure!(column.is_importable(), "{} can't be imported", field);
+            columns.insert(column, header);
+        }
+        Ok(Mapping {
+            columns,
+            authors_separator: file.authors_separator,
+        })
+    }
+
+    /// The mapping for files written by `export`: every header named after an item field.
+    fn from_headers(headers: &csv::StringRecord) -> Mapping {
+        Mapping {
+            columns: headers
+                .iter()
+                .filter_map(|header| {
+                    serde_plain::from_str::<Column>(header)
+                        .ok()
+                        .filter(|column| column.is_importable())
+                        .map(|column| (column, header.to_owned()))
+                })
+                .collect(),
+            authors_separator: default_separator(),
+        }
+    }
+}
+
+/// A row of an import that isn't a valid item.
+#[derive(Debug)]
+pub(crate) struct RowError {
+    /// Where the row is, like "line 3" or "book 12345".
+    pub(crate) row: String,
+    pub(crate) error: failure::Error,
+}
+
+impl fmt::Display for RowError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}: {}", self.row, self.error)
+    }
+}
+
+/// Returns the imported items, after logging every row that isn't valid. Any invalid row fails
+/// the whole import, so fixing the file and trying again doesn't duplicate items.
+pub(crate) fn valid_items(rows: Vec<Result<Item, RowError>>) -> Fallible<Vec<Item>> {
+    let mut items = Vec::new();
+    let mut errors = 0;
+    for row in rows {
+        match row {
+            Ok(item) => items.push(item),
+            Err(err) => {
+                error!("{}", err);
+                errors += 1;
+            }
+        }
+    }
+    ensure!(
+        errors == 0,
+        "{} rows have errors, so nothing was imported",
+        errors
+    );
+    Ok(items)
+}
+
+/// Writes one row per item, with a header row naming each column.
+pub(crate) fn write_items<'a, W, I>(writer: W, items: I) -> Fallible<()>
+where
+    W: Write,
+    I: IntoIterator<Item = &'a Item>,
+{
+    let mut writer = csv::Writer::from_writer(writer);
+    writer.write_record(
+        COLUMNS
+            .iter()
+            .map(serde_plain::to_string)
+            .collect::<Result<Vec<_>, _>>()?,
+    )?;
+    for item in items {
+        let (volume, issue) = match item.volume_and_issue {
+            Some((volume, issue)) => (volume.to_string(), issue.to_string()),
+            None => (String::new(), String::new()),
+        };
+        let optional = |value: &Option<String>| value.clone().unwrap_or_default();
+        writer.write_record(&[
+            item.id().map(|id| id.to_string()).unwrap_or_default(),
+            item.call_number(),
+            item.classification.to_string(),
+            item.authors.join(&format!("{} ", default_separator())),
+            item.original_date
+                .map(|date| date.to_string())
+                .unwrap_or_default(),
+            item.title.clone(),
+            item.language.clone(),
+            serde_plain::to_string(&item.format)?,
+            volume,
+            issue,
+            serde_plain::to_string(&item.location)?,
+            optional(&item.barcode),
+            optional(&item.notes),
+            optional(&item.discogs_release),
+            optional(&item.isbn13),
+            optional(&item.issn),
+            optional(&item.lccn),
+            optional(&item.musicbrainz_release_group),
+            optional(&item.oclc_number),
+            optional(&item.openlibrary_id),
+        ])?;
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+/// Reads items from a CSV file with a header row, using `export`'s column names if there's no
+/// mapping. Rows that aren't valid items are returned as errors, so they can all be reported at
+/// once.
+pub(crate) fn read_items<R: Read>(
+    reader: R,
+    mapping: Option<&Mapping>,
+    defaults: ImportDefaults,
+) -> Fallible<Vec<Result<Item, RowError>>> {
+    let mut reader = csv::Reader::from_reader(reader);
+    let headers = reader.headers()?.clone();
+    let default_mapping;
+    let mapping = if let Some(mapping) = mapping {
+        mapping
+    } else {
+        default_mapping = Mapping::from_headers(&headers);
+        &default_mapping
+    };
+    let mut indexes = HashMap::new();
+    for (column, header) in &mapping.columns {
+        match headers.iter().position(|h| h.trim() == header) {
+            Some(index) => indexes.insert(*column, index),
+            None => bail!("there's no {:?} column", header),
+        };
+    }
+    ensure!(
+        indexes.contains_key(&Column::Title),
+        "there's no title column"
+    );
+
+    let mut rows = Vec::new();
+    for record in reader.records() {
+        let record = record?;
+        let row = format!("line {}", record.position().map_or(0, csv::Position::line));
+        let cell = |column| {
+            indexes
+                .get(&column)
+                .and_then(|&index| record.get(index))
+                .map(str::trim)
+                .filter(|value| !value.is_empty())
+        };
+        rows.push(
+            row_to_item(cell, &mapping.authors_separator, defaults)
+                .map_err(|error| RowError { row, error }),
+        );
+    }
+    Ok(rows)
+}
+
+fn row_to_item<'a, F>(cell: F, authors_separator: &str, defaults: ImportDefaults) -> Fallible<Item>
+where
+    F: Fn(Column) -> Option<&'a str>,
+{
+    let title = cell(Column::Title).ok_or_else(|| failure::err_msg("no title"))?;
+    // Full call numbers, like "LF WATER 1998 eng", are fine too.
+    let classification = match cell(Column::Classification) {
+        Some(call_number) => call_number
+            .split_whitespace()
+            .next()
+            .unwrap_or_default()
+            .parse()?,
+        None => defaults
+            .classification
+            .ok_or_else(|| failure::err_msg("no classification"))?,
+    };
+    let format = match cell(Column::Format) {
+        Some(format) => serde_plain::from_str(&format.to_lowercase())?,
+        None => defaults
+            .format
+            .ok_or_else(|| failure::err_msg("no format"))?,
+    };
+    let location = match cell(Column::Location) {
+        Some(location) => serde_plain::from_str(&location.to_lowercase())?,
+        None => defaults.location,
+    };
+    let mut item = Item::new(
+        classification,
+        title.to_owned(),
+        cell(Column::Language).unwrap_or("und").to_owned(),
+        format,
+        location,
+    );
+
+    if let Some(authors) = cell(Column::Authors) {
+        item.authors = authors
+            .split(authors_separator)
+            .map(str::trim)
+            .filter(|author| !author.is_empty())
+            .map(str::to_owned)
+            .collect();
+    }
+    if let Some(date) = cell(Column::OriginalDate) {
+        item.original_date = Some(
+            date.parse::<PartialDate>()
+                .map_err(|err| failure::err_msg(format!("bad date {:?}: {}", date, err)))?,
+        );
+    }
+    item.volume_and_issue = match (cell(Column::Volume), cell(Column::Issue)) {
+        (Some(volume), Some(issue)) => Some((volume.parse()?, issue.parse()?)),
+        (None, None) => None,
+        _ => bail!("volume and issue have to be given together"),
+    };
+    if let Some(isbn) = cell(Column::Isbn13) {
+        item.isbn13 = Some(
+            normalize_isbn(isbn).ok_or_else(|| failure::err_msg(format!("bad ISBN {:?}", isbn)))?,
+        );
+    }
+    let optional = |column| cell(column).map(str::to_owned);
+    item.barcode = optional(Column::Barcode);
+    item.notes = optional(Column::Notes);
+    item.discogs_release = optional(Column::DiscogsRelease);
+    item.issn = optional(Column::Issn);
+    item.lccn = optional(Column::Lccn);
+    item.musicbrainz_release_group = optional(Column::MusicbrainzReleaseGroup);
+    item.oclc_number = optional(Column::OclcNumber);
+    item.openlibrary_id = optional(Column::OpenlibraryId);
+    Ok(item)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::date::PartialDate;
+    use crate::format::Format;
+    use crate::item::Item;
+    use crate::lesb::LESBClassification;
+    use crate::location::Location;
+    use crate::marc::ImportDefaults;
+    use crate::spreadsheet::{read_items, write_items, Mapping};
+    use failure::Fallible;
+
+    const DEFAULTS: ImportDefaults = ImportDefaults {
+        classification: None,
+        format: None,
+        location: Location::Billy,
+    };
+
+    #[test]
+    fn test_export() -> Fallible<()> {
+        let mut item = Item::test_item();
+        item.authors.push("Noyes, Émilie".to_owned());
+        item.notes = Some("signed, \"first\" edition".to_owned());
+        let mut csv = Vec::new();
+        write_items(&mut csv, vec![&item])?;
+        let csv = String::from_utf8(csv)?;
+        assert!(csv.starts_with("id,call_number,classification,authors,"));
+        assert!(csv.contains(&item.call_number()));
+
+        let items = read_items(csv.as_bytes(), None, DEFAULTS)?;
+        assert_eq!(items.len(), 1);
+        assert_eq!(items[0].as_ref().unwrap(), &item);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_import() -> Fallible<()> {
+        let mapping = Mapping::from_toml(
+            r#"
+                authors_separator = "&"
+
+                [columns]
+                title = "Title"
+                authors = "Author(s)"
+                original_date = "Year"
+                format = "Type"
+                isbn13 = "ISBN"
+            "#,
+        )?;
+        let csv = "\
+Title,Author(s),Year,Type,ISBN,Rating
+Sister Outsider,Lorde & Audre,1984,Paperback,0-89594-141-4,5
+Untitled,,198x,zine,,
+,Nobody,,zine,,
+Fun Home,Bechdel,2006,graphic novel,,4
+";
+        let defaults = ImportDefaults {
+            classification: Some(LESBClassification::NI),
+            ..DEFAULTS
+        };
+        let rows = read_items(csv.as_bytes(), Some(&mapping), defaults)?;
+        assert_eq!(rows.len(), 4);
+        let item = rows[0].as_ref().unwrap();
+        assert_eq!(item.authors, vec!["Lorde", "Audre"]);
+        assert_eq!(item.original_date, Some(PartialDate(1984, None)));
+        assert_eq!(item.format, Format::Paperback);
+        assert_eq!(item.isbn13.as_ref().unwrap(), "9780895941411");
+        assert_eq!(item.language, "und");
+        let errors = rows[1..]
+            .iter()
+            .map(|row| row.as_ref().unwrap_err().to_string())
+            .collect::<Vec<_>>();
+        assert!(errors[0].starts_with("line 3: bad date \"198x\""));
+        assert_eq!(errors[1], "line 4: no title");
+        assert!(errors[2].starts_with("line 5: unknown variant `graphic novel`"));
+
+        let mapping = Mapping::from_toml("[columns]\ntitle = \"Name\"")?;
+        assert!(read_items(csv.as_bytes(), Some(&mapping), defaults).is_err());
+        assert!(Mapping::from_toml("[columns]\ncall_number = \"Call number\"").is_err());
+
+        Ok(())
+    }
+}
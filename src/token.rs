@@ -0,0 +1,191 @@
+// SPDX-License-Identifier: AGPL-3.0-only
+
+use crate::db::{id_to_bytes, Db, Row, SaveData};
+use crate::user::User;
+use chrono::{DateTime, Utc};
+use failure::{ensure, Fallible};
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use sled::IVec;
+use std::collections::HashMap;
+use std::fmt;
+use std::str::FromStr;
+
+/// What an API token is allowed to do.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) enum Scope {
+    /// Read-only access to the catalog.
+    Catalog,
+    /// Checking items in and out and renewing them.
+    Circulation,
+    /// Everything, including managing users.
+    Admin,
+}
+
+impl FromStr for Scope {
+    type Err = serde_plain::Error;
+
+    fn from_str(s: &str) -> Result<Scope, serde_plain::Error> {
+        serde_plain::from_str(s)
+    }
+}
+
+impl fmt::Display for Scope {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{}",
+            serde_plain::to_string(self).map_err(|_| fmt::Error)?
+        )
+    }
+}
+
+/// A bearer token for scripted access to the JSON API.
+///
+/// Only a hash of the secret is stored; the secret itself is shown once, when the token is created.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub(crate) struct Token {
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    id: Option<u64>,
+
+    pub(crate) user: u64,
+    /// A label to tell tokens apart, like "scanner station".
+    pub(crate) name: String,
+    pub(crate) scopes: Vec<Scope>,
+    pub(crate) created: DateTime<Utc>,
+    hash: String,
+}
+
+impl Token {
+    /// Creates a token and returns it along with its secret.
+    pub(crate) fn new(user: u64, name: String, scopes: Vec<Scope>) -> (Token, String) {
+        let mut secret = [0; 32];
+        rand::thread_rng().fill_bytes(&mut secret);
+        let secret = base64::encode_config(&secret, base64::URL_SAFE_NO_PAD);
+        let token = Token {
+            id: None,
+            user,
+            name,
+            scopes,
+            created: Utc::now(),
+            hash: hash(&secret),
+        };
+        (token, secret)
+    }
+
+    pub(crate) fn id(&self) -> Option<u64> {
+        self.id
+    }
+
+    /// Whether the token allows `scope` for its user. Admin tokens can do anything, but only while
+    /// their user is an admin, and no token works for a blocked user.
+    pub(crate) fn allows(&self, user: &User, scope: Scope) -> bool {
+        if user.barcode != self.user || user.blocked {
+            return false;
+        }
+        (user.admin && self.scopes.contains(&Scope::Admin))
+            || (scope != Scope::Admin && self.scopes.contains(&scope))
+    }
+}
+
+pub(crate) fn hash(secret: &str) -> String {
+    let hash = blake2_rfc::blake2b::blake2b(32, &[], secret.as_bytes());
+    base64::encode_config(hash.as_bytes(), base64::URL_SAFE_NO_PAD)
+}
+
+/// Creates and saves a token for a user, returning the secret.
+pub(crate) fn create_token(
+    db: &mut Db,
+    user: u64,
+    name: String,
+    scopes: Vec<Scope>,
+) -> Fallible<String> {
+    ensure!(!scopes.is_empty(), "tokens need at least one scope");
+    let owner = db
+        .load::<User>(user)?
+        .ok_or_else(|| failure::err_msg(format!("no user with barcode {}", user)))?;
+    ensure!(
+        owner.admin || !scopes.contains(&Scope::Admin),
+        "{} isn't an admin, so can't have an admin token",
+        owner.name
+    );
+    let (mut token, secret) = Token::new(user, name, scopes);
+    db.save(&mut token)?;
+    Ok(secret)
+}
+
+impl Row for Token {
+    const TREE: &'static str = "token";
+    const UNIQUE: &'static [&'static str] = &["hash"];
+    const REVERSE: &'static [&'static str] = &["user"];
+
+    fn load(id: u64, blob: &[u8], _secondary: HashMap<&'static str, IVec>) -> Fallible<Token> {
+        let mut token: Token = serde_cbor::from_slice(blob)?;
+        token.id = Some(id);
+        Ok(token)
+    }
+
+    fn save<F>(&mut self, id_gen: F) -> Fallible<SaveData>
+    where
+        F: FnOnce(Option<u64>) -> Fallible<u64>,
+    {
+        let id = id_gen(self.id)?;
+        self.id = Some(id);
+        Ok(SaveData::new(id, serde_cbor::to_vec(self)?)
+            .unique("hash", self.hash.as_bytes().to_vec())
+            .reverse("user", id_to_bytes(self.user).to_vec()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::db::Db;
+    use crate::token::{create_token, Scope, Token};
+    use crate::user::User;
+    use failure::Fallible;
+
+    #[test]
+    fn test_create() -> Fallible<()> {
+        let mut db = Db::open_memory()?;
+        assert!(create_token(&mut db, 0, "scanner".to_owned(), vec![Scope::Catalog]).is_err());
+        db.save(&mut User::test_user())?;
+        assert!(create_token(&mut db, 0, "scanner".to_owned(), Vec::new()).is_err());
+        let secret = create_token(&mut db, 0, "scanner".to_owned(), vec![Scope::Catalog])?;
+        assert_eq!(db.load_token(&secret)?.unwrap().user, 0);
+        assert!(create_token(&mut db, 0, "sync".to_owned(), vec![Scope::Admin]).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test() -> Fallible<()> {
+        let mut db = Db::open_memory()?;
+        let (mut token, secret) = Token::new(1, "scanner".to_owned(), vec![Scope::Circulation]);
+        db.save(&mut token)?;
+
+        assert_eq!(db.load_token(&secret)?, Some(token.clone()));
+        assert_eq!(db.load_token("not a token")?, None);
+        assert_eq!(db.tokens_for_user(1)?, vec![token.clone()]);
+        let mut user = User::new(1, "scanner station".to_owned());
+        assert!(token.allows(&user, Scope::Circulation));
+        assert!(!token.allows(&user, Scope::Admin));
+        assert!(!token.allows(&User::test_user(), Scope::Circulation));
+        user.block(None);
+        assert!(!token.allows(&user, Scope::Circulation));
+
+        // Admin tokens stop being admin tokens when their user is demoted.
+        let (admin_token, _) = Token::new(1, "sync".to_owned(), vec![Scope::Admin]);
+        user.unblock();
+        assert!(!admin_token.allows(&user, Scope::Catalog));
+        user.admin = true;
+        assert!(admin_token.allows(&user, Scope::Catalog));
+        assert_eq!("admin".parse::<Scope>()?, Scope::Admin);
+
+        db.delete::<Token>(token.id().unwrap())?;
+        assert_eq!(db.load_token(&secret)?, None);
+
+        Ok(())
+    }
+}
@@ -1,17 +1,27 @@
 // SPDX-License-Identifier: AGPL-3.0-only
 
-use crate::db::{IndexedRow, Row, SaveData};
-use failure::{ensure, Fallible};
+use crate::circulation;
+use crate::db::{Db, IndexedRow, Row, SaveData, Timestamps};
+use crate::hold::Hold;
+use crate::item::Item;
+use crate::token::Token;
+use failure::{bail, ensure, Fallible};
 use lazy_static::lazy_static;
 use serde::{Deserialize, Serialize};
 use sled::IVec;
 use std::collections::HashMap;
+use std::num::ParseIntError;
 use tantivy::schema::{Document, Field, Schema};
 
 struct UserSchema {
     schema: Schema,
     barcode: Field,
     name: Field,
+    name_prefix: Field,
+    email: Field,
+    created_at: Field,
+    created_order: Field,
+    updated_at: Field,
 }
 
 impl UserSchema {
@@ -21,10 +31,20 @@ impl UserSchema {
         let mut schema_builder = SchemaBuilder::default();
         let barcode = schema_builder.add_u64_field("barcode", INDEXED | STORED | FAST);
         let name = schema_builder.add_text_field("name", TEXT);
+        let name_prefix = schema_builder.add_text_field("name_prefix", TEXT);
+        let email = schema_builder.add_text_field("email", TEXT);
+        let created_at = schema_builder.add_date_field("created_at", INDEXED | FAST);
+        let created_order = schema_builder.add_u64_field("created_order", FAST);
+        let updated_at = schema_builder.add_date_field("updated_at", INDEXED | FAST);
         UserSchema {
             schema: schema_builder.build(),
             barcode,
             name,
+            name_prefix,
+            email,
+            created_at,
+            created_order,
+            updated_at,
         }
     }
 }
@@ -49,23 +69,97 @@ pub(crate) struct User {
     #[serde(default = "return_false")]
     #[serde(skip_serializing_if = "bool_is_false")]
     pub(crate) admin: bool,
+    /// The name to show instead of `name`, if the user prefers one.
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) display_name: Option<String>,
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) email: Option<String>,
+    /// Blocked users can't borrow or renew anything.
+    #[serde(default = "return_false")]
+    #[serde(skip_serializing_if = "bool_is_false")]
+    pub(crate) blocked: bool,
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) blocked_reason: Option<String>,
+    /// Overrides the loan policy's `max_loans` for this user.
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) loan_limit: Option<usize>,
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) notes: Option<String>,
+
+    #[serde(flatten)]
+    pub(crate) timestamps: Timestamps,
 }
 
 impl User {
+    pub(crate) fn new(barcode: u64, name: String) -> User {
+        User {
+            barcode,
+            name,
+            admin: false,
+            display_name: None,
+            email: None,
+            blocked: false,
+            blocked_reason: None,
+            loan_limit: None,
+            notes: None,
+            timestamps: Timestamps::default(),
+        }
+    }
+
+    pub(crate) fn display_name(&self) -> &str {
+        self.display_name.as_ref().unwrap_or(&self.name)
+    }
+
+    pub(crate) fn block(&mut self, reason: Option<String>) {
+        self.blocked = true;
+        self.blocked_reason = reason;
+    }
+
+    pub(crate) fn unblock(&mut self) {
+        self.blocked = false;
+        self.blocked_reason = None;
+    }
+
     fn document(&self) -> Document {
         let mut document = Document::new();
         document.add_u64(SCHEMA.barcode, self.barcode);
         document.add_text(SCHEMA.name, &self.name);
+        if let Some(display_name) = &self.display_name {
+            document.add_text(SCHEMA.name, display_name);
+        }
+        for name in std::iter::once(&self.name).chain(&self.display_name) {
+            for word in name.split(|c: char| !c.is_alphanumeric()) {
+                // Every prefix of every word, so the desk can find someone from the first few
+                // letters of their name.
+                for (end, _) in word.char_indices().skip(2) {
+                    document.add_text(SCHEMA.name_prefix, &word[..end]);
+                }
+            }
+        }
+        if let Some(email) = &self.email {
+            document.add_text(SCHEMA.email, email);
+        }
+        if let Some(created_at) = &self.timestamps.created_at {
+            document.add_date(SCHEMA.created_at, created_at);
+        }
+        document.add_u64(
+            SCHEMA.created_order,
+            self.timestamps.creation_order(self.barcode),
+        );
+        if let Some(updated_at) = &self.timestamps.updated_at {
+            document.add_date(SCHEMA.updated_at, updated_at);
+        }
         document
     }
 
     #[cfg(test)]
     pub(crate) fn test_user() -> User {
-        User {
-            barcode: 0,
-            name: "test user".to_owned(),
-            admin: false,
-        }
+        User::new(0, "test user".to_owned())
     }
 }
 
@@ -85,6 +179,71 @@ impl Row for User {
         Ok(SaveData::new(self.barcode, serde_cbor::to_vec(self)?)
             .index(User::id_field(), self.document()))
     }
+
+    fn timestamps(&mut self) -> Option<&mut Timestamps> {
+        Some(&mut self.timestamps)
+    }
+}
+
+/// Saves a new user, refusing to replace an existing user with the same barcode.
+pub(crate) fn add_user(db: &mut Db, user: &mut User) -> Fallible<()> {
+    if let Some(existing) = db.load::<User>(user.barcode)? {
+        bail!(
+            "barcode {} already belongs to {}",
+            user.barcode,
+            existing.name
+        );
+    }
+    db.save(user)
+}
+
+/// Deletes a user, cancels their holds and revokes their API tokens. Users who still have items
+/// checked out are only deleted with `force`, which leaves those loans pointing at a user who no
+/// longer exists.
+pub(crate) fn delete_user(db: &mut Db, user: &User, force: bool) -> Fallible<()> {
+    let loans = db.loans_for_user(user.barcode)?;
+    ensure!(
+        force || loans.is_empty(),
+        "{} still has {} items checked out",
+        user.name,
+        loans.len()
+    );
+    for hold in db.holds_for_user(user.barcode)? {
+        match db.load::<Item>(hold.item)? {
+            Some(item) => circulation::cancel_hold(db, &item, user)?,
+            None => {
+                if let Some(id) = hold.id() {
+                    db.delete::<Hold>(id)?;
+                }
+            }
+        }
+    }
+    for token in db.tokens_for_user(user.barcode)? {
+        if let Some(id) = token.id() {
+            db.delete::<Token>(id)?;
+        }
+    }
+    db.delete::<User>(user.barcode)
+}
+
+/// Edit forms and commands clear an optional field with an empty string.
+pub(crate) fn non_empty(s: String) -> Option<String> {
+    if s.is_empty() {
+        None
+    } else {
+        Some(s)
+    }
+}
+
+/// Parses an edited loan limit. An empty string or "none" clears it, so the loan policy's
+/// `max_loans` applies again.
+pub(crate) fn parse_loan_limit(s: &str) -> Result<Option<usize>, ParseIntError> {
+    let s = s.trim();
+    if s.is_empty() || s.eq_ignore_ascii_case("none") {
+        Ok(None)
+    } else {
+        s.parse().map(Some)
+    }
 }
 
 impl IndexedRow for User {
@@ -97,16 +256,65 @@ impl IndexedRow for User {
     }
 
     fn query_parser_fields() -> Vec<Field> {
-        vec![SCHEMA.name]
+        vec![SCHEMA.name, SCHEMA.name_prefix, SCHEMA.email]
     }
 }
 
 #[cfg(test)]
 mod tests {
+    use crate::circulation::{check_out, LoanPolicy};
     use crate::db::Db;
-    use crate::user::User;
+    use crate::item::Item;
+    use crate::token::{create_token, Scope};
+    use crate::user::{add_user, delete_user, parse_loan_limit, User};
     use failure::Fallible;
 
+    #[test]
+    fn test_add_delete() -> Fallible<()> {
+        let mut db = Db::open_memory()?;
+        let mut user = User::test_user();
+        add_user(&mut db, &mut user)?;
+        assert!(add_user(&mut db, &mut User::new(0, "someone else".to_owned())).is_err());
+
+        let mut item = Item::test_item();
+        db.save(&mut item)?;
+        check_out(&mut db, &LoanPolicy::default(), &mut item, &user)?;
+        let secret = create_token(
+            &mut db,
+            user.barcode,
+            "scanner".to_owned(),
+            vec![Scope::Catalog],
+        )?;
+        assert!(delete_user(&mut db, &user, false).is_err());
+        delete_user(&mut db, &user, true)?;
+        assert_eq!(db.load::<User>(user.barcode)?, None);
+        assert_eq!(db.load_token(&secret)?, None);
+        assert!(db.query::<User>("test")?.is_empty());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_search() -> Fallible<()> {
+        let mut db = Db::open_memory()?;
+        let mut user = User {
+            display_name: Some("Sam".to_owned()),
+            email: Some("sam@example.com".to_owned()),
+            ..User::new(1, "Samantha Quinn".to_owned())
+        };
+        db.save(&mut user)?;
+        db.save(&mut User::test_user())?;
+
+        for query in &["samantha", "sam", "Quin", "sam@example.com"] {
+            let users = db.query::<User>(query)?;
+            assert_eq!(users.len(), 1, "{}", query);
+            assert_eq!(users[0], user);
+        }
+        assert!(db.query::<User>("qa")?.is_empty());
+
+        Ok(())
+    }
+
     #[test]
     fn test() -> Fallible<()> {
         let mut db = Db::open_memory()?;
@@ -122,4 +330,13 @@ mod tests {
 
         Ok(())
     }
+
+    #[test]
+    fn test_parse_loan_limit() -> Fallible<()> {
+        assert_eq!(parse_loan_limit("3")?, Some(3));
+        assert_eq!(parse_loan_limit("")?, None);
+        assert_eq!(parse_loan_limit("none")?, None);
+        assert!(parse_loan_limit("-1").is_err());
+        Ok(())
+    }
 }
@@ -0,0 +1,215 @@
+use crate::audit::AuditEntry;
+use crate::db::Db;
+use crate::token::{self, Scope, Token};
+use crate::user::{self, non_empty, User};
+use crate::web::api::{self, Denied};
+use askama::Template;
+use failure::Fallible;
+use log::error;
+use rouille::{post_input, try_or_400, Request, Response};
+
+#[derive(Template)]
+#[template(path = "admin/audit.html")]
+struct AuditTemplate {
+    entries: Vec<AuditEntry>,
+}
+
+#[derive(Template)]
+#[template(path = "admin/users.html")]
+struct UsersTemplate {
+    users: Vec<User>,
+}
+
+#[derive(Template)]
+#[template(path = "admin/user.html")]
+struct UserTemplate {
+    user: User,
+    loans: usize,
+    tokens: Vec<Token>,
+}
+
+#[derive(Template)]
+#[template(path = "admin/token.html")]
+struct TokenTemplate {
+    user: User,
+    secret: String,
+}
+
+/// Runs an admin page if the request authenticates with an admin token, which browsers send as
+/// the password of HTTP basic authentication. Changes it makes are attributed to the admin.
+pub(super) fn authorized<F>(request: &Request, db: &mut Db, page: F) -> Response
+where
+    F: FnOnce(&mut Db) -> Response,
+{
+    match api::authenticate(db, request, Scope::Admin) {
+        Ok(_) => page(db),
+        Err(Denied::Missing) | Err(Denied::Invalid) => Response::text("admin login required")
+            .with_status_code(401)
+            .with_unique_header("WWW-Authenticate", "Basic realm=\"LESBIANS admin\""),
+        Err(Denied::Forbidden(_)) => Response::text("admins only").with_status_code(403),
+        Err(Denied::Error(err)) => {
+            error!("{}", err);
+            Response::text("internal server error").with_status_code(500)
+        }
+    }
+}
+
+/// Shows the most recent changes, optionally only those to one tree or row.
+pub(super) fn audit_page(request: &Request, db: &Db) -> Fallible<Response> {
+    let tree = request.get_param("tree").filter(|tree| !tree.is_empty());
+    let id = request
+        .get_param("id")
+        .and_then(|id| id.parse::<u64>().ok());
+    let entries = db.audit_log(
+        |entry| {
+            tree.as_ref().map_or(true, |tree| &entry.tree == tree)
+                && id.map_or(true, |id| entry.id == id)
+        },
+        200,
+    )?;
+    Ok(Response::html(AuditTemplate { entries }.render()?))
+}
+
+pub(super) fn users_page(db: &Db) -> Fallible<Response> {
+    let users = db.iter::<User>()?.collect::<Fallible<_>>()?;
+    Ok(Response::html(UsersTemplate { users }.render()?))
+}
+
+pub(super) fn user_page(db: &Db, barcode: u64) -> Fallible<Response> {
+    Ok(match db.load::<User>(barcode)? {
+        Some(user) => Response::html(
+            UserTemplate {
+                user,
+                loans: db.loans_for_user(barcode)?.len(),
+                tokens: db.tokens_for_user(barcode)?,
+            }
+            .render()?,
+        ),
+        None => Response::empty_404(),
+    })
+}
+
+/// Admin actions mostly fail because of something the admin can fix, like a duplicate barcode, so
+/// show them why.
+fn redirect_or_400(result: Fallible<()>, location: String) -> Response {
+    match result {
+        Ok(()) => Response::redirect_303(location),
+        Err(err) => Response::text(err.to_string()).with_status_code(400),
+    }
+}
+
+fn update_user<F>(db: &mut Db, barcode: u64, f: F) -> Response
+where
+    F: FnOnce(&mut User),
+{
+    let result = db.load::<User>(barcode).and_then(|user| match user {
+        Some(mut user) => {
+            f(&mut user);
+            db.save(&mut user)
+        }
+        None => Err(failure::err_msg(format!(
+            "no user with barcode {}",
+            barcode
+        ))),
+    });
+    redirect_or_400(result, format!("/admin/users/{}", barcode))
+}
+
+pub(super) fn add_user(request: &Request, db: &mut Db) -> Response {
+    let input = try_or_400!(post_input!(request, {
+        barcode: u64,
+        name: String,
+        admin: bool,
+    }));
+    let mut user = User::new(input.barcode, input.name);
+    user.admin = input.admin;
+    redirect_or_400(
+        user::add_user(db, &mut user),
+        format!("/admin/users/{}", input.barcode),
+    )
+}
+
+pub(super) fn edit_user(request: &Request, db: &mut Db, barcode: u64) -> Response {
+    let input = try_or_400!(post_input!(request, {
+        name: String,
+        display_name: String,
+        email: String,
+        loan_limit: String,
+        notes: String,
+    }));
+    let loan_limit = try_or_400!(user::parse_loan_limit(&input.loan_limit));
+    let (name, display_name, email, notes) =
+        (input.name, input.display_name, input.email, input.notes);
+    update_user(db, barcode, |user| {
+        user.name = name;
+        user.display_name = non_empty(display_name);
+        user.email = non_empty(email);
+        user.loan_limit = loan_limit;
+        user.notes = non_empty(notes);
+    })
+}
+
+pub(super) fn block_user(request: &Request, db: &mut Db, barcode: u64) -> Response {
+    let input = try_or_400!(post_input!(request, { reason: String }));
+    update_user(db, barcode, |user| user.block(non_empty(input.reason)))
+}
+
+pub(super) fn unblock_user(db: &mut Db, barcode: u64) -> Response {
+    update_user(db, barcode, User::unblock)
+}
+
+pub(super) fn set_admin(db: &mut Db, barcode: u64, admin: bool) -> Response {
+    update_user(db, barcode, |user| user.admin = admin)
+}
+
+pub(super) fn delete_user(request: &Request, db: &mut Db, barcode: u64) -> Response {
+    let input = try_or_400!(post_input!(request, { force: bool }));
+    let result = db.load::<User>(barcode).and_then(|user| match user {
+        Some(user) => user::delete_user(db, &user, input.force),
+        None => Ok(()),
+    });
+    redirect_or_400(result, "/admin/users".to_owned())
+}
+
+pub(super) fn create_token(request: &Request, db: &mut Db, barcode: u64) -> Response {
+    let input = try_or_400!(post_input!(request, {
+        name: String,
+        catalog: bool,
+        circulation: bool,
+        admin: bool,
+    }));
+    let scopes = [
+        (input.catalog, Scope::Catalog),
+        (input.circulation, Scope::Circulation),
+        (input.admin, Scope::Admin),
+    ]
+    .iter()
+    .filter(|(checked, _)| *checked)
+    .map(|(_, scope)| *scope)
+    .collect();
+    // The secret is only shown this once, so render it directly instead of redirecting.
+    let result = token::create_token(db, barcode, input.name, scopes).and_then(|secret| {
+        let user = db
+            .load::<User>(barcode)?
+            .ok_or_else(|| failure::err_msg(format!("no user with barcode {}", barcode)))?;
+        Ok(TokenTemplate { user, secret }.render()?)
+    });
+    match result {
+        Ok(html) => Response::html(html),
+        Err(err) => Response::text(err.to_string()).with_status_code(400),
+    }
+}
+
+pub(super) fn revoke_token(db: &mut Db, id: u64) -> Response {
+    let result = db.load::<Token>(id).and_then(|token| match token {
+        Some(token) => {
+            db.delete::<Token>(id)?;
+            Ok(token.user)
+        }
+        None => Err(failure::err_msg(format!("no token with id {}", id))),
+    });
+    match result {
+        Ok(user) => Response::redirect_303(format!("/admin/users/{}", user)),
+        Err(err) => Response::text(err.to_string()).with_status_code(400),
+    }
+}
@@ -0,0 +1,308 @@
+use crate::circulation::{self, LoanPolicy};
+use crate::db::Db;
+use crate::item::Item;
+use crate::replication::{self, Changes};
+use crate::token::{Scope, Token};
+use crate::user::User;
+use failure::Fallible;
+use log::error;
+use rouille::input::json_input;
+use rouille::{try_or_400, Request, Response};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+
+fn error(status_code: u16, message: &str) -> Response {
+    Response::json(&json!({ "error": message })).with_status_code(status_code)
+}
+
+fn internal_error(err: &failure::Error) -> Response {
+    error!("{}", err);
+    error(500, "internal server error")
+}
+
+/// Why a request's credentials don't allow what it's doing.
+pub(super) enum Denied {
+    Missing,
+    Invalid,
+    Forbidden(Scope),
+    Error(failure::Error),
+}
+
+/// Returns a token secret sent as a bearer token, or as the password of HTTP basic authentication,
+/// which browsers can prompt for.
+fn secret(request: &Request) -> Option<String> {
+    let mut parts = request.header("Authorization")?.splitn(2, ' ');
+    let (kind, credentials) = (parts.next()?, parts.next()?.trim());
+    if kind.eq_ignore_ascii_case("bearer") {
+        Some(credentials.to_owned())
+    } else if kind.eq_ignore_ascii_case("basic") {
+        let credentials = String::from_utf8(base64::decode(credentials).ok()?).ok()?;
+        credentials.splitn(2, ':').nth(1).map(str::to_owned)
+    } else {
+        None
+    }
+}
+
+/// Browsers attach HTTP basic credentials to requests from any site, so a POST carrying them has
+/// to come from one of our own pages, going by its `Origin` or `Referer` header. Scripts should
+/// send a bearer token instead.
+pub(super) fn cross_site(request: &Request, base_url: &str) -> bool {
+    let basic = request
+        .header("Authorization")
+        .and_then(|authorization| authorization.split_whitespace().next())
+        .map_or(false, |kind| kind.eq_ignore_ascii_case("basic"));
+    let from = request
+        .header("Origin")
+        .or_else(|| request.header("Referer"));
+    request.method() == "POST"
+        && basic
+        && !from.map_or(false, |from| {
+            from == base_url || from.starts_with(&format!("{}/", base_url))
+        })
+}
+
+/// Checks that the request's token allows `scope` for a user who still exists. Changes made
+/// afterwards are attributed to the token's user.
+pub(super) fn authenticate(db: &mut Db, request: &Request, scope: Scope) -> Result<Token, Denied> {
+    let secret = secret(request).ok_or(Denied::Missing)?;
+    let token = db
+        .load_token(&secret)
+        .map_err(Denied::Error)?
+        .ok_or(Denied::Invalid)?;
+    let user = db
+        .load::<User>(token.user)
+        .map_err(Denied::Error)?
+        .ok_or(Denied::Invalid)?;
+    if user.blocked {
+        return Err(Denied::Invalid);
+    }
+    if !token.allows(&user, scope) {
+        return Err(Denied::Forbidden(scope));
+    }
+    db.set_actor(Some(token.user));
+    Ok(token)
+}
+
+/// Checks the request's bearer token, returning the response to send instead if it doesn't allow
+/// `scope`.
+fn authorize(db: &mut Db, request: &Request, scope: Scope) -> Result<Token, Response> {
+    authenticate(db, request, scope).map_err(|denied| match denied {
+        Denied::Missing => {
+            error(401, "missing bearer token").with_unique_header("WWW-Authenticate", "Bearer")
+        }
+        Denied::Invalid => error(401, "invalid bearer token")
+            .with_unique_header("WWW-Authenticate", "Bearer error=\"invalid_token\""),
+        Denied::Forbidden(scope) => error(403, &format!("token does not have the {} scope", scope)),
+        Denied::Error(err) => internal_error(&err),
+    })
+}
+
+/// Requests mostly fail because of something the caller can fix, like checking out an item that's
+/// already out, so tell them why.
+fn json_or_400<T: Serialize>(result: Fallible<T>) -> Response {
+    match result {
+        Ok(value) => Response::json(&value),
+        Err(err) => error(400, &err.to_string()),
+    }
+}
+
+fn load_item(db: &Db, barcode: &str) -> Fallible<Item> {
+    db.load_by_barcode(barcode)?
+        .ok_or_else(|| failure::err_msg(format!("no item with barcode {:?}", barcode)))
+}
+
+fn load_user(db: &Db, barcode: u64) -> Fallible<User> {
+    db.load(barcode)?
+        .ok_or_else(|| failure::err_msg(format!("no user with barcode {}", barcode)))
+}
+
+pub(super) fn item(request: &Request, db: &mut Db, barcode: &str) -> Response {
+    if let Err(response) = authorize(db, request, Scope::Catalog) {
+        return response;
+    }
+    match db.load_by_barcode(barcode) {
+        Ok(Some(item)) => Response::json(&item),
+        Ok(None) => error(404, "not found"),
+        Err(err) => internal_error(&err),
+    }
+}
+
+pub(super) fn search(request: &Request, db: &mut Db) -> Response {
+    if let Err(response) = authorize(db, request, Scope::Catalog) {
+        return response;
+    }
+    let query = request.get_param("q").unwrap_or_default();
+    json_or_400(db.query::<Item>(&query))
+}
+
+#[derive(Deserialize)]
+struct CheckOut {
+    item: String,
+    user: u64,
+}
+
+#[derive(Deserialize)]
+struct CheckIn {
+    item: String,
+}
+
+pub(super) fn check_out(request: &Request, db: &mut Db, policy: &LoanPolicy) -> Response {
+    if let Err(response) = authorize(db, request, Scope::Circulation) {
+        return response;
+    }
+    let input: CheckOut = try_or_400!(json_input(request));
+    json_or_400((|| {
+        let mut item = load_item(db, &input.item)?;
+        let user = load_user(db, input.user)?;
+        circulation::check_out(db, policy, &mut item, &user)?;
+        Ok(item)
+    })())
+}
+
+pub(super) fn check_in(request: &Request, db: &mut Db) -> Response {
+    if let Err(response) = authorize(db, request, Scope::Circulation) {
+        return response;
+    }
+    let input: CheckIn = try_or_400!(json_input(request));
+    json_or_400((|| {
+        let mut item = load_item(db, &input.item)?;
+        let (loan, hold) = circulation::check_in(db, &mut item)?;
+        Ok(json!({ "loan": loan, "hold": hold }))
+    })())
+}
+
+pub(super) fn renew(request: &Request, db: &mut Db, policy: &LoanPolicy) -> Response {
+    if let Err(response) = authorize(db, request, Scope::Circulation) {
+        return response;
+    }
+    let input: CheckIn = try_or_400!(json_input(request));
+    json_or_400((|| {
+        let mut item = load_item(db, &input.item)?;
+        circulation::renew(db, policy, &mut item)?;
+        Ok(item)
+    })())
+}
+
+pub(super) fn user(request: &Request, db: &mut Db, barcode: u64) -> Response {
+    if let Err(response) = authorize(db, request, Scope::Admin) {
+        return response;
+    }
+    match db.load::<User>(barcode) {
+        Ok(Some(user)) => Response::json(&user),
+        Ok(None) => error(404, "not found"),
+        Err(err) => internal_error(&err),
+    }
+}
+
+pub(super) fn changes(request: &Request, db: &mut Db) -> Response {
+    if let Err(response) = authorize(db, request, Scope::Admin) {
+        return response;
+    }
+    let since = match request.get_param("since") {
+        Some(since) => Some(try_or_400!(since.parse::<u64>())),
+        None => None,
+    };
+    match replication::changes(db, since) {
+        Ok(changes) => Response::json(&changes),
+        Err(err) => internal_error(&err),
+    }
+}
+
+/// Merges changes pushed from a replica.
+pub(super) fn merge(request: &Request, db: &mut Db) -> Response {
+    if let Err(response) = authorize(db, request, Scope::Admin) {
+        return response;
+    }
+    let changes: Changes = try_or_400!(json_input(request));
+    json_or_400(replication::merge(db, changes.rows, true))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{authenticate, cross_site, Denied};
+    use crate::db::Db;
+    use crate::token::{create_token, Scope};
+    use crate::user::User;
+    use failure::Fallible;
+    use rouille::Request;
+
+    fn request(authorization: &str) -> Request {
+        let headers = vec![("Authorization".to_owned(), authorization.to_owned())];
+        Request::fake_http("GET", "/api/changes", headers, Vec::new())
+    }
+
+    fn check(db: &mut Db, authorization: &str) -> Option<u64> {
+        match authenticate(db, &request(authorization), Scope::Admin) {
+            Ok(token) => Some(token.user),
+            Err(Denied::Error(err)) => panic!("{}", err),
+            Err(_) => None,
+        }
+    }
+
+    #[test]
+    fn test() -> Fallible<()> {
+        let mut db = Db::open_memory()?;
+        let mut user = User::test_user();
+        user.admin = true;
+        db.save(&mut user)?;
+        let secret = create_token(&mut db, user.barcode, "sync".to_owned(), vec![Scope::Admin])?;
+
+        let bearer = format!("Bearer {}", secret);
+        let basic = format!("Basic {}", base64::encode(&format!("admin:{}", secret)));
+        assert_eq!(check(&mut db, &bearer), Some(user.barcode));
+        assert_eq!(check(&mut db, &basic), Some(user.barcode));
+        assert_eq!(check(&mut db, "Bearer not a token"), None);
+
+        // Changes are attributed to whoever authenticated.
+        user.admin = false;
+        db.save(&mut user)?;
+        assert_eq!(db.audit_log(|_| true, 1)?[0].actor, Some(user.barcode));
+        assert_eq!(check(&mut db, &bearer), None);
+        user.admin = true;
+        user.block(None);
+        db.save(&mut user)?;
+        assert_eq!(check(&mut db, &bearer), None);
+        db.delete::<User>(user.barcode)?;
+        assert_eq!(check(&mut db, &bearer), None);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_cross_site() {
+        let base_url = "https://library.example";
+        let post = |headers: Vec<(&str, &str)>| {
+            let headers = headers
+                .into_iter()
+                .map(|(name, value)| (name.to_owned(), value.to_owned()))
+                .collect();
+            Request::fake_http("POST", "/admin/users/1/promote", headers, Vec::new())
+        };
+        let basic = ("Authorization", "Basic YWRtaW46c2VjcmV0");
+
+        assert!(cross_site(&post(vec![basic]), base_url));
+        assert!(cross_site(
+            &post(vec![basic, ("Origin", "https://evil.example")]),
+            base_url
+        ));
+        assert!(cross_site(
+            &post(vec![basic, ("Referer", "https://library.example.evil/")]),
+            base_url
+        ));
+        assert!(!cross_site(
+            &post(vec![basic, ("Origin", base_url)]),
+            base_url
+        ));
+        assert!(!cross_site(
+            &post(vec![
+                basic,
+                ("Referer", "https://library.example/admin/users/1")
+            ]),
+            base_url
+        ));
+        assert!(!cross_site(
+            &post(vec![("Authorization", "Bearer secret")]),
+            base_url
+        ));
+    }
+}
@@ -0,0 +1,142 @@
+use crate::db::Db;
+use crate::item::Item;
+use crate::lesb::LESBCategory;
+use askama::Template;
+use chrono::{DateTime, SecondsFormat, TimeZone, Utc};
+use failure::Fallible;
+use rouille::{Request, Response};
+
+/// How many items the new acquisitions feed shows.
+const NEW_ITEMS: usize = 50;
+
+#[derive(Template)]
+#[template(path = "feeds/new.xml")]
+struct NewItemsFeed {
+    title: String,
+    self_url: String,
+    base_url: String,
+    updated: String,
+    entries: Vec<FeedEntry>,
+}
+
+pub(super) fn rfc3339(time: DateTime<Utc>) -> String {
+    time.to_rfc3339_opts(SecondsFormat::Secs, true)
+}
+
+struct FeedEntry {
+    id: u64,
+    title: String,
+    authors: Vec<String>,
+    call_number: String,
+    format: String,
+    category: LESBCategory,
+    classification: String,
+    published: String,
+    updated: String,
+}
+
+impl FeedEntry {
+    fn new(item: &Item) -> Option<FeedEntry> {
+        let published = item.timestamps.created_at?;
+        let category = item.classification.category();
+        Some(FeedEntry {
+            id: item.id()?,
+            title: item.title.clone(),
+            authors: item.authors.clone(),
+            call_number: item.call_number(),
+            format: item.format.to_string(),
+            category,
+            classification: format!(
+                "{} -- {}",
+                category.description(),
+                item.classification.description()
+            ),
+            published: rfc3339(published),
+            updated: rfc3339(item.timestamps.updated_at.unwrap_or(published)),
+        })
+    }
+}
+
+fn new_items_feed(
+    db: &Db,
+    category: Option<LESBCategory>,
+    base_url: &str,
+) -> Fallible<NewItemsFeed> {
+    let since = Utc.timestamp(0, 0);
+    let items = match category {
+        Some(category) => db.added_since::<Item>(
+            since,
+            Some(&format!("classification:\"{}\"", category)),
+            Some(NEW_ITEMS),
+        )?,
+        None => db.added_since::<Item>(since, None, Some(NEW_ITEMS))?,
+    };
+    let updated = items
+        .iter()
+        .filter_map(|item| item.timestamps.updated_at.or(item.timestamps.created_at))
+        .max()
+        .unwrap_or_else(Utc::now);
+
+    let (title, self_url) = match category {
+        Some(category) => (
+            format!("New acquisitions: {}", category.description()),
+            format!("{}/feeds/new.atom?category={}", base_url, category),
+        ),
+        None => (
+            "New acquisitions".to_owned(),
+            format!("{}/feeds/new.atom", base_url),
+        ),
+    };
+    Ok(NewItemsFeed {
+        title,
+        self_url,
+        base_url: base_url.to_owned(),
+        updated: rfc3339(updated),
+        entries: items.iter().filter_map(FeedEntry::new).collect(),
+    })
+}
+
+pub(super) fn new_items(request: &Request, db: &Db, base_url: &str) -> Fallible<Response> {
+    let category = match request.get_param("category") {
+        Some(category) => match category.parse::<LESBCategory>() {
+            Ok(category) => Some(category),
+            Err(err) => return Ok(Response::text(err.to_string()).with_status_code(400)),
+        },
+        None => None,
+    };
+    Ok(Response::from_data(
+        "application/atom+xml; charset=utf-8",
+        new_items_feed(db, category, base_url)?.render()?,
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::new_items_feed;
+    use crate::db::Db;
+    use crate::item::Item;
+    use crate::lesb::{LESBCategory, LESBClassification};
+    use askama::Template;
+    use failure::Fallible;
+
+    #[test]
+    fn test() -> Fallible<()> {
+        let mut db = Db::open_memory()?;
+        let mut item = Item::test_item();
+        item.title = "Zines & <records>".to_owned();
+        item.classification = LESBClassification::LF;
+        db.save(&mut item)?;
+        db.save(&mut Item::test_item())?;
+
+        let feed = new_items_feed(&db, None, "http://library.example")?;
+        assert_eq!(feed.entries.len(), 2);
+        let feed = new_items_feed(&db, Some(LESBCategory::L), "http://library.example")?;
+        assert_eq!(feed.entries.len(), 1);
+        assert_eq!(feed.entries[0].classification, "Literature -- Fiction");
+        let xml = feed.render()?;
+        assert!(xml.contains("<title>Zines &amp; &lt;records&gt;</title>"));
+        assert!(xml.contains("<category term=\"L\" label=\"Literature\"/>"));
+
+        Ok(())
+    }
+}
@@ -0,0 +1,256 @@
+use crate::format::Format;
+use crate::item::Item;
+use askama::Template;
+use failure::Fallible;
+use serde_json::{json, Map, Value};
+
+pub(super) const XML_DECLARATION: &str = "<?xml version=\"1.0\" encoding=\"utf-8\"?>\n";
+
+/// An identifier from another catalog.
+pub(super) struct Identifier {
+    /// The MODS identifier type, like "isbn".
+    kind: &'static str,
+    value: String,
+}
+
+impl Identifier {
+    /// The identifier as a URI, for Dublin Core.
+    fn uri(&self) -> String {
+        match self.kind {
+            "isbn" | "issn" => format!("urn:{}:{}", self.kind, self.value),
+            "lccn" => format!("info:lccn/{}", self.value),
+            _ => format!("info:oclcnum/{}", self.value),
+        }
+    }
+}
+
+pub(super) struct Part {
+    volume: u64,
+    issue: u64,
+}
+
+/// What Dublin Core and MODS records say about an item.
+pub(super) struct Metadata {
+    url: String,
+    title: String,
+    authors: Vec<String>,
+    date: Option<String>,
+    language: String,
+    format: String,
+    subject: String,
+    call_number: String,
+    /// A term from the DCMI Type Vocabulary.
+    dc_type: &'static str,
+    /// A MODS `typeOfResource`.
+    mods_type: &'static str,
+    serial: bool,
+    part: Option<Part>,
+    identifiers: Vec<Identifier>,
+}
+
+fn is_music(format: Format) -> bool {
+    match format {
+        Format::CD
+        | Format::Vinyl12Inch
+        | Format::Vinyl10Inch
+        | Format::Vinyl7Inch
+        | Format::Cassette => true,
+        Format::Paperback | Format::Hardcover | Format::Magazine | Format::Zine => false,
+    }
+}
+
+impl Metadata {
+    pub(super) fn new(item: &Item, base_url: &str) -> Metadata {
+        let music = is_music(item.format);
+        let mut identifiers = Vec::new();
+        let mut identifier = |kind, value: &Option<String>| {
+            if let Some(value) = value {
+                identifiers.push(Identifier {
+                    kind,
+                    value: value.clone(),
+                });
+            }
+        };
+        identifier("isbn", &item.isbn13);
+        identifier("issn", &item.issn);
+        identifier("lccn", &item.lccn);
+        identifier("oclc", &item.oclc_number);
+        Metadata {
+            url: format!("{}/item/{}", base_url, item.id().unwrap_or_default()),
+            title: item.title.clone(),
+            authors: item.authors.clone(),
+            date: item.original_date.map(|date| date.to_string()),
+            language: item.language.clone(),
+            format: item.format.to_string(),
+            subject: format!(
+                "{} -- {}",
+                item.classification.category().description(),
+                item.classification.description()
+            ),
+            call_number: item.call_number(),
+            dc_type: if music { "Sound" } else { "Text" },
+            mods_type: if music {
+                "sound recording-musical"
+            } else {
+                "text"
+            },
+            serial: item.format == Format::Magazine,
+            part: item
+                .volume_and_issue
+                .map(|(volume, issue)| Part { volume, issue }),
+            identifiers,
+        }
+    }
+
+    /// An `oai_dc` record, without an XML declaration so it can be embedded.
+    pub(super) fn dublin_core(&self) -> Fallible<String> {
+        Ok(DublinCore { m: self }.render()?)
+    }
+
+    /// A MODS record, without an XML declaration so it can be embedded.
+    pub(super) fn mods(&self) -> Fallible<String> {
+        Ok(Mods { m: self }.render()?)
+    }
+}
+
+#[derive(Template)]
+#[template(path = "metadata/dc.xml")]
+struct DublinCore<'a> {
+    m: &'a Metadata,
+}
+
+#[derive(Template)]
+#[template(path = "metadata/mods.xml")]
+struct Mods<'a> {
+    m: &'a Metadata,
+}
+
+/// Returns the schema.org type for a format.
+fn schema_type(format: Format) -> &'static str {
+    match format {
+        Format::Paperback | Format::Hardcover | Format::Zine => "Book",
+        Format::Magazine => "PublicationIssue",
+        Format::CD
+        | Format::Vinyl12Inch
+        | Format::Vinyl10Inch
+        | Format::Vinyl7Inch
+        | Format::Cassette => "MusicAlbum",
+    }
+}
+
+/// Returns a schema.org description of an item as JSON-LD.
+pub(super) fn json_ld(item: &Item, base_url: &str) -> Value {
+    let schema_type = schema_type(item.format);
+    let mut ld = Map::new();
+    ld.insert("@context".to_owned(), json!("https://schema.org"));
+    ld.insert("@type".to_owned(), json!(schema_type));
+    ld.insert(
+        "@id".to_owned(),
+        json!(format!(
+            "{}/item/{}",
+            base_url,
+            item.id().unwrap_or_default()
+        )),
+    );
+    ld.insert("name".to_owned(), json!(item.title));
+    if !item.authors.is_empty() {
+        let (property, creator_type) = if schema_type == "MusicAlbum" {
+            ("byArtist", "MusicGroup")
+        } else {
+            ("author", "Person")
+        };
+        let creators = item
+            .authors
+            .iter()
+            .map(|name| json!({ "@type": creator_type, "name": name }))
+            .collect();
+        ld.insert(property.to_owned(), Value::Array(creators));
+    }
+    if let Some(date) = item.original_date {
+        ld.insert("datePublished".to_owned(), json!(date.to_string()));
+    }
+    ld.insert("inLanguage".to_owned(), json!(item.language));
+    match item.format {
+        Format::Paperback => {
+            ld.insert(
+                "bookFormat".to_owned(),
+                json!("https://schema.org/Paperback"),
+            );
+        }
+        Format::Hardcover => {
+            ld.insert(
+                "bookFormat".to_owned(),
+                json!("https://schema.org/Hardcover"),
+            );
+        }
+        _ => {}
+    }
+    if let Some(isbn) = &item.isbn13 {
+        ld.insert("isbn".to_owned(), json!(isbn));
+    }
+    if schema_type == "PublicationIssue" {
+        let mut periodical = json!({ "@type": "Periodical" });
+        if let Some(issn) = &item.issn {
+            periodical["issn"] = json!(issn);
+        }
+        if let Some((volume, issue)) = item.volume_and_issue {
+            ld.insert("issueNumber".to_owned(), json!(issue.to_string()));
+            periodical = json!({
+                "@type": "PublicationVolume",
+                "volumeNumber": volume.to_string(),
+                "isPartOf": periodical,
+            });
+        }
+        ld.insert("isPartOf".to_owned(), periodical);
+    }
+    Value::Object(ld)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{json_ld, Metadata};
+    use crate::db::Db;
+    use crate::format::Format;
+    use crate::item::Item;
+    use failure::Fallible;
+
+    #[test]
+    fn test() -> Fallible<()> {
+        let mut db = Db::open_memory()?;
+        let mut item = Item::test_item();
+        item.title = "Color problems & solutions".to_owned();
+        db.save(&mut item)?;
+        let base_url = "http://library.example";
+        let id = item.id().unwrap();
+
+        let metadata = Metadata::new(&item, base_url);
+        let dc = metadata.dublin_core()?;
+        assert!(dc.contains("<dc:title>Color problems &amp; solutions</dc:title>"));
+        assert!(dc.contains("<dc:identifier>urn:isbn:9780999609934</dc:identifier>"));
+        assert!(dc.contains("<dc:date>1902-01</dc:date>"));
+        let mods = metadata.mods()?;
+        assert!(mods.contains("<identifier type=\"oclc\">1087838699</identifier>"));
+        assert!(mods.contains("<typeOfResource>text</typeOfResource>"));
+
+        let ld = json_ld(&item, base_url);
+        assert_eq!(ld["@type"], "Book");
+        assert_eq!(ld["@id"], format!("http://library.example/item/{}", id));
+        assert_eq!(ld["author"][0]["name"], "Vanderpoel, Emily Noyes");
+
+        item.format = Format::Magazine;
+        item.volume_and_issue = Some((3, 12));
+        item.issn = Some("0000-0000".to_owned());
+        let ld = json_ld(&item, base_url);
+        assert_eq!(ld["@type"], "PublicationIssue");
+        assert_eq!(ld["issueNumber"], "12");
+        assert_eq!(ld["isPartOf"]["volumeNumber"], "3");
+        assert_eq!(ld["isPartOf"]["isPartOf"]["issn"], "0000-0000");
+        item.format = Format::CD;
+        assert_eq!(
+            json_ld(&item, base_url)["byArtist"][0]["@type"],
+            "MusicGroup"
+        );
+
+        Ok(())
+    }
+}
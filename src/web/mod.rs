@@ -1,26 +1,380 @@
+mod admin;
+mod api;
+mod feed;
+mod metadata;
+mod oai;
+mod opds;
+mod sru;
+
+use crate::circulation::{self, Overdue};
+use crate::citation::{self, CitationFormat};
+use crate::config::Config;
 use crate::db::Db;
+use crate::hold::Hold;
+use crate::item::Item;
+use crate::lesb::{LESBCategory, LESBClassification};
+use crate::user::User;
+use crate::web::metadata::{Metadata, XML_DECLARATION};
 use askama::Template;
-use rouille::{router, Response};
+use chrono::Utc;
+use failure::Fallible;
+use log::error;
+use rouille::{accept, router, Request, Response};
 use std::io;
 use std::net::ToSocketAddrs;
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
 
 #[derive(Template)]
 #[template(path = "index.html")]
 struct IndexTemplate;
 
-pub(crate) fn serve<A>(addr: A, db: Db) -> !
+#[derive(Template)]
+#[template(path = "item.html")]
+struct ItemTemplate<'a> {
+    id: u64,
+    item: &'a Item,
+    classification: String,
+    json_ld: String,
+}
+
+#[derive(Template)]
+#[template(path = "user.html")]
+struct UserTemplate {
+    user: User,
+    loans: Vec<Item>,
+}
+
+#[derive(Template)]
+#[template(path = "overdue.html")]
+struct OverdueTemplate {
+    overdue: Vec<Overdue>,
+}
+
+#[derive(Template)]
+#[template(path = "holds.html")]
+struct HoldsTemplate {
+    heading: String,
+    holds: Vec<HoldRow>,
+}
+
+struct HoldRow {
+    title: String,
+    call_number: String,
+    user: String,
+    placed: String,
+    ready: bool,
+}
+
+impl HoldRow {
+    fn new(db: &Db, hold: &Hold) -> Fallible<HoldRow> {
+        let item = db
+            .load::<Item>(hold.item)?
+            .ok_or_else(|| failure::err_msg(format!("failed to find item {}", hold.item)))?;
+        let user = match db.load::<User>(hold.user)? {
+            Some(user) => user.name,
+            None => format!("unknown user {}", hold.user),
+        };
+        Ok(HoldRow {
+            title: item.title.clone(),
+            call_number: item.call_number(),
+            user,
+            placed: hold.placed.format("%Y-%m-%d").to_string(),
+            ready: hold.is_ready(),
+        })
+    }
+}
+
+fn holds_page(db: &Db, heading: String, holds: &[Hold]) -> Fallible<Response> {
+    let holds = holds
+        .iter()
+        .map(|hold| HoldRow::new(db, hold))
+        .collect::<Fallible<_>>()?;
+    Ok(Response::html(HoldsTemplate { heading, holds }.render()?))
+}
+
+/// The item page, or a Dublin Core, MODS or JSON-LD description of the item, depending on the
+/// `Accept` header.
+fn item_page(request: &Request, db: &Db, id: u64, base_url: &str) -> Fallible<Response> {
+    let item = match db.load::<Item>(id)? {
+        Some(item) => item,
+        None => return Ok(Response::empty_404()),
+    };
+    let json_ld = metadata::json_ld(&item, base_url).to_string();
+    let xml = |content_type, xml| {
+        Response::from_data(content_type, format!("{}{}", XML_DECLARATION, xml))
+    };
+    let response = accept!(request,
+        "text/html" => Response::html(
+            ItemTemplate {
+                id,
+                item: &item,
+                classification: format!(
+                    "{} -- {}",
+                    item.classification.category().description(),
+                    item.classification.description()
+                ),
+                // Keep a "</script>" in a title from ending the script element.
+                json_ld: json_ld.replace("</", "<\\/"),
+            }
+            .render()?,
+        ),
+        "application/ld+json" => Response::from_data("application/ld+json", json_ld.clone()),
+        "application/mods+xml" => {
+            xml("application/mods+xml", Metadata::new(&item, base_url).mods()?)
+        },
+        "application/dc+xml" => {
+            xml("application/dc+xml", Metadata::new(&item, base_url).dublin_core()?)
+        },
+    );
+    Ok(response.with_unique_header("Vary", "Accept"))
+}
+
+fn item_holds_page(db: &Db, id: u64) -> Fallible<Response> {
+    match db.load::<Item>(id)? {
+        Some(item) => holds_page(db, item.title, &db.holds_for_item(id)?),
+        None => Ok(Response::empty_404()),
+    }
+}
+
+fn user_holds_page(db: &Db, barcode: u64) -> Fallible<Response> {
+    match db.load::<User>(barcode)? {
+        Some(user) => holds_page(db, user.name, &db.holds_for_user(barcode)?),
+        None => Ok(Response::empty_404()),
+    }
+}
+
+fn user_page(db: &Db, barcode: u64) -> Fallible<Response> {
+    Ok(match db.load::<User>(barcode)? {
+        Some(user) => Response::html(
+            UserTemplate {
+                user,
+                loans: db.loans_for_user(barcode)?,
+            }
+            .render()?,
+        ),
+        None => Response::empty_404(),
+    })
+}
+
+fn overdue_page(db: &Db) -> Fallible<Response> {
+    Ok(Response::html(
+        OverdueTemplate {
+            overdue: circulation::overdue(db, Utc::now())?,
+        }
+        .render()?,
+    ))
+}
+
+/// The most search results `/search.bib` and the like cite.
+const SEARCH_CITATIONS: usize = 100;
+
+/// Splits a file name like "12.bib" into its stem and citation format.
+fn citation_file(name: &str) -> Option<(&str, CitationFormat)> {
+    let mut parts = name.splitn(2, '.');
+    let stem = parts.next()?;
+    Some((stem, CitationFormat::from_extension(parts.next()?)?))
+}
+
+fn citations(format: CitationFormat, items: &[Item]) -> Fallible<Response> {
+    let mut body = Vec::new();
+    citation::write_citations(&mut body, format, items)?;
+    Ok(Response::from_data(format.content_type(), body))
+}
+
+fn item_citation(db: &Db, id: u64, format: CitationFormat) -> Fallible<Response> {
+    match db.load::<Item>(id)? {
+        Some(item) => citations(format, &[item]),
+        None => Ok(Response::empty_404()),
+    }
+}
+
+fn search_citations(request: &Request, db: &mut Db, format: CitationFormat) -> Fallible<Response> {
+    let query = request.get_param("q").unwrap_or_default();
+    match db.query_with_limit::<Item>(&query, SEARCH_CITATIONS) {
+        Ok(items) => citations(format, &items),
+        Err(err) => Ok(Response::text(err.to_string()).with_status_code(400)),
+    }
+}
+
+/// The scheme and host to put in front of absolute links.
+fn base_url(request: &Request, config: &Config) -> String {
+    match &config.base_url {
+        Some(base_url) => base_url.trim_end_matches('/').to_owned(),
+        None => format!(
+            "{}://{}",
+            if request.is_secure() { "https" } else { "http" },
+            request.header("Host").unwrap_or("localhost")
+        ),
+    }
+}
+
+fn or_500(result: Fallible<Response>) -> Response {
+    result.unwrap_or_else(|err| {
+        error!("{}", err);
+        Response::text("internal server error").with_status_code(500)
+    })
+}
+
+#[allow(clippy::too_many_lines)]
+pub(crate) fn serve<A>(addr: A, db: Db, config: Config) -> !
 where
     A: ToSocketAddrs,
 {
-    let db = Arc::new(db);
+    let db = Arc::new(Mutex::new(db));
     rouille::start_server(addr, move |request| {
         let db = db.clone();
         rouille::log(request, io::stdout(), || {
+            let mut db = db.lock().unwrap();
+            // Authenticated routes set the actor to the token's user.
+            db.set_actor(None);
+            if api::cross_site(request, &base_url(request, &config)) {
+                return Response::text("cross-site request refused").with_status_code(403);
+            }
             router!(request,
                 (GET) (/) => {
                     Response::html(IndexTemplate.render().unwrap())
                 },
+                (GET) (/admin/audit) => {
+                    admin::authorized(request, &mut db, |db| or_500(admin::audit_page(request, db)))
+                },
+                (GET) (/admin/users) => {
+                    admin::authorized(request, &mut db, |db| or_500(admin::users_page(db)))
+                },
+                (POST) (/admin/users) => {
+                    admin::authorized(request, &mut db, |db| admin::add_user(request, db))
+                },
+                (GET) (/admin/users/{barcode: u64}) => {
+                    admin::authorized(request, &mut db, |db| or_500(admin::user_page(db, barcode)))
+                },
+                (POST) (/admin/users/{barcode: u64}) => {
+                    admin::authorized(request, &mut db, |db| admin::edit_user(request, db, barcode))
+                },
+                (POST) (/admin/users/{barcode: u64}/block) => {
+                    admin::authorized(request, &mut db, |db| admin::block_user(request, db, barcode))
+                },
+                (POST) (/admin/users/{barcode: u64}/delete) => {
+                    admin::authorized(request, &mut db, |db| admin::delete_user(request, db, barcode))
+                },
+                (POST) (/admin/users/{barcode: u64}/demote) => {
+                    admin::authorized(request, &mut db, |db| admin::set_admin(db, barcode, false))
+                },
+                (POST) (/admin/users/{barcode: u64}/tokens) => {
+                    admin::authorized(request, &mut db, |db| {
+                        admin::create_token(request, db, barcode)
+                    })
+                },
+                (POST) (/admin/tokens/{id: u64}/revoke) => {
+                    admin::authorized(request, &mut db, |db| admin::revoke_token(db, id))
+                },
+                (POST) (/admin/users/{barcode: u64}/promote) => {
+                    admin::authorized(request, &mut db, |db| admin::set_admin(db, barcode, true))
+                },
+                (POST) (/admin/users/{barcode: u64}/unblock) => {
+                    admin::authorized(request, &mut db, |db| admin::unblock_user(db, barcode))
+                },
+                (GET) (/api/changes) => {
+                    api::changes(request, &mut db)
+                },
+                (POST) (/api/changes) => {
+                    api::merge(request, &mut db)
+                },
+                (POST) (/api/checkin) => {
+                    api::check_in(request, &mut db)
+                },
+                (POST) (/api/checkout) => {
+                    api::check_out(request, &mut db, &config.loans)
+                },
+                (GET) (/api/item/{barcode: String}) => {
+                    api::item(request, &mut db, &barcode)
+                },
+                (POST) (/api/renew) => {
+                    api::renew(request, &mut db, &config.loans)
+                },
+                (GET) (/api/search) => {
+                    api::search(request, &mut db)
+                },
+                (GET) (/api/user/{barcode: u64}) => {
+                    api::user(request, &mut db, barcode)
+                },
+                (GET) (/feeds/{name: String}) => {
+                    // The router can't match a literal dot, so match the file name here.
+                    match name.as_str() {
+                        "new.atom" => {
+                            or_500(feed::new_items(request, &db, &base_url(request, &config)))
+                        }
+                        _ => Response::empty_404(),
+                    }
+                },
+                (GET) (/item/{id: u64}) => {
+                    or_500(item_page(request, &db, id, &base_url(request, &config)))
+                },
+                (GET) (/item/{name: String}) => {
+                    // The router can't match a literal dot, so match the file name here.
+                    match citation_file(&name) {
+                        Some((id, format)) => match id.parse() {
+                            Ok(id) => or_500(item_citation(&db, id, format)),
+                            Err(_) => Response::empty_404(),
+                        },
+                        None => Response::empty_404(),
+                    }
+                },
+                (GET) (/item/{id: u64}/holds) => {
+                    admin::authorized(request, &mut db, |db| or_500(item_holds_page(db, id)))
+                },
+                (GET) (/oai) => {
+                    let admin_email = config.admin_email.as_ref().map(String::as_str);
+                    or_500(oai::oai(request, &db, &base_url(request, &config), admin_email))
+                },
+                (POST) (/oai) => {
+                    let admin_email = config.admin_email.as_ref().map(String::as_str);
+                    or_500(oai::oai(request, &db, &base_url(request, &config), admin_email))
+                },
+                (GET) (/opds) => {
+                    or_500(opds::root_page(&base_url(request, &config)))
+                },
+                (GET) (/opds/category/{category: LESBCategory}) => {
+                    or_500(opds::category_page(&base_url(request, &config), category))
+                },
+                (GET) (/opds/classification/{classification: LESBClassification}) => {
+                    or_500(opds::classification_page(
+                        request,
+                        &db,
+                        &base_url(request, &config),
+                        classification,
+                    ))
+                },
+                (GET) (/opds/search) => {
+                    or_500(opds::search_page(request, &db, &base_url(request, &config)))
+                },
+                (GET) (/opds/{name: String}) => {
+                    // The router can't match a literal dot, so match the file name here.
+                    match name.as_str() {
+                        "opensearch.xml" => {
+                            or_500(opds::opensearch_description(&base_url(request, &config)))
+                        }
+                        _ => Response::empty_404(),
+                    }
+                },
+                (GET) (/overdue) => {
+                    admin::authorized(request, &mut db, |db| or_500(overdue_page(db)))
+                },
+                (GET) (/sru) => {
+                    or_500(sru::search_retrieve(request, &db, &base_url(request, &config)))
+                },
+                (GET) (/user/{barcode: u64}) => {
+                    admin::authorized(request, &mut db, |db| or_500(user_page(db, barcode)))
+                },
+                (GET) (/user/{barcode: u64}/holds) => {
+                    admin::authorized(request, &mut db, |db| or_500(user_holds_page(db, barcode)))
+                },
+                (GET) (/{name: String}) => {
+                    match citation_file(&name) {
+                        Some(("search", format)) => {
+                            or_500(search_citations(request, &mut db, format))
+                        }
+                        _ => Response::empty_404(),
+                    }
+                },
                 _ => Response::empty_404(),
             )
         })
@@ -0,0 +1,725 @@
+use crate::db::Db;
+use crate::item::Item;
+use crate::lesb::LESBClassification;
+use crate::web::metadata::{Metadata, XML_DECLARATION};
+use askama::Template;
+use chrono::{DateTime, NaiveDate, SecondsFormat, TimeZone, Utc};
+use failure::Fallible;
+use rouille::input::post::raw_urlencoded_post_input;
+use rouille::url::form_urlencoded;
+use rouille::{Request, Response};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+/// How many headers or records a list response holds before it needs a resumption token.
+const PAGE_SIZE: usize = 100;
+
+struct MetadataFormat {
+    prefix: &'static str,
+    schema: &'static str,
+    namespace: &'static str,
+}
+
+const METADATA_FORMATS: &[MetadataFormat] = &[
+    MetadataFormat {
+        prefix: "oai_dc",
+        schema: "http://www.openarchives.org/OAI/2.0/oai_dc.xsd",
+        namespace: "http://www.openarchives.org/OAI/2.0/oai_dc/",
+    },
+    MetadataFormat {
+        prefix: "mods",
+        schema: "http://www.loc.gov/standards/mods/v3/mods-3-7.xsd",
+        namespace: "http://www.loc.gov/mods/v3",
+    },
+];
+
+/// An OAI-PMH error condition, like `badArgument`.
+struct OaiError {
+    code: &'static str,
+    message: String,
+}
+
+/// Why a request failed: something to tell the harvester, or our own problem.
+enum Failure {
+    Oai(OaiError),
+    Internal(failure::Error),
+}
+
+impl From<failure::Error> for Failure {
+    fn from(err: failure::Error) -> Failure {
+        Failure::Internal(err)
+    }
+}
+
+impl From<askama::Error> for Failure {
+    fn from(err: askama::Error) -> Failure {
+        Failure::Internal(err.into())
+    }
+}
+
+fn oai_error<S: Into<String>>(code: &'static str, message: S) -> Failure {
+    Failure::Oai(OaiError {
+        code,
+        message: message.into(),
+    })
+}
+
+fn bad_argument<S: Into<String>>(message: S) -> Failure {
+    oai_error("badArgument", message)
+}
+
+type Args<'a> = HashMap<&'a str, &'a str>;
+
+/// Checks that a request has the `required` arguments and nothing but those and `optional` ones.
+fn check(args: &Args, required: &[&str], optional: &[&str]) -> Result<(), Failure> {
+    if let Some(name) = required.iter().find(|name| !args.contains_key(*name)) {
+        return Err(bad_argument(format!("missing {}", name)));
+    }
+    match args
+        .keys()
+        .find(|name| !required.contains(name) && !optional.contains(name))
+    {
+        Some(name) => Err(bad_argument(format!("illegal argument {}", name))),
+        None => Ok(()),
+    }
+}
+
+fn metadata_prefix(prefix: &str) -> Result<&'static str, Failure> {
+    METADATA_FORMATS
+        .iter()
+        .find(|format| format.prefix == prefix)
+        .map(|format| format.prefix)
+        .ok_or_else(|| {
+            oai_error(
+                "cannotDisseminateFormat",
+                format!("unknown metadata format {:?}", prefix),
+            )
+        })
+}
+
+/// The domain name part of `base_url`, which OAI identifiers are namespaced by.
+fn repository_identifier(base_url: &str) -> &str {
+    let host = base_url.splitn(2, "://").nth(1).unwrap_or(base_url);
+    host.split(|c| c == ':' || c == '/').next().unwrap_or(host)
+}
+
+fn oai_identifier(base_url: &str, id: u64) -> String {
+    format!("oai:{}:{}", repository_identifier(base_url), id)
+}
+
+fn load(db: &Db, base_url: &str, identifier: &str) -> Result<Item, Failure> {
+    let prefix = format!("oai:{}:", repository_identifier(base_url));
+    let item = if identifier.starts_with(&prefix) {
+        match identifier[prefix.len()..].parse() {
+            Ok(id) => db.load::<Item>(id)?,
+            Err(_) => None,
+        }
+    } else {
+        None
+    };
+    item.ok_or_else(|| oai_error("idDoesNotExist", format!("no item {:?}", identifier)))
+}
+
+/// Set specs can't hold "N📖", so classification sets use the variant names.
+fn set_spec(classification: LESBClassification) -> String {
+    format!("{}:{:?}", classification.category(), classification)
+}
+
+fn item_sets(item: &Item) -> Vec<String> {
+    vec![
+        item.classification.category().to_string(),
+        set_spec(item.classification),
+    ]
+}
+
+/// When an item last changed, to the second.
+fn datestamp(item: &Item) -> DateTime<Utc> {
+    Utc.timestamp(
+        item.timestamps
+            .last_changed()
+            .map_or(0, |time| time.timestamp()),
+        0,
+    )
+}
+
+fn format_datestamp(time: DateTime<Utc>) -> String {
+    time.to_rfc3339_opts(SecondsFormat::Secs, true)
+}
+
+/// Parses a `from` or `until` argument, returning whether it was only a day. A day in `until`
+/// includes the whole day.
+fn parse_datestamp(s: &str, until: bool) -> Result<(DateTime<Utc>, bool), Failure> {
+    if let Ok(date) = NaiveDate::parse_from_str(s, "%Y-%m-%d") {
+        let time = if until {
+            date.and_hms(23, 59, 59)
+        } else {
+            date.and_hms(0, 0, 0)
+        };
+        Ok((Utc.from_utc_datetime(&time), true))
+    } else if let Ok(time) = Utc.datetime_from_str(s, "%Y-%m-%dT%H:%M:%SZ") {
+        Ok((time, false))
+    } else {
+        Err(bad_argument(format!("{:?} is not a datestamp", s)))
+    }
+}
+
+#[derive(Template)]
+#[template(path = "oai/response.xml")]
+struct OaiResponse<'a> {
+    response_date: String,
+    base_url: &'a str,
+    args: Vec<Arg<'a>>,
+    error: Option<OaiError>,
+    body: String,
+}
+
+struct Arg<'a> {
+    name: &'a str,
+    value: &'a str,
+}
+
+#[derive(Template)]
+#[template(path = "oai/identify.xml")]
+struct Identify<'a> {
+    base_url: &'a str,
+    admin_email: Option<&'a str>,
+    earliest_datestamp: String,
+}
+
+#[derive(Template)]
+#[template(path = "oai/formats.xml")]
+struct ListMetadataFormats {
+    formats: &'static [MetadataFormat],
+}
+
+#[derive(Template)]
+#[template(path = "oai/sets.xml")]
+struct ListSets {
+    sets: Vec<Set>,
+}
+
+struct Set {
+    spec: String,
+    name: String,
+}
+
+#[derive(Template)]
+#[template(path = "oai/records.xml")]
+struct Records {
+    verb: &'static str,
+    with_metadata: bool,
+    records: Vec<Record>,
+    resumption: Option<Resumption>,
+}
+
+struct Record {
+    identifier: String,
+    datestamp: String,
+    sets: Vec<String>,
+    metadata: String,
+}
+
+impl Record {
+    fn new(item: &Item, base_url: &str, prefix: Option<&str>) -> Fallible<Record> {
+        let metadata = Metadata::new(item, base_url);
+        Ok(Record {
+            identifier: oai_identifier(base_url, item.id().unwrap_or_default()),
+            datestamp: format_datestamp(datestamp(item)),
+            sets: item_sets(item),
+            metadata: match prefix {
+                Some("mods") => metadata.mods()?,
+                Some(_) => metadata.dublin_core()?,
+                None => String::new(),
+            },
+        })
+    }
+}
+
+struct Resumption {
+    token: String,
+    complete_list_size: usize,
+    cursor: usize,
+}
+
+/// The arguments of a `ListIdentifiers` or `ListRecords` request, which resumption tokens hold
+/// along with where the next page starts.
+#[derive(Clone, Serialize, Deserialize)]
+struct ListQuery {
+    metadata_prefix: String,
+    from: Option<DateTime<Utc>>,
+    until: Option<DateTime<Utc>>,
+    set: Option<String>,
+    /// The last item ID on the previous page.
+    after: u64,
+}
+
+impl ListQuery {
+    fn new(args: &Args) -> Result<ListQuery, Failure> {
+        if let Some(token) = args.get("resumptionToken") {
+            check(args, &["resumptionToken"], &[])?;
+            return base64::decode_config(token, base64::URL_SAFE_NO_PAD)
+                .ok()
+                .and_then(|json| serde_json::from_slice(&json).ok())
+                .ok_or_else(|| {
+                    oai_error(
+                        "badResumptionToken",
+                        format!("invalid resumption token {:?}", token),
+                    )
+                });
+        }
+
+        check(args, &["metadataPrefix"], &["from", "until", "set"])?;
+        let from = match args.get("from") {
+            Some(from) => Some(parse_datestamp(from, false)?),
+            None => None,
+        };
+        let until = match args.get("until") {
+            Some(until) => Some(parse_datestamp(until, true)?),
+            None => None,
+        };
+        if let (Some((from, from_day)), Some((until, until_day))) = (from, until) {
+            if from_day != until_day {
+                return Err(bad_argument("from and until have different granularities"));
+            }
+            if from > until {
+                return Err(bad_argument("from is after until"));
+            }
+        }
+        Ok(ListQuery {
+            metadata_prefix: metadata_prefix(args["metadataPrefix"])?.to_owned(),
+            from: from.map(|(from, _)| from),
+            until: until.map(|(until, _)| until),
+            set: args.get("set").map(|set| (*set).to_owned()),
+            after: 0,
+        })
+    }
+
+    fn token(&self) -> Fallible<String> {
+        Ok(base64::encode_config(
+            &serde_json::to_vec(self)?,
+            base64::URL_SAFE_NO_PAD,
+        ))
+    }
+
+    fn matches(&self, item: &Item) -> bool {
+        let datestamp = datestamp(item);
+        self.from.map_or(true, |from| datestamp >= from)
+            && self.until.map_or(true, |until| datestamp <= until)
+            && self
+                .set
+                .as_ref()
+                .map_or(true, |set| item_sets(item).contains(set))
+    }
+}
+
+fn identify(
+    db: &Db,
+    base_url: &str,
+    admin_email: Option<&str>,
+    args: &Args,
+) -> Result<String, Failure> {
+    check(args, &[], &[])?;
+    let mut earliest = None;
+    for item in db.iter::<Item>()? {
+        let datestamp = datestamp(&item?);
+        earliest = Some(earliest.map_or(datestamp, |earliest| std::cmp::min(earliest, datestamp)));
+    }
+    Ok(Identify {
+        base_url,
+        admin_email,
+        earliest_datestamp: format_datestamp(earliest.unwrap_or_else(|| Utc.timestamp(0, 0))),
+    }
+    .render()?)
+}
+
+fn list_metadata_formats(db: &Db, base_url: &str, args: &Args) -> Result<String, Failure> {
+    check(args, &[], &["identifier"])?;
+    // Every item is available in every format.
+    if let Some(identifier) = args.get("identifier") {
+        load(db, base_url, identifier)?;
+    }
+    Ok(ListMetadataFormats {
+        formats: METADATA_FORMATS,
+    }
+    .render()?)
+}
+
+/// Each category is a set, and each classification is a set within its category.
+fn list_sets(args: &Args) -> Result<String, Failure> {
+    check(args, &[], &["resumptionToken"])?;
+    if let Some(token) = args.get("resumptionToken") {
+        return Err(oai_error(
+            "badResumptionToken",
+            format!("invalid resumption token {:?}", token),
+        ));
+    }
+    let mut sets = Vec::new();
+    let mut category = None;
+    for classification in &LESBClassification::ALL {
+        if category != Some(classification.category()) {
+            category = Some(classification.category());
+            sets.push(Set {
+                spec: classification.category().to_string(),
+                name: classification.category().description().to_owned(),
+            });
+        }
+        sets.push(Set {
+            spec: set_spec(*classification),
+            name: format!(
+                "{} -- {}",
+                classification.category().description(),
+                classification.description()
+            ),
+        });
+    }
+    Ok(ListSets { sets }.render()?)
+}
+
+fn list(db: &Db, base_url: &str, args: &Args, verb: &'static str) -> Result<String, Failure> {
+    let query = ListQuery::new(args)?;
+    // Rows aren't stored in ID order, so sort the matches for `after` to mark a place in them.
+    let mut ids = Vec::new();
+    for item in db.iter::<Item>()? {
+        let item = item?;
+        if query.matches(&item) {
+            ids.push(item.id().unwrap_or_default());
+        }
+    }
+    ids.sort_unstable();
+    let complete_list_size = ids.len();
+    let cursor = ids.iter().take_while(|&&id| id <= query.after).count();
+    let page = ids[cursor..]
+        .iter()
+        .take(PAGE_SIZE)
+        .map(|&id| {
+            db.load::<Item>(id)?
+                .ok_or_else(|| failure::err_msg(format!("failed to find item {}", id)))
+        })
+        .collect::<Fallible<Vec<_>>>()?;
+    if page.is_empty() {
+        return Err(oai_error("noRecordsMatch", "no items match"));
+    }
+
+    let with_metadata = verb == "ListRecords";
+    let resumption = if cursor + page.len() < complete_list_size {
+        let next = ListQuery {
+            after: page.last().and_then(Item::id).unwrap_or_default(),
+            ..query.clone()
+        };
+        Some(next.token()?)
+    } else if query.after > 0 {
+        // The last page of a resumed list has an empty token.
+        Some(String::new())
+    } else {
+        None
+    };
+    Ok(Records {
+        verb,
+        with_metadata,
+        records: page
+            .iter()
+            .map(|item| {
+                Record::new(
+                    item,
+                    base_url,
+                    Some(query.metadata_prefix.as_str()).filter(|_| with_metadata),
+                )
+            })
+            .collect::<Fallible<_>>()?,
+        resumption: resumption.map(|token| Resumption {
+            token,
+            complete_list_size,
+            cursor,
+        }),
+    }
+    .render()?)
+}
+
+fn get_record(db: &Db, base_url: &str, args: &Args) -> Result<String, Failure> {
+    check(args, &["identifier", "metadataPrefix"], &[])?;
+    let prefix = metadata_prefix(args["metadataPrefix"])?;
+    let item = load(db, base_url, args["identifier"])?;
+    Ok(Records {
+        verb: "GetRecord",
+        with_metadata: true,
+        records: vec![Record::new(&item, base_url, Some(prefix))?],
+        resumption: None,
+    }
+    .render()?)
+}
+
+fn respond(
+    db: &Db,
+    base_url: &str,
+    admin_email: Option<&str>,
+    args: &[(String, String)],
+) -> Fallible<String> {
+    let mut map = HashMap::new();
+    let mut repeated = None;
+    for (name, value) in args {
+        if map.insert(name.as_str(), value.as_str()).is_some() {
+            repeated = Some(name);
+        }
+    }
+    let verb = map.remove("verb");
+    let result = match (repeated, verb) {
+        (Some(name), _) => Err(bad_argument(format!("{} is repeated", name))),
+        (None, Some("Identify")) => identify(db, base_url, admin_email, &map),
+        (None, Some("ListMetadataFormats")) => list_metadata_formats(db, base_url, &map),
+        (None, Some("ListSets")) => list_sets(&map),
+        (None, Some("ListIdentifiers")) => list(db, base_url, &map, "ListIdentifiers"),
+        (None, Some("ListRecords")) => list(db, base_url, &map, "ListRecords"),
+        (None, Some("GetRecord")) => get_record(db, base_url, &map),
+        (None, Some(verb)) => Err(oai_error("badVerb", format!("unknown verb {:?}", verb))),
+        (None, None) => Err(oai_error("badVerb", "missing verb")),
+    };
+    let (body, error) = match result {
+        Ok(body) => (body, None),
+        Err(Failure::Oai(error)) => (String::new(), Some(error)),
+        Err(Failure::Internal(err)) => return Err(err),
+    };
+    // The request element only repeats the arguments back if they made sense.
+    let args = match &error {
+        Some(error) if error.code == "badVerb" || error.code == "badArgument" => Vec::new(),
+        _ => args
+            .iter()
+            .map(|(name, value)| Arg { name, value })
+            .collect(),
+    };
+    Ok(format!(
+        "{}{}",
+        XML_DECLARATION,
+        OaiResponse {
+            response_date: format_datestamp(Utc::now()),
+            base_url,
+            args,
+            error,
+            body,
+        }
+        .render()?
+    ))
+}
+
+/// An OAI-PMH 2.0 repository of every item, for harvesters.
+pub(super) fn oai(
+    request: &Request,
+    db: &Db,
+    base_url: &str,
+    admin_email: Option<&str>,
+) -> Fallible<Response> {
+    let args: Vec<(String, String)> = if request.method() == "POST" {
+        raw_urlencoded_post_input(request)?
+    } else {
+        form_urlencoded::parse(request.raw_query_string().as_bytes())
+            .into_owned()
+            .collect()
+    };
+    Ok(Response::from_data(
+        "text/xml; charset=utf-8",
+        respond(db, base_url, admin_email, &args)?,
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{respond, PAGE_SIZE};
+    use crate::db::Db;
+    use crate::item::Item;
+    use crate::lesb::LESBClassification;
+    use failure::Fallible;
+    use xml::reader::{EventReader, XmlEvent};
+
+    const BASE_URL: &str = "http://library.example:8080";
+
+    fn request(db: &Db, query: &[(&str, &str)]) -> Fallible<String> {
+        let args = query
+            .iter()
+            .map(|(name, value)| ((*name).to_owned(), (*value).to_owned()))
+            .collect::<Vec<_>>();
+        respond(db, BASE_URL, Some("library@example.com"), &args)
+    }
+
+    /// Returns the text of the first `element` in `xml`.
+    fn text(xml: &str, element: &str) -> String {
+        let mut inside = false;
+        for event in EventReader::from_str(xml) {
+            match event.unwrap() {
+                XmlEvent::StartElement { name, .. } if name.local_name == element => inside = true,
+                XmlEvent::Characters(text) if inside => return text,
+                XmlEvent::EndElement { .. } if inside => return String::new(),
+                _ => {}
+            }
+        }
+        panic!("no {} element in {}", element, xml)
+    }
+
+    #[test]
+    fn test() -> Fallible<()> {
+        let mut db = Db::open_memory()?;
+        let mut ids = Vec::new();
+        for i in 0..=PAGE_SIZE {
+            let mut item = Item::test_item();
+            if i == 0 {
+                item.classification = LESBClassification::NBookEmoji;
+            }
+            db.save(&mut item)?;
+            ids.push(item.id().unwrap());
+        }
+
+        let identify = request(&db, &[("verb", "Identify")])?;
+        assert_eq!(
+            text(&identify, "baseURL"),
+            "http://library.example:8080/oai"
+        );
+        assert_eq!(text(&identify, "adminEmail"), "library@example.com");
+        let sets = request(&db, &[("verb", "ListSets")])?;
+        assert!(sets.contains("<setSpec>N:NBookEmoji</setSpec>"));
+        assert_eq!(text(&sets, "setName"), "General Works");
+
+        let records = request(
+            &db,
+            &[
+                ("verb", "ListRecords"),
+                ("metadataPrefix", "oai_dc"),
+                ("set", "N:NBookEmoji"),
+            ],
+        )?;
+        assert_eq!(records.matches("<record>").count(), 1);
+        assert_eq!(
+            text(&records, "identifier"),
+            format!("oai:library.example:{}", ids[0])
+        );
+        assert!(text(&records, "title").starts_with("Color problems"));
+        assert!(!records.contains("resumptionToken"));
+
+        let identifiers = request(
+            &db,
+            &[("verb", "ListIdentifiers"), ("metadataPrefix", "mods")],
+        )?;
+        assert_eq!(identifiers.matches("<header>").count(), PAGE_SIZE);
+        assert!(!identifiers.contains("<metadata>"));
+        let token = text(&identifiers, "resumptionToken");
+        let identifiers = request(
+            &db,
+            &[("verb", "ListIdentifiers"), ("resumptionToken", &token)],
+        )?;
+        assert_eq!(identifiers.matches("<header>").count(), 1);
+        assert!(identifiers.contains(&format!(
+            "<resumptionToken completeListSize=\"{}\" cursor=\"{}\"></resumptionToken>",
+            PAGE_SIZE + 1,
+            PAGE_SIZE
+        )));
+
+        let record = request(
+            &db,
+            &[
+                ("verb", "GetRecord"),
+                ("identifier", &format!("oai:library.example:{}", ids[1])),
+                ("metadataPrefix", "mods"),
+            ],
+        )?;
+        assert!(record.contains("<setSpec>N:NI</setSpec>"));
+        assert!(record.contains("<typeOfResource>text</typeOfResource>"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_resumption() -> Fallible<()> {
+        // More items than one byte can count, so their IDs' bytes can't sort in numeric order.
+        let mut db = Db::open_memory()?;
+        let mut ids = Vec::new();
+        for _ in 0..257 {
+            let mut item = Item::test_item();
+            db.save(&mut item)?;
+            ids.push(item.id().unwrap());
+        }
+
+        let prefix = "oai:library.example:";
+        let mut listed = Vec::new();
+        let mut token: Option<String> = None;
+        loop {
+            let identifiers = match &token {
+                Some(token) => request(
+                    &db,
+                    &[
+                        ("verb", "ListIdentifiers"),
+                        ("resumptionToken", token.as_str()),
+                    ],
+                )?,
+                None => request(
+                    &db,
+                    &[("verb", "ListIdentifiers"), ("metadataPrefix", "oai_dc")],
+                )?,
+            };
+            for event in EventReader::from_str(&identifiers) {
+                if let XmlEvent::Characters(text) = event? {
+                    if text.starts_with(prefix) {
+                        listed.push(text[prefix.len()..].parse::<u64>()?);
+                    }
+                }
+            }
+            assert!(listed.len() <= ids.len(), "listed an item twice");
+            let next = text(&identifiers, "resumptionToken");
+            if next.is_empty() {
+                break;
+            }
+            token = Some(next);
+        }
+        assert_eq!(listed, ids);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_errors() -> Fallible<()> {
+        let mut db = Db::open_memory()?;
+        db.save(&mut Item::test_item())?;
+        for (query, code) in &[
+            (vec![("verb", "Delete")], "badVerb"),
+            (vec![("verb", "ListRecords")], "badArgument"),
+            (
+                vec![
+                    ("verb", "ListRecords"),
+                    ("metadataPrefix", "oai_dc"),
+                    ("from", "2019-01-01"),
+                    ("until", "2019-01-01T00:00:00Z"),
+                ],
+                "badArgument",
+            ),
+            (
+                vec![("verb", "ListRecords"), ("metadataPrefix", "marc21")],
+                "cannotDisseminateFormat",
+            ),
+            (
+                vec![
+                    ("verb", "ListRecords"),
+                    ("metadataPrefix", "oai_dc"),
+                    ("until", "2000-01-01"),
+                ],
+                "noRecordsMatch",
+            ),
+            (
+                vec![("verb", "ListRecords"), ("resumptionToken", "nope")],
+                "badResumptionToken",
+            ),
+            (
+                vec![
+                    ("verb", "GetRecord"),
+                    ("identifier", "oai:library.example:0"),
+                    ("metadataPrefix", "oai_dc"),
+                ],
+                "idDoesNotExist",
+            ),
+        ] {
+            let response = request(&db, query)?;
+            assert!(
+                response.contains(&format!("<error code=\"{}\">", code)),
+                "{}",
+                response
+            );
+        }
+
+        Ok(())
+    }
+}
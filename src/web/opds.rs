@@ -0,0 +1,389 @@
+use crate::db::Db;
+use crate::item::Item;
+use crate::lesb::{LESBCategory, LESBClassification};
+use crate::web::feed::rfc3339;
+use askama::Template;
+use chrono::Utc;
+use failure::Fallible;
+use rouille::url::form_urlencoded;
+use rouille::url::percent_encoding::{utf8_percent_encode, PATH_SEGMENT_ENCODE_SET};
+use rouille::{Request, Response};
+
+/// How many items an acquisition feed shows on each page.
+const PAGE_SIZE: usize = 50;
+
+const NAVIGATION: &str = "application/atom+xml;profile=opds-catalog;kind=navigation";
+const ACQUISITION: &str = "application/atom+xml;profile=opds-catalog;kind=acquisition";
+
+#[derive(Template)]
+#[template(path = "opds/navigation.xml")]
+struct NavigationFeed<'a> {
+    base_url: &'a str,
+    path: String,
+    title: String,
+    updated: String,
+    entries: Vec<NavigationEntry>,
+}
+
+struct NavigationEntry {
+    title: String,
+    path: String,
+    content: String,
+    /// Whether the entry leads to another navigation feed or to items.
+    kind: &'static str,
+}
+
+#[derive(Template)]
+#[template(path = "opds/acquisition.xml")]
+struct AcquisitionFeed<'a> {
+    base_url: &'a str,
+    path: String,
+    title: String,
+    updated: String,
+    /// The navigation feed this one is under.
+    up: String,
+    previous: Option<String>,
+    next: Option<String>,
+    entries: Vec<AcquisitionEntry>,
+}
+
+struct AcquisitionEntry {
+    id: u64,
+    title: String,
+    authors: Vec<String>,
+    language: String,
+    issued: Option<String>,
+    isbn: Option<String>,
+    classification: LESBClassification,
+    label: String,
+    call_number: String,
+    format: String,
+    status: &'static str,
+    updated: String,
+}
+
+impl AcquisitionEntry {
+    fn new(item: &Item) -> AcquisitionEntry {
+        AcquisitionEntry {
+            id: item.id().unwrap_or_default(),
+            title: item.title.clone(),
+            authors: item.authors.clone(),
+            language: item.language.clone(),
+            issued: item.original_date.map(|date| date.to_string()),
+            isbn: item.isbn13.clone(),
+            classification: item.classification,
+            label: label(item.classification),
+            call_number: item.call_number(),
+            format: item.format.to_string(),
+            status: if item.is_checked_out() {
+                "checked out"
+            } else {
+                "available"
+            },
+            updated: rfc3339(item.timestamps.last_changed().unwrap_or_else(Utc::now)),
+        }
+    }
+}
+
+#[derive(Template)]
+#[template(path = "opds/opensearch.xml")]
+struct OpenSearchDescription<'a> {
+    base_url: &'a str,
+}
+
+fn label(classification: LESBClassification) -> String {
+    format!(
+        "{} -- {}",
+        classification.category().description(),
+        classification.description()
+    )
+}
+
+fn category_path(category: LESBCategory) -> String {
+    format!("/opds/category/{}", category)
+}
+
+fn classification_path(classification: LESBClassification) -> String {
+    format!(
+        "/opds/classification/{}",
+        utf8_percent_encode(&classification.to_string(), PATH_SEGMENT_ENCODE_SET)
+    )
+}
+
+fn root_feed(base_url: &str) -> NavigationFeed {
+    let mut entries = Vec::<NavigationEntry>::new();
+    for classification in &LESBClassification::ALL {
+        let category = classification.category();
+        let path = category_path(category);
+        if entries.last().map_or(true, |entry| entry.path != path) {
+            entries.push(NavigationEntry {
+                title: category.description().to_owned(),
+                path,
+                content: format!("Classifications in {}", category),
+                kind: "navigation",
+            });
+        }
+    }
+    NavigationFeed {
+        base_url,
+        path: "/opds".to_owned(),
+        title: "LESBIANS".to_owned(),
+        updated: rfc3339(Utc::now()),
+        entries,
+    }
+}
+
+fn category_feed(base_url: &str, category: LESBCategory) -> NavigationFeed {
+    NavigationFeed {
+        base_url,
+        path: category_path(category),
+        title: category.description().to_owned(),
+        updated: rfc3339(Utc::now()),
+        entries: LESBClassification::ALL
+            .iter()
+            .filter(|classification| classification.category() == category)
+            .map(|classification| NavigationEntry {
+                title: classification.description().to_owned(),
+                path: classification_path(*classification),
+                content: classification.to_string(),
+                kind: "acquisition",
+            })
+            .collect(),
+    }
+}
+
+fn page_path(path: &str, page: usize) -> String {
+    if page == 1 {
+        path.to_owned()
+    } else {
+        let separator = if path.contains('?') { '&' } else { '?' };
+        format!("{}{}page={}", path, separator, page)
+    }
+}
+
+/// Paths to the pages before and after `page` of `path`, which has `count` items in all.
+fn page_links(path: &str, page: usize, count: usize) -> (Option<String>, Option<String>) {
+    (
+        Some(page_path(path, page - 1)).filter(|_| page > 1),
+        Some(page_path(path, page + 1)).filter(|_| offset(page) + PAGE_SIZE < count),
+    )
+}
+
+fn acquisition_feed<'a>(
+    base_url: &'a str,
+    path: &str,
+    title: String,
+    up: String,
+    page: usize,
+    count: usize,
+    items: &[Item],
+) -> AcquisitionFeed<'a> {
+    let (previous, next) = page_links(path, page, count);
+    AcquisitionFeed {
+        base_url,
+        path: page_path(path, page),
+        title,
+        updated: rfc3339(
+            items
+                .iter()
+                .filter_map(|item| item.timestamps.last_changed())
+                .max()
+                .unwrap_or_else(Utc::now),
+        ),
+        up,
+        previous,
+        next,
+        entries: items.iter().map(AcquisitionEntry::new).collect(),
+    }
+}
+
+fn classification_feed<'a>(
+    db: &Db,
+    base_url: &'a str,
+    classification: LESBClassification,
+    page: usize,
+) -> Fallible<Option<AcquisitionFeed<'a>>> {
+    // Quoted, so the query parser keeps N📖 as one term.
+    let (count, items) = db.newest_page::<Item>(
+        &format!("classification:\"{}\"", classification),
+        offset(page),
+        PAGE_SIZE,
+    )?;
+    if past_end(page, count) {
+        return Ok(None);
+    }
+    Ok(Some(acquisition_feed(
+        base_url,
+        &classification_path(classification),
+        label(classification),
+        category_path(classification.category()),
+        page,
+        count,
+        &items,
+    )))
+}
+
+/// The `page` parameter, or `None` if it isn't a page number that could exist.
+fn page(request: &Request) -> Option<usize> {
+    match request.get_param("page") {
+        None => Some(1),
+        Some(page) => page
+            .parse()
+            .ok()
+            .filter(|&page: &usize| page >= 1 && (page - 1).checked_mul(PAGE_SIZE).is_some()),
+    }
+}
+
+/// How many items come before `page`, which `page` has already checked won't overflow.
+fn offset(page: usize) -> usize {
+    (page - 1) * PAGE_SIZE
+}
+
+/// Whether `page` comes after the last page of `count` items. The first page always exists.
+fn past_end(page: usize, count: usize) -> bool {
+    page > 1 && offset(page) >= count
+}
+
+pub(super) fn root_page(base_url: &str) -> Fallible<Response> {
+    Ok(Response::from_data(
+        NAVIGATION,
+        root_feed(base_url).render()?,
+    ))
+}
+
+pub(super) fn category_page(base_url: &str, category: LESBCategory) -> Fallible<Response> {
+    Ok(Response::from_data(
+        NAVIGATION,
+        category_feed(base_url, category).render()?,
+    ))
+}
+
+pub(super) fn classification_page(
+    request: &Request,
+    db: &Db,
+    base_url: &str,
+    classification: LESBClassification,
+) -> Fallible<Response> {
+    let page = match page(request) {
+        Some(page) => page,
+        None => return Ok(Response::empty_400()),
+    };
+    let feed = match classification_feed(db, base_url, classification, page)? {
+        Some(feed) => feed,
+        None => return Ok(Response::empty_404()),
+    };
+    Ok(Response::from_data(ACQUISITION, feed.render()?))
+}
+
+pub(super) fn search_page(request: &Request, db: &Db, base_url: &str) -> Fallible<Response> {
+    let query = request.get_param("q").unwrap_or_default();
+    let page = match page(request) {
+        Some(page) => page,
+        None => return Ok(Response::empty_400()),
+    };
+    let (count, items) = match db.query_page::<Item>(&query, offset(page), PAGE_SIZE) {
+        Ok(results) => results,
+        Err(err) => return Ok(Response::text(err.to_string()).with_status_code(400)),
+    };
+    if past_end(page, count) {
+        return Ok(Response::empty_404());
+    }
+    let path = format!(
+        "/opds/search?q={}",
+        form_urlencoded::byte_serialize(query.as_bytes()).collect::<String>()
+    );
+    let feed = acquisition_feed(
+        base_url,
+        &path,
+        format!("Search results for {:?}", query),
+        "/opds".to_owned(),
+        page,
+        count,
+        &items,
+    );
+    Ok(Response::from_data(ACQUISITION, feed.render()?))
+}
+
+/// Tells e-reader apps how to search the catalog.
+pub(super) fn opensearch_description(base_url: &str) -> Fallible<Response> {
+    Ok(Response::from_data(
+        "application/opensearchdescription+xml",
+        OpenSearchDescription { base_url }.render()?,
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{category_feed, classification_feed, root_feed, PAGE_SIZE};
+    use crate::db::Db;
+    use crate::item::Item;
+    use crate::lesb::{LESBCategory, LESBClassification};
+    use askama::Template;
+    use failure::Fallible;
+    use rouille::Request;
+
+    #[test]
+    fn test() -> Fallible<()> {
+        let base_url = "http://library.example";
+        let root = root_feed(base_url);
+        assert_eq!(root.entries.len(), 10);
+        assert_eq!(root.entries[3].path, "/opds/category/L");
+        let category = category_feed(base_url, LESBCategory::N);
+        assert_eq!(
+            category.entries.last().unwrap().path,
+            "/opds/classification/N%F0%9F%93%96"
+        );
+
+        let mut db = Db::open_memory()?;
+        let mut item = Item::test_item();
+        item.title = "Zines & <records>".to_owned();
+        db.save(&mut item)?;
+        item = Item::test_item();
+        item.classification = LESBClassification::LF;
+        db.save(&mut item)?;
+        item = Item::test_item();
+        item.classification = LESBClassification::NBookEmoji;
+        db.save(&mut item)?;
+        let older = item.id();
+        item = Item::test_item();
+        item.classification = LESBClassification::NBookEmoji;
+        db.save(&mut item)?;
+        let feed = classification_feed(&db, base_url, LESBClassification::NBookEmoji, 1)?.unwrap();
+        assert_eq!(feed.entries.len(), 2);
+        assert_eq!(Some(feed.entries[0].id), item.id());
+        assert_eq!(Some(feed.entries[1].id), older);
+
+        let feed = classification_feed(&db, base_url, LESBClassification::NI, 1)?.unwrap();
+        assert_eq!(feed.entries.len(), 1);
+        assert_eq!(feed.up, "/opds/category/N");
+        assert_eq!(feed.next, None);
+        let xml = feed.render()?;
+        assert!(xml.contains("<title>Zines &amp; &lt;records&gt;</title>"));
+        assert!(xml.contains("<summary type=\"text\">NI VANDE 1902 eng: hardcover book, available"));
+        assert!(classification_feed(&db, base_url, LESBClassification::NI, 2)?.is_none());
+        assert!(
+            classification_feed(&db, base_url, LESBClassification::AC, 1)?
+                .unwrap()
+                .entries
+                .is_empty()
+        );
+        let request = |url: &str| Request::fake_http("GET", url, Vec::new(), Vec::new());
+        assert_eq!(super::page(&request("/opds/search?q=zines")), Some(1));
+        assert_eq!(super::page(&request("/opds/search?page=3")), Some(3));
+        assert_eq!(super::page(&request("/opds/search?page=0")), None);
+        assert_eq!(super::page(&request("/opds/search?page=lots")), None);
+        assert_eq!(
+            super::page(&request("/opds/search?page=18446744073709551615")),
+            None
+        );
+        assert_eq!(
+            super::page_links("/opds/search?q=zines", 2, PAGE_SIZE * 3),
+            (
+                Some("/opds/search?q=zines".to_owned()),
+                Some("/opds/search?q=zines&page=3".to_owned())
+            )
+        );
+
+        Ok(())
+    }
+}
@@ -0,0 +1,434 @@
+use crate::cql::{self, Boolean, Clause, Query};
+use crate::db::{self, Db};
+use crate::isbn::normalize_isbn;
+use crate::item::Item;
+use crate::marc;
+use crate::web::metadata::{Metadata, XML_DECLARATION};
+use askama::Template;
+use failure::Fallible;
+use rouille::url::form_urlencoded;
+use rouille::{Request, Response};
+use std::collections::HashMap;
+
+/// How many records a search returns if the client doesn't say.
+const DEFAULT_RECORDS: usize = 10;
+/// The most records a client can ask for at once.
+const MAX_RECORDS: usize = 100;
+
+#[derive(Clone, Copy)]
+enum RecordSchema {
+    DublinCore,
+    Marcxml,
+}
+
+impl RecordSchema {
+    fn from_param(s: &str) -> Option<RecordSchema> {
+        match s {
+            "dc" | "info:srw/schema/1/dc-v1.1" => Some(RecordSchema::DublinCore),
+            "marcxml" | "info:srw/schema/1/marcxml-v1.1" => Some(RecordSchema::Marcxml),
+            _ => None,
+        }
+    }
+
+    fn uri(self) -> &'static str {
+        match self {
+            RecordSchema::DublinCore => "info:srw/schema/1/dc-v1.1",
+            RecordSchema::Marcxml => "info:srw/schema/1/marcxml-v1.1",
+        }
+    }
+}
+
+/// An SRU diagnostic, from the list at <http://www.loc.gov/standards/sru/diagnostics/>.
+struct Diagnostic {
+    number: u32,
+    details: String,
+}
+
+impl Diagnostic {
+    fn new<S: Into<String>>(number: u32, details: S) -> Diagnostic {
+        Diagnostic {
+            number,
+            details: details.into(),
+        }
+    }
+
+    fn message(&self) -> &'static str {
+        match self.number {
+            4 => "Unsupported operation",
+            6 => "Unsupported parameter value",
+            7 => "Mandatory parameter not supplied",
+            10 => "Query syntax error",
+            16 => "Unsupported index",
+            19 => "Unsupported relation",
+            20 => "Unsupported relation modifier",
+            27 => "Empty term unsupported",
+            37 => "Unsupported boolean operator",
+            38 => "Too many boolean operators in query",
+            61 => "First record position out of range",
+            66 => "Unknown schema for retrieval",
+            71 => "Unsupported record packing",
+            _ => "General system error",
+        }
+    }
+}
+
+/// The index fields a CQL index searches.
+fn fields(index: Option<&str>) -> Option<&'static [&'static str]> {
+    match index {
+        None | Some("cql.serverchoice") => Some(&["title", "author", "isbn"]),
+        Some("dc.title") => Some(&["title"]),
+        Some("dc.creator") => Some(&["author"]),
+        Some("bath.isbn") => Some(&["isbn"]),
+        Some("local.classification") => Some(&["classification"]),
+        _ => None,
+    }
+}
+
+/// A phrase query for `text` in one field, normalized the way the field is indexed.
+fn leaf(field: &str, text: &str) -> String {
+    let text = match field {
+        "isbn" => normalize_isbn(text).unwrap_or_else(|| text.to_owned()),
+        "classification" => text.to_uppercase(),
+        _ => text.to_owned(),
+    };
+    format!("{}:\"{}\"", field, text)
+}
+
+fn any_field(fields: &[&str], text: &str) -> String {
+    let leaves = fields
+        .iter()
+        .map(|field| leaf(field, text))
+        .collect::<Vec<_>>();
+    format!("({})", leaves.join(" "))
+}
+
+fn translate_clause(clause: &Clause) -> Result<String, Diagnostic> {
+    let index = clause.index.as_ref().map(String::as_str);
+    if index == Some("cql.allrecords") {
+        return Ok("id:[0 TO *]".to_owned());
+    }
+    let fields = fields(index).ok_or_else(|| Diagnostic::new(16, index.unwrap_or_default()))?;
+    if let Some(modifier) = clause.modifiers.first() {
+        return Err(Diagnostic::new(20, modifier.as_str()));
+    }
+    // tantivy's phrases can't hold a quote, and masking isn't supported, so escaped characters
+    // are just characters. Words without letters or numbers wouldn't be indexed, so they'd match
+    // anything.
+    let term = clause.term.replace('\\', "").replace('"', "");
+    let words = term
+        .split_whitespace()
+        .filter(|word| word.chars().any(char::is_alphanumeric))
+        .collect::<Vec<_>>();
+    if words.is_empty() {
+        return Err(Diagnostic::new(27, clause.term.as_str()));
+    }
+    // tantivy turns a group of one required query, like "(+a)", into a required query in the
+    // enclosing group, so only use "+" for more than one word.
+    match clause.relation.as_str() {
+        "=" | "all" if words.len() > 1 => Ok(format!(
+            "({})",
+            words
+                .iter()
+                .map(|word| format!("+{}", any_field(fields, word)))
+                .collect::<Vec<_>>()
+                .join(" ")
+        )),
+        "=" | "all" | "any" => Ok(format!(
+            "({})",
+            words
+                .iter()
+                .map(|word| any_field(fields, word))
+                .collect::<Vec<_>>()
+                .join(" ")
+        )),
+        "==" | "adj" | "exact" => Ok(any_field(fields, &words.join(" "))),
+        relation => Err(Diagnostic::new(19, relation)),
+    }
+}
+
+/// Translates a CQL query into tantivy's query syntax. A chain of the same boolean, like
+/// `a and b and c`, becomes a single group, since tantivy can't parse groups nested deeply.
+fn translate(query: &Query) -> Result<String, Diagnostic> {
+    let boolean = match query {
+        Query::Clause(clause) => return translate_clause(clause),
+        Query::Boolean(Boolean::Prox, _, _) => return Err(Diagnostic::new(37, "prox")),
+        Query::Boolean(boolean, _, _) => *boolean,
+    };
+    // Booleans chain to the left, so the first operand is at the bottom.
+    let mut operands = Vec::new();
+    let mut first = query;
+    while let Query::Boolean(next, left, right) = first {
+        if *next != boolean {
+            break;
+        }
+        operands.push(&**right);
+        first = left;
+    }
+    operands.push(first);
+    operands.reverse();
+    let operands = operands
+        .iter()
+        .enumerate()
+        .map(|(i, operand)| {
+            let prefix = match boolean {
+                Boolean::Or => "",
+                Boolean::Not if i > 0 => "-",
+                _ => "+",
+            };
+            Ok(format!("{}{}", prefix, translate(operand)?))
+        })
+        .collect::<Result<Vec<_>, Diagnostic>>()?;
+    Ok(format!("({})", operands.join(" ")))
+}
+
+struct SearchRequest {
+    /// The query in tantivy's syntax.
+    query: String,
+    start_record: usize,
+    maximum_records: usize,
+    record_schema: RecordSchema,
+}
+
+fn parse_request(params: &HashMap<String, String>) -> Result<SearchRequest, Diagnostic> {
+    let param = |name: &str| params.get(name).map(String::as_str);
+    if let Some(operation) = param("operation") {
+        if operation != "searchRetrieve" {
+            return Err(Diagnostic::new(4, operation));
+        }
+    }
+    let query = param("query").ok_or_else(|| Diagnostic::new(7, "query"))?;
+    let query = cql::parse(query).map_err(|err| Diagnostic::new(10, err.to_string()))?;
+    let query = translate(&query)?;
+    db::check_query(&query).map_err(|err| Diagnostic::new(38, err.to_string()))?;
+    let number = |name: &str, default: usize| match param(name) {
+        Some(value) => match value.parse() {
+            Ok(number) => Ok(number),
+            Err(_) => Err(Diagnostic::new(6, name)),
+        },
+        None => Ok(default),
+    };
+    let start_record = number("startRecord", 1)?;
+    if start_record == 0 {
+        return Err(Diagnostic::new(6, "startRecord"));
+    }
+    // SRU 1.2 calls it recordPacking, and 2.0 calls it recordXMLEscaping.
+    for name in &["recordPacking", "recordXMLEscaping"] {
+        if let Some(packing) = param(name) {
+            if packing != "xml" {
+                return Err(Diagnostic::new(71, packing));
+            }
+        }
+    }
+    Ok(SearchRequest {
+        query,
+        start_record,
+        maximum_records: std::cmp::min(number("maximumRecords", DEFAULT_RECORDS)?, MAX_RECORDS),
+        record_schema: match param("recordSchema") {
+            Some(schema) => {
+                RecordSchema::from_param(schema).ok_or_else(|| Diagnostic::new(66, schema))?
+            }
+            None => RecordSchema::DublinCore,
+        },
+    })
+}
+
+#[derive(Template)]
+#[template(path = "sru/response.xml")]
+struct SearchRetrieveResponse {
+    version: &'static str,
+    namespace: &'static str,
+    diagnostic_namespace: &'static str,
+    /// The element that says records aren't escaped, which SRU 2.0 renamed.
+    packing_element: &'static str,
+    number_of_records: usize,
+    record_schema: &'static str,
+    records: Vec<SruRecord>,
+    next_record_position: Option<usize>,
+    diagnostic: Option<Diagnostic>,
+}
+
+struct SruRecord {
+    data: String,
+    position: usize,
+}
+
+fn respond(db: &Db, base_url: &str, params: &HashMap<String, String>) -> Fallible<String> {
+    // SRU 2.0 made `version` and `operation` optional, so a request with neither is 2.0.
+    let sru2 = match params.get("version") {
+        Some(version) => version == "2.0",
+        None => !params.contains_key("operation"),
+    };
+    let mut response = if sru2 {
+        SearchRetrieveResponse {
+            version: "2.0",
+            namespace: "http://docs.oasis-open.org/ns/search-ws/sruResponse",
+            diagnostic_namespace: "http://docs.oasis-open.org/ns/search-ws/diagnostic",
+            packing_element: "recordXMLEscaping",
+            number_of_records: 0,
+            record_schema: "",
+            records: Vec::new(),
+            next_record_position: None,
+            diagnostic: None,
+        }
+    } else {
+        SearchRetrieveResponse {
+            version: "1.2",
+            namespace: "http://www.loc.gov/zing/srw/",
+            diagnostic_namespace: "http://www.loc.gov/zing/srw/diagnostic/",
+            packing_element: "recordPacking",
+            number_of_records: 0,
+            record_schema: "",
+            records: Vec::new(),
+            next_record_position: None,
+            diagnostic: None,
+        }
+    };
+
+    match parse_request(params) {
+        Ok(request) => {
+            let (count, items) = db.query_page::<Item>(
+                &request.query,
+                request.start_record - 1,
+                request.maximum_records,
+            )?;
+            response.number_of_records = count;
+            if count > 0 && request.start_record > count {
+                response.diagnostic = Some(Diagnostic::new(61, request.start_record.to_string()));
+                return Ok(format!("{}{}", XML_DECLARATION, response.render()?));
+            }
+            response.record_schema = request.record_schema.uri();
+            for (i, item) in items.iter().enumerate() {
+                response.records.push(SruRecord {
+                    data: match request.record_schema {
+                        RecordSchema::DublinCore => Metadata::new(item, base_url).dublin_core()?,
+                        RecordSchema::Marcxml => marc::Record::from_item(item).to_marcxml(),
+                    },
+                    position: request.start_record + i,
+                });
+            }
+            let next = request.start_record + items.len();
+            if next <= count {
+                response.next_record_position = Some(next);
+            }
+        }
+        Err(diagnostic) => response.diagnostic = Some(diagnostic),
+    }
+    Ok(format!("{}{}", XML_DECLARATION, response.render()?))
+}
+
+/// An SRU `searchRetrieve` endpoint, so library and citation clients can search the catalog.
+pub(super) fn search_retrieve(request: &Request, db: &Db, base_url: &str) -> Fallible<Response> {
+    let params = form_urlencoded::parse(request.raw_query_string().as_bytes())
+        .into_owned()
+        .collect();
+    Ok(Response::from_data(
+        "text/xml; charset=utf-8",
+        respond(db, base_url, &params)?,
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::respond;
+    use crate::db::Db;
+    use crate::item::Item;
+    use crate::lesb::LESBClassification;
+    use failure::Fallible;
+    use std::collections::HashMap;
+
+    fn search(db: &Db, params: &[(&str, &str)]) -> Fallible<String> {
+        let params = params
+            .iter()
+            .map(|(name, value)| ((*name).to_owned(), (*value).to_owned()))
+            .collect::<HashMap<_, _>>();
+        respond(db, "http://library.example", &params)
+    }
+
+    fn count(db: &Db, query: &str) -> Fallible<String> {
+        let xml = search(
+            db,
+            &[
+                ("version", "1.2"),
+                ("operation", "searchRetrieve"),
+                ("query", query),
+            ],
+        )?;
+        let start = xml.find("<srw:numberOfRecords>").unwrap() + 21;
+        let end = xml.find("</srw:numberOfRecords>").unwrap();
+        Ok(xml[start..end].to_owned())
+    }
+
+    #[test]
+    fn test() -> Fallible<()> {
+        let mut db = Db::open_memory()?;
+        let mut item = Item::test_item();
+        item.title = "Tipping the velvet".to_owned();
+        item.authors = vec!["Waters, Sarah".to_owned()];
+        item.isbn13 = Some("9781860495243".to_owned());
+        item.classification = LESBClassification::LF;
+        db.save(&mut item)?;
+        db.save(&mut Item::test_item())?;
+
+        assert_eq!(count(&db, "velvet")?, "1");
+        assert_eq!(count(&db, "dc.title all \"velvet tipping\"")?, "1");
+        assert_eq!(count(&db, "dc.title adj \"velvet tipping\"")?, "0");
+        assert_eq!(count(&db, "dc.title any \"velvet color\"")?, "2");
+        assert_eq!(count(&db, "dc.creator = waters")?, "1");
+        assert_eq!(
+            count(&db, "dc.creator = waters or dc.creator = vanderpoel")?,
+            "2"
+        );
+        assert_eq!(count(&db, "bath.isbn = 1-86049-524-2")?, "1");
+        assert_eq!(count(&db, "local.classification = l")?, "1");
+        assert_eq!(count(&db, "local.classification = N")?, "1");
+        assert_eq!(
+            count(&db, "cql.allRecords = 1 not dc.creator = waters")?,
+            "1"
+        );
+        let chained = |clauses| vec!["velvet"; clauses].join(" and ");
+        assert_eq!(count(&db, &chained(128))?, "1");
+        let xml = search(&db, &[("query", &chained(100_000))])?;
+        assert!(xml.contains("info:srw/diagnostic/1/10<"), "{}", xml);
+        let alternating = ["velvet and velvet"; 12].join(" or ");
+        let xml = search(&db, &[("query", &alternating)])?;
+        assert!(xml.contains("info:srw/diagnostic/1/38<"), "{}", xml);
+
+        let xml = search(
+            &db,
+            &[
+                ("query", "cql.allRecords = 1"),
+                ("maximumRecords", "1"),
+                ("recordSchema", "marcxml"),
+            ],
+        )?;
+        assert!(xml.contains("sruResponse"));
+        assert!(xml.contains("marcxml-v1.1</srw:recordSchema>"));
+        assert!(xml.contains("<srw:recordXMLEscaping>xml</srw:recordXMLEscaping>"));
+        assert!(xml.contains("<srw:nextRecordPosition>2</srw:nextRecordPosition>"));
+        assert_eq!(xml.matches("<record xmlns").count(), 1);
+        let xml = search(
+            &db,
+            &[("query", "velvet"), ("startRecord", "99999999999999")],
+        )?;
+        assert!(xml.contains("info:srw/diagnostic/1/61<"), "{}", xml);
+        assert_eq!(xml.matches("<record xmlns").count(), 0);
+
+        for (query, number) in &[
+            ("dc.subject = velvet", 16),
+            ("dc.title < velvet", 19),
+            ("dc.title =/relevant velvet", 20),
+            ("velvet and dc.title = \"- *\"", 27),
+            ("velvet prox color", 37),
+            ("(velvet", 10),
+        ] {
+            let xml = search(&db, &[("query", query)])?;
+            assert!(
+                xml.contains(&format!("info:srw/diagnostic/1/{}<", number)),
+                "{}",
+                xml
+            );
+        }
+
+        Ok(())
+    }
+}